@@ -5,6 +5,7 @@ use {
         ecs::*,
         math::*,
         prelude::*,
+        tiled::{collision::convex_decomposition, prefab::PrefabRegistry, ObjectShape, Properties},
     },
     std::ops,
 };
@@ -13,7 +14,7 @@ pub use ncollide2d::{
     self as nc,
     bounding_volume::{self, BoundingVolume, HasBoundingVolume},
     query::{self, DefaultTOIDispatcher, Proximity},
-    shape::{Ball, Cuboid, ShapeHandle},
+    shape::{Ball, Compound, ConvexPolygon, Cuboid, Polyline, ShapeHandle},
 };
 
 pub mod math;
@@ -370,3 +371,91 @@ impl LuaComponentInterface for Shape {
 inventory::submit! {
     LuaComponent::new::<Shape>("Shape")
 }
+
+/// How many vertices ellipse objects which aren't circles are approximated with.
+const ELLIPSE_VERTICES: usize = 16;
+
+/// Add a hook to a prefab registry which gives every object spawned from a map a
+/// `Position`, and a `Shape` if it's a rectangle, ellipse, polygon, polyline or tile
+/// object. Polygons which aren't convex are split into a compound of convex pieces, and
+/// ellipses which aren't circles are approximated by polygons.
+pub fn add_tiled_components<L, T, O>(registry: &mut PrefabRegistry<L, T, O>)
+where
+    L: Properties,
+    T: Properties,
+    O: Properties,
+{
+    registry.add_hook(|prefab, builder| {
+        builder.add(Position(prefab.isometry()));
+
+        let object = prefab.object;
+        let shape = match &object.shape {
+            ObjectShape::Rect { width, height } if *width > 0. && *height > 0. => {
+                let half_extents = Vector2::new(width / 2., height / 2.);
+                // Tile objects extend up from their origin rather than down.
+                let center = match prefab.gid {
+                    Some(_) => Vector2::new(half_extents.x, -half_extents.y),
+                    None => half_extents,
+                };
+                Shape::new(
+                    Isometry2::translation(center.x, center.y),
+                    ShapeHandle::new(Cuboid::new(half_extents)),
+                )
+            }
+            ObjectShape::Polygon { points } => {
+                let points = points
+                    .iter()
+                    .map(|&(x, y)| Point2::new(x, y))
+                    .collect::<Vec<_>>();
+                let mut pieces = convex_decomposition(&points).ok_or_else(|| {
+                    anyhow!(
+                        "polygon object {} is degenerate or intersects itself",
+                        object.id
+                    )
+                })?;
+                let handle = match pieces.len() {
+                    1 => ShapeHandle::new(pieces.pop().unwrap()),
+                    _ => ShapeHandle::new(Compound::new(
+                        pieces
+                            .into_iter()
+                            .map(|piece| (Isometry2::identity(), ShapeHandle::new(piece)))
+                            .collect(),
+                    )),
+                };
+                Shape::new(Isometry2::identity(), handle)
+            }
+            ObjectShape::Ellipse { width, height } if *width > 0. && *height > 0. => {
+                let radii = Vector2::new(width / 2., height / 2.);
+                let local = Isometry2::translation(radii.x, radii.y);
+                if radii.x == radii.y {
+                    Shape::new(local, ShapeHandle::new(Ball::new(radii.x)))
+                } else {
+                    let points = (0..ELLIPSE_VERTICES)
+                        .map(|i| {
+                            let angle =
+                                i as f32 / ELLIPSE_VERTICES as f32 * 2. * std::f32::consts::PI;
+                            Point2::new(radii.x * angle.cos(), radii.y * angle.sin())
+                        })
+                        .collect::<Vec<_>>();
+                    let polygon = ConvexPolygon::try_from_points(&points)
+                        .ok_or_else(|| anyhow!("ellipse object {} is degenerate", object.id))?;
+                    Shape::new(local, ShapeHandle::new(polygon))
+                }
+            }
+            ObjectShape::Polyline { points } if points.len() >= 2 => {
+                let points = points
+                    .iter()
+                    .map(|&(x, y)| Point2::new(x, y))
+                    .collect::<Vec<_>>();
+                Shape::new(
+                    Isometry2::identity(),
+                    ShapeHandle::new(Polyline::new(points, None)),
+                )
+            }
+            _ => return Ok(()),
+        };
+
+        builder.add(shape);
+        Ok(())
+    });
+}
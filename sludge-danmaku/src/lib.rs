@@ -36,6 +36,21 @@ impl<R: RngCore> SharedRng<R> {
 
 impl<R: RngCore> LuaUserData for SharedRng<R> {}
 
+/// Reseed the RNG shared by all bullet patterns from `seed`, so that patterns play
+/// out identically across runs. The RNG is reseeded in place, so batches and pattern
+/// builders which already hold it are reseeded too. Seeds should be recorded with
+/// `sludge::replay::ReplayRecorder::record_seed` for replays to reproduce them.
+pub fn seed_rng(lua: LuaContext, seed: u64) -> Result<()> {
+    match lua.named_registry_value::<_, Option<SharedRng<XorShiftRng>>>(RNG_REGISTRY_KEY)? {
+        Some(rng) => *rng.rng.borrow_mut() = XorShiftRng::seed_from_u64(seed),
+        None => {
+            let rng = SharedRng::new(XorShiftRng::seed_from_u64(seed));
+            lua.set_named_registry_value(RNG_REGISTRY_KEY, rng)?;
+        }
+    }
+    Ok(())
+}
+
 impl<R: RngCore> RngCore for SharedRng<R> {
     fn next_u32(&mut self) -> u32 {
         self.rng.borrow_mut().next_u32()
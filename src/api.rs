@@ -1,7 +1,8 @@
 use crate::{
     ecs::{Component, Entity, EntityBuilder, World},
     filesystem::Filesystem,
-    Resources, SimpleComponent, SludgeLuaContextExt, SludgeResultExt,
+    persist, PersistRequest, PersistRequestKind, Resources, SchedulerQueueChannel, SimpleComponent,
+    SludgeLuaContextExt,
 };
 use {
     anyhow::*,
@@ -15,16 +16,18 @@ use {
     },
 };
 
+mod assets;
 mod log;
 mod math;
 mod thread;
 
 pub const SERIALIZER_THUNK_REGISTRY_KEY: &'static str = "sludge.serialize";
 pub const LOOKUP_THUNK_REGISTRY_KEY: &'static str = "sludge.lookup";
-pub const WORLD_TABLE_REGISTRY_KEY: &'static str = "sludge.world_table";
+pub const ENTITY_REMAP_TABLE_REGISTRY_KEY: &'static str = "sludge.entity_remap";
+pub const RESOLVE_ENTITY_REGISTRY_KEY: &'static str = "sludge.resolve_entity";
+pub const RESOLVE_ASSET_REGISTRY_KEY: &'static str = "sludge.resolve_asset";
 pub const PERMANENTS_SER_TABLE_REGISTRY_KEY: &'static str = "sludge.permanents_ser";
 pub const PERMANENTS_DE_TABLE_REGISTRY_KEY: &'static str = "sludge.permanents_de";
-pub const PLAYBACK_THUNK_REGISTRY_KEY: &'static str = "sludge.playback_thunk";
 pub const PACKAGE_REGISTRY_KEY: &'static str = "sludge.package";
 pub const DEFAULT_PACKAGE_PATH: &'static str = "/?.lua";
 
@@ -57,6 +60,38 @@ impl EntityUserDataRegistry {
         }
     }
 
+    /// The type names of all registered components, in sorted order.
+    pub fn component_names(&self) -> impl Iterator<Item = &str> + '_ {
+        let mut names = self.named.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        names.into_iter()
+    }
+
+    pub fn is_registered(&self, type_name: &str) -> bool {
+        self.named.contains_key(type_name)
+    }
+
+    /// Add the components of a table like the ones passed to `spawn` to a builder, by
+    /// calling the bundler of each component named in it.
+    pub fn bundle<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        table: LuaTable<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        for pair in table.pairs::<LuaString, LuaValue<'lua>>() {
+            let (k, v) = pair?;
+            let s = k.to_str()?;
+            let bundler = match self.named.get(s) {
+                Some(comp) => &comp.bundler,
+                None => return Err(format_err!("unknown component {}", s)).to_lua_err(),
+            };
+            bundler(lua, v, builder)?;
+        }
+
+        Ok(())
+    }
+
     pub fn get_archetype<'lua>(
         &self,
         lua: LuaContext<'lua>,
@@ -258,11 +293,11 @@ impl LuaUserData for LuaEntityUserData {
             |lua, this, ()| -> LuaResult<Option<LuaFunction>> {
                 let lookup_thunk =
                     lua.named_registry_value::<_, LuaFunction>(LOOKUP_THUNK_REGISTRY_KEY)?;
-                let world_table =
-                    lua.named_registry_value::<_, LuaTable>(WORLD_TABLE_REGISTRY_KEY)?;
+                let resolve =
+                    lua.named_registry_value::<_, LuaFunction>(RESOLVE_ENTITY_REGISTRY_KEY)?;
                 lookup_thunk.call((
                     LuaLightUserData(Entity::from_bits(this.0).id() as *mut _),
-                    world_table,
+                    resolve,
                 ))
             },
         );
@@ -275,6 +310,78 @@ impl From<LuaEntityUserData> for Entity {
     }
 }
 
+/// Bits of the entity which unpersisted entity userdata point to until they are
+/// retargeted. No live entity will ever have this ID and generation.
+const DANGLING_ENTITY_BITS: u64 = u64::MAX;
+
+/// Called while unpersisting in place of every persisted entity userdata. Creates
+/// a placeholder userdata and records it in the entity remap table under its old
+/// ID, so that it can be pointed at the respawned entity once the world has been
+/// played back.
+fn resolve_entity<'lua>(
+    lua: LuaContext<'lua>,
+    id: LuaLightUserData,
+) -> LuaResult<LuaAnyUserData<'lua>> {
+    let remap_table = lua.named_registry_value::<_, LuaTable>(ENTITY_REMAP_TABLE_REGISTRY_KEY)?;
+    let placeholders = match remap_table.get::<_, Option<LuaTable>>(id)? {
+        Some(placeholders) => placeholders,
+        None => {
+            let placeholders = lua.create_table()?;
+            remap_table.set(id, placeholders.clone())?;
+            placeholders
+        }
+    };
+
+    let ud = lua.create_userdata(LuaEntityUserData(DANGLING_ENTITY_BITS))?;
+    ud.set_user_value(lua.create_table()?)?;
+    placeholders.set(placeholders.len()? + 1, ud.clone())?;
+
+    Ok(ud)
+}
+
+/// Point every placeholder created during unpersisting at the entity its old ID
+/// was respawned as. Placeholders for entities which were not persisted are left
+/// dangling.
+pub(crate) fn retarget_entity_placeholders<'lua>(
+    lua: LuaContext<'lua>,
+    remap: &HashMap<usize, Entity>,
+) -> LuaResult<()> {
+    let remap_table = lua.named_registry_value::<_, LuaTable>(ENTITY_REMAP_TABLE_REGISTRY_KEY)?;
+    for pair in remap_table.pairs::<LuaLightUserData, LuaTable>() {
+        let (id, placeholders) = pair?;
+        if let Some(&entity) = remap.get(&(id.0 as usize)) {
+            for ud in placeholders.sequence_values::<LuaAnyUserData>() {
+                ud?.borrow_mut::<LuaEntityUserData>()?.0 = entity.to_bits();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Fill in the component fields of every retargeted placeholder, and then clear
+/// the entity remap table. Must be called after the respawned entities have had
+/// their components inserted.
+pub(crate) fn finish_entity_placeholders<'lua>(lua: LuaContext<'lua>) -> LuaResult<()> {
+    let resources = lua.resources();
+    let registry = resources.fetch::<EntityUserDataRegistry>();
+    let remap_table = lua.named_registry_value::<_, LuaTable>(ENTITY_REMAP_TABLE_REGISTRY_KEY)?;
+    for pair in remap_table.pairs::<LuaValue, LuaTable>() {
+        let (_, placeholders) = pair?;
+        for ud in placeholders.sequence_values::<LuaAnyUserData>() {
+            let ud = ud?;
+            let bits = ud.borrow::<LuaEntityUserData>()?.0;
+            if bits != DANGLING_ENTITY_BITS {
+                ud.set_user_value(registry.get_archetype(lua, Entity::from_bits(bits))?)?;
+            }
+        }
+    }
+
+    lua.set_named_registry_value(ENTITY_REMAP_TABLE_REGISTRY_KEY, lua.create_table()?)?;
+
+    Ok(())
+}
+
 /// An [`Entity`] wrapped for use with Lua and provided with a metatable that
 /// allows for Lua operations on it, for components which support such.
 ///
@@ -282,7 +389,9 @@ impl From<LuaEntityUserData> for Entity {
 ///
 /// Once passed to `Lua`, a `LuaEntity` becomes a userdata object which is
 /// persisted as light userdata containing the 32-bit version of the entity
-/// ID.
+/// ID. When unpersisted, it is remapped to whichever entity that ID was respawned
+/// as; if the entity it referred to was not persisted, it is left dangling and
+/// `is_alive` will return `false`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct LuaEntity(u64);
 
@@ -362,11 +471,11 @@ inventory::collect!(Module);
 /// function EntityTable.serialize(entity, table)
 ///     return table -- by default we just pass the table through.
 /// end
-/// -- This function is called when the world is being deserialized. It receives
-/// -- the table which returned by `EntityTable.serialize` and is expected to reconstruct
-/// -- the serialized entity.
-/// function EntityTable.deserialize(table)
-///     sludge.spawn(table) -- by default we just assume the table can be spawned.
+/// -- This function is called when the world is being deserialized. It receives a
+/// -- freshly spawned, empty entity and the table returned by `EntityTable.serialize`,
+/// -- and is expected to reconstruct the serialized entity's components.
+/// function EntityTable.deserialize(entity, table)
+///     sludge.insert(entity, table) -- by default we just assume the table can be inserted.
 /// end
 /// ```
 #[derive(Debug, SimpleComponent)]
@@ -428,15 +537,7 @@ pub fn spawn<'lua>(lua: LuaContext<'lua>, table: LuaTable<'lua>) -> LuaResult<Lu
     let mut world = resources.fetch_mut::<World>();
     let mut builder = EntityBuilder::new();
 
-    for pair in table.pairs::<LuaString, LuaValue<'lua>>() {
-        let (k, v) = pair?;
-        let s = k.to_str()?;
-        let bundler = match registry.named.get(s) {
-            Some(comp) => &comp.bundler,
-            None => return Err(format_err!("unknown component {}", s)).to_lua_err(),
-        };
-        bundler(lua, v, &mut builder)?;
-    }
+    registry.bundle(lua, table, &mut builder)?;
 
     Ok(LuaEntity::from(world.spawn(builder.build())))
 }
@@ -450,15 +551,7 @@ pub fn insert<'lua>(
     let mut world = resources.fetch_mut::<World>();
     let mut builder = EntityBuilder::new();
 
-    for pair in table.pairs::<LuaString, LuaValue<'lua>>() {
-        let (k, v) = pair?;
-        let s = k.to_str()?;
-        let bundler = match registry.named.get(s) {
-            Some(comp) => &comp.bundler,
-            None => return Err(format_err!("unknown component {}", s)).to_lua_err(),
-        };
-        bundler(lua, v, &mut builder)?;
-    }
+    registry.bundle(lua, table, &mut builder)?;
 
     world.insert(entity.into(), builder.build()).to_lua_err()?;
 
@@ -479,13 +572,76 @@ pub fn clear<'lua>(lua: LuaContext<'lua>, _: ()) -> LuaResult<()> {
     Ok(())
 }
 
+fn request_persist<'lua>(
+    lua: LuaContext<'lua>,
+    kind: PersistRequestKind,
+    (slot, thread, is_main): (String, LuaThread<'lua>, bool),
+) -> LuaResult<()> {
+    if is_main {
+        return Err(anyhow!(
+            "saving and loading can only be requested from a scheduled thread"
+        ))
+        .to_lua_err();
+    }
+
+    persist::save_slot_path(&slot).to_lua_err()?;
+    let request = PersistRequest {
+        kind,
+        slot,
+        thread: lua.create_registry_value(thread)?,
+    };
+
+    lua.resources()
+        .fetch::<SchedulerQueueChannel>()
+        .persist
+        .try_send(request)
+        .unwrap();
+    Ok(())
+}
+
+pub fn request_save<'lua>(
+    lua: LuaContext<'lua>,
+    args: (String, LuaThread<'lua>, bool),
+) -> LuaResult<()> {
+    request_persist(lua, PersistRequestKind::Save, args)
+}
+
+pub fn request_load<'lua>(
+    lua: LuaContext<'lua>,
+    args: (String, LuaThread<'lua>, bool),
+) -> LuaResult<()> {
+    request_persist(lua, PersistRequestKind::Load, args)
+}
+
 inventory::submit! {
     Module::parse("sludge", |lua| {
+        // `sludge` is loaded before `sludge.thread`, so `coroutine` is still around.
+        let coroutine = lua.globals().get::<_, LuaTable>("coroutine")?;
+        let running = coroutine.get::<_, LuaFunction>("running")?;
+        let yield_ = coroutine.get::<_, LuaFunction>("yield")?;
+        let persist_request_thunk = lua
+            .load(include_str!("api/lua/persist_request_thunk.lua"))
+            .set_name("persist_request")?
+            .eval::<LuaFunction>()?;
+
+        let save = persist_request_thunk.call::<_, LuaFunction>((
+            lua.create_function(request_save)?,
+            running.clone(),
+            yield_.clone(),
+        ))?;
+        let load = persist_request_thunk.call::<_, LuaFunction>((
+            lua.create_function(request_load)?,
+            running,
+            yield_,
+        ))?;
+
         let table = lua.create_table_from(vec![
             ("spawn", lua.create_function(spawn)?),
             ("insert", lua.create_function(insert)?),
             ("despawn", lua.create_function(despawn)?),
             ("clear", lua.create_function(clear)?),
+            ("save", save),
+            ("load", load),
         ])?;
 
         Ok(LuaValue::Table(table))
@@ -501,40 +657,57 @@ inventory::submit! {
 /// which will simply search for any Lua files found in the VFS.
 ///
 /// The limitations of opening files through this `require` are the same as opening
-/// any file through the `Filesystem`.
+/// any file through the `Filesystem`. If the module can't be found, the error lists
+/// every path which was tried and why it couldn't be opened.
 pub fn require<'lua>(lua: LuaContext<'lua>, module: String) -> LuaResult<LuaValue> {
     let package = lua.named_registry_value::<_, LuaTable>(PACKAGE_REGISTRY_KEY)?;
     let loaded_modules = package.get::<_, LuaTable>("modules")?;
     if let Some(module) = loaded_modules.get::<_, Option<LuaValue>>(module.as_str())? {
-        Ok(module)
-    } else {
+        return Ok(module);
+    }
+
+    let package_path = package.get::<_, LuaString>("path")?;
+    let mut failures = Vec::new();
+    let mut found = None;
+    {
         let resources = lua.resources();
         let mut fs = resources.fetch_mut::<Filesystem>();
-        let package_path = package.get::<_, LuaString>("path")?;
-        let segments = package_path.to_str()?.split(":");
-
-        for segment in segments {
+        for segment in package_path.to_str()?.split(':') {
             let path = segment.replace('?', &module);
-            let mut file = match fs.open(&path) {
-                Ok(file) => file,
-                Err(_) => continue,
-            };
             let mut buf = String::new();
-            file.read_to_string(&mut buf)
-                .log_error_err(module_path!())
-                .to_lua_err()?;
+            match fs
+                .open(&path)
+                .and_then(|mut file| Ok(file.read_to_string(&mut buf)?))
+            {
+                Ok(_) => {
+                    found = Some((path, buf));
+                    break;
+                }
+                Err(err) => failures.push(format!("\n\tno file '{}' ({:#})", path, err)),
+            }
+        }
+    }
+
+    // The filesystem is no longer borrowed here, so that the module can `require` other
+    // modules while it loads.
+    match found {
+        Some((path, buf)) => {
+            // Naming the chunk `@path` makes Lua report locations in the module, including
+            // in tracebacks, as `path:line`.
             let loaded = lua
                 .load(&buf)
-                .set_name(&module)?
+                .set_name(&format!("@{}", path))?
                 .into_function()?
                 .call::<_, LuaValue>(())?;
-            loaded_modules.set(path.as_str(), loaded.clone())?;
-            return Ok(loaded);
+            loaded_modules.set(module.as_str(), loaded.clone())?;
+            Ok(loaded)
         }
-
-        // FIXME: better error reporting here; collect errors from individual module attempts
-        // and log them?
-        Err(anyhow!("module not found!")).to_lua_err()
+        None => Err(anyhow!(
+            "module '{}' not found:{}",
+            module,
+            failures.concat()
+        ))
+        .to_lua_err(),
     }
 }
 
@@ -676,12 +849,10 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<()> {
             .eval::<LuaFunction>()?,
     )?;
 
-    lua.set_named_registry_value(
-        PLAYBACK_THUNK_REGISTRY_KEY,
-        lua.load(include_str!("api/lua/playback_thunk.lua"))
-            .set_name("playback")?
-            .eval::<LuaFunction>()?,
-    )?;
+    let resolve = lua.create_function(resolve_entity)?;
+    lua.register_permanents(RESOLVE_ENTITY_REGISTRY_KEY, resolve.clone())?;
+    lua.set_named_registry_value(RESOLVE_ENTITY_REGISTRY_KEY, resolve)?;
+    lua.set_named_registry_value(ENTITY_REMAP_TABLE_REGISTRY_KEY, lua.create_table()?)?;
 
     Ok(())
 }
@@ -0,0 +1,41 @@
+use crate::{
+    api::{SludgeApiLuaContextExt, RESOLVE_ASSET_REGISTRY_KEY},
+    assets::{Key, LuaAsset, LuaAssetHandle},
+};
+use {anyhow::*, rlua::prelude::*};
+
+/// Load an asset of a registered type. The key is either a path, or a table which is
+/// turned into a structured key.
+pub fn load_asset<'lua>(
+    lua: LuaContext<'lua>,
+    (type_name, key): (LuaString<'lua>, Key<'static>),
+) -> LuaResult<LuaAssetHandle> {
+    LuaAssetHandle::load(lua, type_name.to_str()?, key).to_lua_err()
+}
+
+/// Called while unpersisting in place of every persisted asset handle, to load the
+/// asset again.
+fn resolve_asset<'lua>(
+    lua: LuaContext<'lua>,
+    persisted: LuaTable<'lua>,
+) -> LuaResult<LuaAssetHandle> {
+    load_asset(lua, (persisted.get("type")?, persisted.get("key")?))
+}
+
+pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
+    let resolve = lua.create_function(resolve_asset)?;
+    lua.register_permanents(RESOLVE_ASSET_REGISTRY_KEY, resolve.clone())?;
+    lua.set_named_registry_value(RESOLVE_ASSET_REGISTRY_KEY, resolve)?;
+
+    Ok(LuaValue::Table(lua.create_table_from(vec![
+        ("load", lua.create_function(load_asset)?),
+        (
+            "types",
+            lua.create_function(|_lua, ()| Ok(LuaAsset::type_names()))?,
+        ),
+    ])?))
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.assets", load)
+}
@@ -1,6 +1,7 @@
--- Defer looking up an entity in a persisted entity ID table to preserve uniqueness.
-return function(entity_id, world_table)
+-- Defer resolving a persisted entity ID until the world has been played back, or a
+-- persisted asset until it can be loaded again.
+return function(persisted, resolve)
     return function()
-        return world_table[entity_id]
+        return resolve(persisted)
     end
-end
\ No newline at end of file
+end
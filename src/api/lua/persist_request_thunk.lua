@@ -0,0 +1,10 @@
+-- Wraps a function which queues a save or load request on behalf of the running
+-- thread, so that the thread yields right after making the request. The scheduler
+-- services the request once no thread is running and then wakes the requesting
+-- thread with the result.
+return function(request, running, yield)
+    return function(slot)
+        request(slot, running())
+        return yield()
+    end
+end
@@ -0,0 +1,10 @@
+-- Wraps a spawning function so that it's passed the location it was called from.
+return function(spawn, pcall, error)
+    return function(...)
+        -- `error` reports the location of the function `level` calls up the stack;
+        -- level 1 is `pcall`, 2 is us, and 3 is whoever called us. If our caller
+        -- isn't a Lua function, the location is empty.
+        local _, where = pcall(error, "", 3)
+        return spawn(where, ...)
+    end
+end
@@ -1,34 +1,59 @@
-use crate::{Atom, Event, EventName, Resources, SchedulerQueueChannel, SludgeLuaContextExt};
+use crate::{
+    lua_thread_stats, spawn_thread, Atom, Event, EventName, Resources, SchedulerQueueChannel,
+    SludgeLuaContextExt, THREAD_ERROR_HANDLER_REGISTRY_KEY,
+};
 use {anyhow::*, rlua::prelude::*};
 
+/// Turn a location reported by `error` into a spawn site; `error` reports locations
+/// as `chunk:line: `, or nothing if the location isn't known.
+fn spawn_site(location: LuaString) -> LuaResult<Option<String>> {
+    let location = location.to_str()?.trim_end_matches(": ");
+    Ok(Some(location.to_owned()).filter(|s| !s.is_empty()))
+}
+
 pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
     // Steal coroutine then get rid of it from the global table so that
     // all coroutine manipulation goes through Space.
     let coroutine = lua.globals().get::<_, LuaTable>("coroutine")?;
     lua.globals().set("coroutine", LuaValue::Nil)?;
 
-    let spawn = lua.create_function(|ctx, task: LuaValue| {
-        let thread = match task {
-            LuaValue::Function(f) => ctx.create_thread(f)?,
-            LuaValue::Thread(th) => th,
-            _ => {
-                return Err(LuaError::FromLuaConversionError {
-                    to: "thread or function",
-                    from: "lua value",
-                    message: None,
-                })
-            }
-        };
+    let spawn_thunk = lua
+        .load(include_str!("lua/spawn_thunk.lua"))
+        .set_name("spawn")?
+        .eval::<LuaFunction>()?;
+    let pcall = lua.globals().get::<_, LuaFunction>("pcall")?;
+    let error = lua.globals().get::<_, LuaFunction>("error")?;
 
-        let key = ctx.create_registry_value(thread.clone())?;
-        ctx.resources()
-            .fetch::<SchedulerQueueChannel>()
-            .spawn
-            .try_send(key)
-            .unwrap();
-        Ok(thread)
+    let spawn = spawn_thunk.call::<_, LuaFunction>((
+        lua.create_function(|ctx, (location, task): (LuaString, LuaValue)| {
+            spawn_thread(ctx, None, spawn_site(location)?, task)
+        })?,
+        pcall.clone(),
+        error.clone(),
+    ))?;
+
+    let spawn_in = spawn_thunk.call::<_, LuaFunction>((
+        lua.create_function(
+            |ctx, (location, group, task): (LuaString, LuaString, LuaValue)| {
+                let group = Some(group.to_str()?.into());
+                spawn_thread(ctx, group, spawn_site(location)?, task)
+            },
+        )?,
+        pcall,
+        error,
+    ))?;
+
+    let stats = lua.create_function(|ctx, thread: LuaThread| lua_thread_stats(ctx, thread))?;
+
+    let set_error_handler = lua.create_function(|ctx, handler: Option<LuaFunction>| {
+        ctx.set_named_registry_value(THREAD_ERROR_HANDLER_REGISTRY_KEY, handler)
     })?;
 
+    let kill = lua.create_function(|ctx, thread: LuaThread| ctx.kill(thread))?;
+
+    let kill_group =
+        lua.create_function(|ctx, group: LuaString| ctx.kill_group(group.to_str()?))?;
+
     let broadcast = lua.create_function(|ctx, (string, args): (LuaString, LuaMultiValue)| {
         let event = Event::Broadcast {
             name: EventName(Atom::from(string.to_str()?)),
@@ -83,6 +108,11 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
 
     Ok(LuaValue::Table(lua.create_table_from(vec![
         ("spawn", spawn),
+        ("spawn_in", spawn_in),
+        ("kill", kill),
+        ("kill_group", kill_group),
+        ("stats", stats),
+        ("set_error_handler", set_error_handler),
         ("broadcast", broadcast),
         ("notify", notify),
         ("yield", yield_),
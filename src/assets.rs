@@ -1,25 +1,37 @@
 use crate::{
+    api::{LOOKUP_THUNK_REGISTRY_KEY, RESOLVE_ASSET_REGISTRY_KEY},
     ecs::{Entity, ScContext, SmartComponent},
-    Resources, UnifiedResources,
+    filesystem::Filesystem,
+    Resources, SludgeLuaContextExt, UnifiedResources,
 };
 use {
     anyhow::*,
     arc_swap::ArcSwap,
     hashbrown::{HashMap, HashSet},
+    rlua::prelude::*,
     serde::{de::DeserializeOwned, *},
     serde_hashkey::OrderedFloatPolicy,
     std::{
         any::{self, Any, TypeId},
         borrow::Cow,
+        collections::VecDeque,
         fmt,
         marker::PhantomData,
-        ops,
+        mem, ops,
         path::{Path, PathBuf},
-        sync::{Arc, Condvar, Mutex},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Condvar, Mutex,
+        },
         thread::{self, ThreadId},
+        time::{Duration, Instant, SystemTime},
     },
 };
 
+pub mod loader;
+
+pub use loader::{LoadBatch, Loader, Paused, Pending};
+
 pub type DefaultCache = Cache<'static, UnifiedResources<'static>>;
 
 pub struct Loaded<T> {
@@ -60,6 +72,36 @@ pub trait Asset: Send + Sync + 'static + Sized {
         cache: &Cache<'a, R>,
         resources: &R,
     ) -> Result<Loaded<Self>>;
+
+    /// Load this asset in two stages: the part which can run on a [`Loader`] worker
+    /// thread, and the part which has to run on the main thread, such as uploading a
+    /// texture to the GPU. The default runs all of [`Asset::load`] on the worker, so
+    /// assets which touch the `Graphics` context must override this.
+    fn load_staged<'a, R: Resources<'a>>(
+        key: &Key,
+        cache: &Cache<'a, R>,
+        resources: &R,
+    ) -> Result<Staged<'a, Self, R>> {
+        Self::load(key, cache, resources).map(Staged::Loaded)
+    }
+
+    /// A rough estimate of how many bytes this asset takes up, including any memory it
+    /// holds on the GPU. This is what [`Cache::memory_usage`] reports and what the
+    /// cache's memory budget is measured in.
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+}
+
+/// The main-thread half of a staged load.
+pub type Finalizer<'a, T, R> = Box<dyn FnOnce(&Cache<'a, R>, &R) -> Result<Loaded<T>> + Send>;
+
+/// The result of [`Asset::load_staged`].
+pub enum Staged<'a, T, R: Resources<'a>> {
+    /// The asset was loaded entirely on the worker.
+    Loaded(Loaded<T>),
+    /// The asset needs finishing on the main thread.
+    Finalize(Finalizer<'a, T, R>),
 }
 
 #[derive(Debug)]
@@ -201,6 +243,147 @@ impl<'a> Key<'a> {
     }
 }
 
+impl<'lua, 'a> ToLua<'lua> for Key<'a> {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        match self {
+            Key::Path(path) => path
+                .to_str()
+                .ok_or_else(|| anyhow!("asset path {} is not valid UTF-8", path.display()))
+                .to_lua_err()?
+                .to_lua(lua),
+            Key::Structured(structured) => rlua_serde::to_value(lua, &structured),
+        }
+    }
+}
+
+/// Strings are converted to path keys, and tables to structured keys.
+impl<'lua> FromLua<'lua> for Key<'static> {
+    fn from_lua(lua_value: LuaValue<'lua>, _lua: LuaContext<'lua>) -> LuaResult<Self> {
+        match lua_value {
+            LuaValue::String(path) => Ok(Key::from(PathBuf::from(path.to_str()?))),
+            LuaValue::Table(_) => Ok(Key::Structured(rlua_serde::from_value(lua_value)?)),
+            other => Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "Key",
+                message: Some("expected a path or a table".to_owned()),
+            }),
+        }
+    }
+}
+
+type LuaAssetLoader = fn(&DefaultCache, &Key) -> Result<Arc<dyn Any + Send + Sync>>;
+
+/// An asset type which Lua can load through `sludge.assets.load`, registered with
+/// `inventory::submit!` under the name Lua refers to it by.
+pub struct LuaAsset {
+    type_name: &'static str,
+    load: LuaAssetLoader,
+}
+
+impl LuaAsset {
+    pub fn new<T: Asset>(type_name: &'static str) -> Self {
+        Self {
+            type_name,
+            load: |cache, key| {
+                Ok(cache.get::<T>(key)?.0.arc_swap().clone() as Arc<dyn Any + Send + Sync>)
+            },
+        }
+    }
+
+    /// The type names of all registered asset types, in sorted order.
+    pub fn type_names() -> Vec<&'static str> {
+        let mut names = inventory::iter::<LuaAsset>
+            .into_iter()
+            .map(|asset| asset.type_name)
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+}
+
+inventory::collect!(LuaAsset);
+
+/// An asset loaded from Lua. It keeps the asset referenced for as long as Lua holds
+/// on to it, and is persisted as its type name and key, so that the asset is loaded
+/// again from the `DefaultCache` when unpersisted.
+#[derive(Clone)]
+pub struct LuaAssetHandle {
+    type_name: &'static str,
+    key: Key<'static>,
+    value: Arc<dyn Any + Send + Sync>,
+}
+
+impl fmt::Debug for LuaAssetHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LuaAssetHandle")
+            .field("type_name", &self.type_name)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl LuaAssetHandle {
+    /// Load an asset of the registered type with the given name from the
+    /// `DefaultCache` in the Lua context's resources.
+    pub fn load(lua: LuaContext, type_name: &str, key: Key<'static>) -> Result<Self> {
+        let asset_type = inventory::iter::<LuaAsset>
+            .into_iter()
+            .find(|asset| asset.type_name == type_name)
+            .ok_or_else(|| anyhow!("no asset type named `{}` is registered", type_name))?;
+        let resources = lua.resources();
+        let cache = resources
+            .try_fetch::<DefaultCache>()
+            .ok_or_else(|| anyhow!("can't load assets without a `DefaultCache` resource"))?;
+        let value = (asset_type.load)(&cache, &key)?;
+
+        Ok(Self {
+            type_name: asset_type.type_name,
+            key,
+            value,
+        })
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn key(&self) -> &Key<'static> {
+        &self.key
+    }
+
+    /// Get the asset, if it's of type `T`.
+    pub fn get<T: Asset>(&self) -> Option<Cached<T>> {
+        let swap = self.value.clone().downcast::<ArcSwap<T>>().ok()?;
+        Some(Cached(arc_swap::Cache::new(swap)))
+    }
+}
+
+impl LuaUserData for LuaAssetHandle {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("type", |_lua, this, ()| Ok(this.type_name));
+
+        methods.add_method("key", |lua, this, ()| this.key.clone().to_lua(lua));
+
+        methods.add_meta_method(LuaMetaMethod::ToString, |_lua, this, ()| {
+            Ok(format!("{}({})", this.type_name, this.key))
+        });
+
+        methods.add_meta_method(
+            LuaMetaMethod::Persist,
+            |lua, this, ()| -> LuaResult<LuaFunction> {
+                let lookup_thunk =
+                    lua.named_registry_value::<_, LuaFunction>(LOOKUP_THUNK_REGISTRY_KEY)?;
+                let resolve =
+                    lua.named_registry_value::<_, LuaFunction>(RESOLVE_ASSET_REGISTRY_KEY)?;
+                let persisted = lua.create_table()?;
+                persisted.set("type", this.type_name)?;
+                persisted.set("key", this.key.clone())?;
+                lookup_thunk.call((persisted, resolve))
+            },
+        );
+    }
+}
+
 #[derive(Debug)]
 enum ResourceState {
     Done(Arc<dyn Any + Send + Sync>),
@@ -210,12 +393,75 @@ enum ResourceState {
 #[derive(Default)]
 struct KeyEntry {
     types: HashMap<TypeId, ResourceState>,
+    /// The value of the cache's clock when this key was last fetched.
+    last_used: u64,
+}
+
+/// Type-erased function which reloads an asset of a specific type and swaps the new
+/// value into its `ArcSwap`.
+type Reloader<'a, R> = fn(&Cache<'a, R>, &Key<'static>, Arc<dyn Any + Send + Sync>) -> Result<()>;
+
+/// Type-erased operations on the loaded assets of a specific type.
+struct AssetType<'a, R: Resources<'a>> {
+    name: &'static str,
+    reload: Reloader<'a, R>,
+    memory_usage: fn(&(dyn Any + Send + Sync)) -> usize,
+}
+
+impl<'a, R: Resources<'a>> Clone for AssetType<'a, R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, R: Resources<'a>> Copy for AssetType<'a, R> {}
+
+impl<'a, R: Resources<'a>> AssetType<'a, R> {
+    fn of<T: Asset>() -> Self {
+        Self {
+            name: any::type_name::<T>(),
+            reload: Cache::reload_as::<T>,
+            memory_usage: |value| {
+                let swap = value.downcast_ref::<ArcSwap<T>>().unwrap();
+                swap.load().memory_usage()
+            },
+        }
+    }
+}
+
+/// How much memory the loaded assets of a single type take up, as reported by
+/// [`Cache::memory_usage`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub type_name: &'static str,
+    /// The number of loaded assets of this type.
+    pub count: usize,
+    /// The total of [`Asset::memory_usage`] over the loaded assets of this type.
+    pub bytes: usize,
+    /// How many of `bytes` are taken up by assets with no `Cached` handles left, which
+    /// [`Cache::collect_garbage`] would unload.
+    pub unreferenced_bytes: usize,
+}
+
+/// An asset which could be unloaded, for deciding what to evict.
+struct Candidate {
+    key: Key<'static>,
+    type_id: TypeId,
+    last_used: u64,
+    bytes: usize,
 }
 
+/// The keys each loaded asset depends on, by the key and type of the asset. Assets of
+/// different types loaded from the same key can depend on different things.
+type Dependencies = HashMap<Key<'static>, HashMap<TypeId, HashSet<Key<'static>>>>;
+
 pub struct Cache<'a, R: Resources<'a>> {
     resources: R,
     entries: Mutex<HashMap<Key<'static>, KeyEntry>>,
-    dependencies: Mutex<HashMap<Key<'static>, HashSet<Key<'static>>>>,
+    dependencies: Mutex<Dependencies>,
+    types: Mutex<HashMap<TypeId, AssetType<'a, R>>>,
+    clock: AtomicU64,
+    memory_budget: Mutex<Option<usize>>,
     _marker: PhantomData<&'a ()>,
 }
 
@@ -225,10 +471,355 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
             resources,
             entries: Mutex::new(HashMap::new()),
             dependencies: Mutex::new(HashMap::new()),
+            types: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+            memory_budget: Mutex::new(None),
             _marker: PhantomData,
         }
     }
 
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Set the number of bytes (as estimated by [`Asset::memory_usage`]) which loaded
+    /// assets may take up before the cache starts unloading the least recently used
+    /// assets which no `Cached` handles refer to. Assets which are still in use are
+    /// never unloaded, so the budget may still be exceeded. `None`, the default, means
+    /// no limit.
+    ///
+    /// The budget is enforced whenever an asset is loaded on the main thread. Assets
+    /// loaded by a [`Loader`] count towards it, but can't evict anything themselves.
+    pub fn set_memory_budget(&self, budget: Option<usize>) {
+        *self.memory_budget.lock().unwrap() = budget;
+        self.enforce_memory_budget();
+    }
+
+    pub fn memory_budget(&self) -> Option<usize> {
+        *self.memory_budget.lock().unwrap()
+    }
+
+    /// Memory used by the loaded assets of each type, largest first.
+    pub fn memory_usage(&self) -> Vec<MemoryUsage> {
+        let types = self.types.lock().unwrap().clone();
+        let mut usage = HashMap::<TypeId, MemoryUsage>::new();
+
+        for entry in self.entries.lock().unwrap().values() {
+            for (type_id, state) in &entry.types {
+                let value = match state {
+                    ResourceState::Done(value) => value,
+                    ResourceState::Loading(..) => continue,
+                };
+
+                let asset_type = &types[type_id];
+                let bytes = (asset_type.memory_usage)(&**value);
+                let usage = usage.entry(*type_id).or_insert(MemoryUsage {
+                    type_name: asset_type.name,
+                    count: 0,
+                    bytes: 0,
+                    unreferenced_bytes: 0,
+                });
+                usage.count += 1;
+                usage.bytes += bytes;
+                if Arc::strong_count(value) == 1 {
+                    usage.unreferenced_bytes += bytes;
+                }
+            }
+        }
+
+        let mut usage = usage.into_iter().map(|(_, u)| u).collect::<Vec<_>>();
+        usage.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+        usage
+    }
+
+    /// Total memory used by every loaded asset.
+    pub fn total_memory_usage(&self) -> usize {
+        self.memory_usage().iter().map(|usage| usage.bytes).sum()
+    }
+
+    /// Unload every asset loaded with the given key, returning how many were unloaded.
+    /// `Cached` handles to them stay valid, but are no longer reloaded, and the next
+    /// [`Cache::get`] loads the asset from scratch. Assets which are in the middle of
+    /// being loaded aren't touched.
+    pub fn unload(&self, key: &Key) -> usize {
+        let key = key.clone_static();
+        let unloaded = {
+            let mut entries = self.entries.lock().unwrap();
+            let unloaded = match entries.get_mut(&key) {
+                Some(entry) => {
+                    let done = entry
+                        .types
+                        .iter()
+                        .filter(|(_, state)| matches!(state, ResourceState::Done(_)))
+                        .map(|(&type_id, _)| type_id)
+                        .collect::<Vec<_>>();
+                    done.into_iter()
+                        .filter_map(|type_id| entry.types.remove(&type_id))
+                        .collect::<Vec<_>>()
+                }
+                None => Vec::new(),
+            };
+            self.remove_if_empty(&mut entries, &key);
+            unloaded
+        };
+
+        log::info!("unloaded {} assets for key {}", unloaded.len(), key);
+        unloaded.len()
+    }
+
+    /// Unload every asset which no `Cached` handles refer to, returning how many were
+    /// unloaded. Unloading an asset can drop the last handle to one of its
+    /// dependencies, so this keeps going until nothing else can be unloaded.
+    pub fn collect_garbage(&self) -> usize {
+        let mut total = 0;
+        loop {
+            let candidates = self.unreferenced();
+            if candidates.is_empty() {
+                break;
+            }
+            total += self.evict(candidates).len();
+        }
+
+        if total > 0 {
+            log::info!("collected {} unreferenced assets", total);
+        }
+        total
+    }
+
+    /// Unload the least recently used unreferenced assets until the loaded assets fit
+    /// in the memory budget, or there's nothing left to unload.
+    fn enforce_memory_budget(&self) {
+        let budget = match self.memory_budget() {
+            Some(budget) => budget,
+            None => return,
+        };
+
+        let mut used = self.total_memory_usage();
+        while used > budget {
+            let mut candidates = self.unreferenced();
+            if candidates.is_empty() {
+                log::warn!(
+                    "assets in use take up {} bytes, over the cache's budget of {} bytes",
+                    used,
+                    budget
+                );
+                break;
+            }
+
+            candidates.sort_by_key(|candidate| candidate.last_used);
+            let mut over = used - budget;
+            let evicted = candidates
+                .into_iter()
+                .take_while(|candidate| {
+                    let take = over > 0;
+                    over = over.saturating_sub(candidate.bytes);
+                    take
+                })
+                .collect::<Vec<_>>();
+
+            let evicted = self.evict(evicted);
+            if evicted.is_empty() {
+                break;
+            }
+
+            for (key, type_name, bytes) in evicted {
+                log::info!(
+                    "evicted asset of type {} for key {} to stay under the memory budget",
+                    type_name,
+                    key
+                );
+                used -= bytes;
+            }
+        }
+    }
+
+    /// Every loaded asset which no `Cached` handles refer to.
+    fn unreferenced(&self) -> Vec<Candidate> {
+        let types = self.types.lock().unwrap().clone();
+        let entries = self.entries.lock().unwrap();
+        let mut candidates = Vec::new();
+        for (key, entry) in entries.iter() {
+            for (type_id, state) in &entry.types {
+                match state {
+                    ResourceState::Done(value) if Arc::strong_count(value) == 1 => {
+                        candidates.push(Candidate {
+                            key: key.clone(),
+                            type_id: *type_id,
+                            last_used: entry.last_used,
+                            bytes: (types[type_id].memory_usage)(&**value),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+        candidates
+    }
+
+    /// Unload the given assets if they're still unreferenced, returning the key, type
+    /// name and size of each one which was.
+    fn evict(&self, candidates: Vec<Candidate>) -> Vec<(Key<'static>, &'static str, usize)> {
+        let types = self.types.lock().unwrap().clone();
+        let mut evicted = Vec::new();
+        // The values are dropped only once the entries are unlocked, in case dropping
+        // them does anything interesting.
+        let mut values = Vec::new();
+        {
+            let mut entries = self.entries.lock().unwrap();
+            for candidate in candidates {
+                let entry = match entries.get_mut(&candidate.key) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+
+                match entry.types.get(&candidate.type_id) {
+                    Some(ResourceState::Done(value)) if Arc::strong_count(value) == 1 => {}
+                    _ => continue,
+                }
+
+                values.extend(entry.types.remove(&candidate.type_id));
+                self.remove_if_empty(&mut entries, &candidate.key);
+                evicted.push((
+                    candidate.key,
+                    types[&candidate.type_id].name,
+                    candidate.bytes,
+                ));
+            }
+        }
+        drop(values);
+
+        evicted
+    }
+
+    fn remove_if_empty(&self, entries: &mut HashMap<Key<'static>, KeyEntry>, key: &Key<'static>) {
+        if entries
+            .get(key)
+            .map_or(false, |entry| entry.types.is_empty())
+        {
+            entries.remove(key);
+            self.dependencies.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Every key which has been loaded or which a loaded asset depends on.
+    pub fn keys(&self) -> Vec<Key<'static>> {
+        let mut keys = self
+            .entries
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<HashSet<_>>();
+        for deps in self.dependencies.lock().unwrap().values() {
+            keys.extend(deps.values().flatten().cloned());
+        }
+        keys.into_iter().collect()
+    }
+
+    /// Reload every asset loaded with the given key, atomically swapping in the new
+    /// values so that all `Cached` handles to them see the change. Then, reload every
+    /// asset which depends on them, and so on.
+    ///
+    /// If an asset fails to reload, it keeps its old value and the assets depending on it
+    /// aren't reloaded. The first error encountered is returned after everything else has
+    /// been reloaded.
+    ///
+    /// Reloading is subject to the same concurrency caveats as [`Cache::get`]; in
+    /// addition, an asset which is in the middle of being loaded isn't reloaded.
+    pub fn reload(&self, key: &Key) -> Result<()> {
+        let mut result = Ok(());
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(key.clone_static());
+
+        while let Some(key) = queue.pop_front() {
+            if !visited.insert(key.clone()) {
+                continue;
+            }
+
+            match self.reload_one(&key) {
+                Ok(()) => queue.extend(self.dependents(&key)),
+                Err(err) => {
+                    log::error!("error reloading asset with key {}: {:#}", key, err);
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    fn reload_one(&self, key: &Key<'static>) -> Result<()> {
+        let loaded = match self.entries.lock().unwrap().get(key) {
+            Some(entry) => entry
+                .types
+                .iter()
+                .filter_map(|(&type_id, state)| match state {
+                    ResourceState::Done(value) => Some((type_id, value.clone())),
+                    ResourceState::Loading(..) => None,
+                })
+                .collect::<Vec<_>>(),
+            None => return Ok(()),
+        };
+
+        for (type_id, value) in loaded {
+            let reload = self.types.lock().unwrap()[&type_id].reload;
+            reload(self, key, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn reload_as<T: Asset>(
+        &self,
+        key: &Key<'static>,
+        value: Arc<dyn Any + Send + Sync>,
+    ) -> Result<()> {
+        let loaded = T::load(key, self, &self.resources).with_context(|| {
+            anyhow!(
+                "error reloading asset of type {} for key {}",
+                any::type_name::<T>(),
+                key
+            )
+        })?;
+
+        {
+            let mut dependencies = self.dependencies.lock().unwrap();
+            let by_type = dependencies.entry(key.clone()).or_default();
+            if loaded.deps.is_empty() {
+                by_type.remove(&TypeId::of::<T>());
+            } else {
+                by_type.insert(TypeId::of::<T>(), loaded.deps.into_iter().collect());
+            }
+            if by_type.is_empty() {
+                dependencies.remove(key);
+            }
+        }
+
+        let swap = value.downcast::<ArcSwap<T>>().unwrap();
+        swap.store(Arc::new(loaded.value));
+        log::info!(
+            "reloaded asset of type {} for key {}",
+            any::type_name::<T>(),
+            key
+        );
+
+        Ok(())
+    }
+
+    /// Every key with a loaded asset which directly depends on the given key.
+    fn dependents(&self, key: &Key<'static>) -> Vec<Key<'static>> {
+        self.dependencies
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, by_type)| by_type.values().any(|deps| deps.contains(key)))
+            .map(|(dependent, _)| dependent.clone())
+            .collect()
+    }
+
     /// Load a resource, inserting it into the cache if unloaded and returning a reference
     /// to the cached value if already loaded.
     ///
@@ -247,6 +838,11 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
     /// The bottom line is, if your `Asset` implementation keeps all its recursive calls to
     /// `Cache::get` on the same thread it's called on, then this method will act reasonably
     /// and will not deadlock or fail (unless the loading itself fails.)
+    ///
+    /// When called on a [`Loader`] worker thread, assets are loaded with
+    /// [`Asset::load_staged`] and their finalizers are handed to the main thread. So, the
+    /// main thread must not `get` an asset which a worker is in the middle of loading, as
+    /// it would wait for the worker while the worker waits for it.
     pub fn get<T>(&self, key: &Key) -> Result<Cached<T>>
     where
         T: Asset,
@@ -269,6 +865,10 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
         //     threads waiting on our loading resource when we are done (or fail.)
         let signal_loaded = {
             let mut entries = self.entries.lock().unwrap();
+            if let Some(entry) = entries.get_mut(key) {
+                entry.last_used = self.tick();
+            }
+
             match entries
                 .get(key)
                 .and_then(|e| e.types.get(&TypeId::of::<T>()))
@@ -281,12 +881,20 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
                     if *thread_id != thread::current().id() =>
                 {
                     let loaded = loaded.clone();
-                    let entries = loaded.wait(entries).unwrap();
-                    let entry = entries
-                        .get(key)
-                        .and_then(|e| e.types.get(&TypeId::of::<T>()));
-                    if let Some(ResourceState::Done(value)) = entry {
-                        let downcast = value.clone().downcast::<ArcSwap<T>>().unwrap();
+                    // A loader worker has to let the main thread have its turn while it
+                    // waits, in case the main thread is what it's waiting on.
+                    let value = loader::without_turn(|| {
+                        let entries = loaded.wait(entries).unwrap();
+                        match entries
+                            .get(key)
+                            .and_then(|e| e.types.get(&TypeId::of::<T>()))
+                        {
+                            Some(ResourceState::Done(value)) => Some(value.clone()),
+                            _ => None,
+                        }
+                    });
+                    if let Some(value) = value {
+                        let downcast = value.downcast::<ArcSwap<T>>().unwrap();
                         return Ok(Cached(arc_swap::Cache::new(downcast)));
                     } else {
                         bail!("an error occurred while waiting for another thread to load a resource with the key {}", key);
@@ -306,7 +914,7 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
             }
         };
 
-        let loaded = match T::load(key, self, &self.resources) {
+        let loaded = match loader::load(self, key) {
             Ok(t) => t,
             Err(err) => {
                 // On an error, we unblock the other threads waiting for this resource
@@ -325,19 +933,389 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
             dependencies
                 .entry(key.clone_static())
                 .or_default()
+                .entry(TypeId::of::<T>())
+                .or_default()
                 .extend(loaded.deps);
         }
         let wrapped = Arc::new(ArcSwap::from_pointee(loaded.value));
 
+        // The type has to be registered before any of its assets are in `entries`, so
+        // that anything iterating over `entries` can look it up.
+        self.types
+            .lock()
+            .unwrap()
+            .entry(TypeId::of::<T>())
+            .or_insert_with(AssetType::of::<T>);
+
         {
             let mut entries = self.entries.lock().unwrap();
-            entries.entry(key.clone_static()).or_default().types.insert(
+            let entry = entries.entry(key.clone_static()).or_default();
+            entry.last_used = self.tick();
+            entry.types.insert(
                 TypeId::of::<T>(),
                 ResourceState::Done(wrapped.clone() as Arc<dyn Any + Send + Sync>),
             );
         }
         signal_loaded.notify_all();
 
+        // Evicting assets drops them, and dropping anything holding on to the GPU has to
+        // happen on the main thread, so background loads leave the budget for later.
+        if !loader::on_worker_thread() {
+            self.enforce_memory_budget();
+        }
+
         Ok(Cached(arc_swap::Cache::new(wrapped)))
     }
 }
+
+/// A polling watcher which reloads assets when the files they were loaded from change.
+/// Only files on physical mounts of the `Filesystem` keep track of when they were
+/// modified, so assets in zip archives are never reloaded. This is intended for use
+/// during development.
+#[derive(Debug)]
+pub struct Watcher {
+    interval: Duration,
+    last_poll: Option<Instant>,
+    modified: HashMap<Key<'static>, SystemTime>,
+}
+
+impl Watcher {
+    /// Create a watcher which checks for changes at most once per `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_poll: None,
+            modified: HashMap::new(),
+        }
+    }
+
+    /// Check the modification times of every path-keyed asset in the cache (and every
+    /// path they depend on), reloading those which changed since the last poll. Returns
+    /// the keys which were reloaded. Errors while reloading are logged rather than
+    /// returned, so that a half-written file doesn't interrupt the game.
+    pub fn poll<'a, R: Resources<'a>>(&mut self, cache: &Cache<'a, R>) -> Vec<Key<'static>> {
+        let now = Instant::now();
+        match self.last_poll {
+            Some(last) if now.duration_since(last) < self.interval => return Vec::new(),
+            _ => self.last_poll = Some(now),
+        }
+
+        let mut changed = Vec::new();
+        {
+            let fs = match cache.resources.try_fetch::<Filesystem>() {
+                Some(fs) => fs,
+                None => return Vec::new(),
+            };
+
+            for key in cache.keys() {
+                let modified = match key.to_path().ok().and_then(|path| fs.modified(path)) {
+                    Some(modified) => modified,
+                    None => continue,
+                };
+
+                match self.modified.insert(key.clone(), modified) {
+                    Some(previous) if previous != modified => changed.push(key),
+                    _ => {}
+                }
+            }
+        }
+
+        // The filesystem has to be released before reloading, since loading assets
+        // borrows it mutably.
+        for key in &changed {
+            log::info!("asset file {} changed; reloading", key);
+            let _ = cache.reload(key);
+        }
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SharedResources;
+    use std::{
+        panic,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    type TestCache = Cache<'static, SharedResources<'static>>;
+
+    static LEAF_LOADS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Leaf(usize);
+
+    impl Asset for Leaf {
+        fn load<'a, R: Resources<'a>>(
+            _key: &Key,
+            _cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Loaded<Self>> {
+            Ok(Leaf(LEAF_LOADS.fetch_add(1, Ordering::SeqCst) + 1).into())
+        }
+    }
+
+    struct Branch(usize);
+
+    impl Asset for Branch {
+        fn load<'a, R: Resources<'a>>(
+            key: &Key,
+            cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Loaded<Self>> {
+            let leaf_key = Key::from(key.to_path()?.with_extension("leaf"));
+            let leaf = cache.get::<Leaf>(&leaf_key)?;
+            let value = leaf.load().0 * 10;
+            Ok(Loaded::with_deps(Branch(value), vec![leaf_key]))
+        }
+    }
+
+    #[test]
+    fn reload_propagates_to_dependents() -> Result<()> {
+        let cache = Cache::new(SharedResources::new());
+        let branch = cache.get::<Branch>(&Key::from_path("/tree.branch"))?;
+        let leaf = cache.get::<Leaf>(&Key::from_path("/tree.leaf"))?;
+        assert_eq!(leaf.load().0, 1);
+        assert_eq!(branch.load().0, 10);
+
+        cache.reload(&Key::from_path("/tree.leaf"))?;
+        assert_eq!(leaf.load().0, 2);
+        assert_eq!(branch.load().0, 20);
+
+        Ok(())
+    }
+
+    struct Bark(usize);
+
+    impl Asset for Bark {
+        fn load<'a, R: Resources<'a>>(
+            key: &Key,
+            cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Loaded<Self>> {
+            let sap_key = Key::from(key.to_path()?.with_extension("sap"));
+            let sap = cache.get::<Leaf>(&sap_key)?;
+            let value = sap.load().0;
+            Ok(Loaded::with_deps(Bark(value), vec![sap_key]))
+        }
+    }
+
+    #[test]
+    fn reload_keeps_dependencies_of_every_type() -> Result<()> {
+        let cache = Cache::new(SharedResources::new());
+        let branch = cache.get::<Branch>(&Key::from_path("/oak.branch"))?;
+        let bark = cache.get::<Bark>(&Key::from_path("/oak.branch"))?;
+        cache.reload(&Key::from_path("/oak.branch"))?;
+
+        let before = branch.load().0;
+        cache.reload(&Key::from_path("/oak.leaf"))?;
+        assert_ne!(branch.load().0, before);
+
+        let before = bark.load().0;
+        cache.reload(&Key::from_path("/oak.sap"))?;
+        assert_ne!(bark.load().0, before);
+
+        Ok(())
+    }
+
+    struct Uploaded {
+        loaded_on: ThreadId,
+        finalized_on: ThreadId,
+    }
+
+    impl Asset for Uploaded {
+        fn load<'a, R: Resources<'a>>(
+            _key: &Key,
+            _cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Loaded<Self>> {
+            Ok(Uploaded {
+                loaded_on: thread::current().id(),
+                finalized_on: thread::current().id(),
+            }
+            .into())
+        }
+
+        fn load_staged<'a, R: Resources<'a>>(
+            _key: &Key,
+            _cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Staged<'a, Self, R>> {
+            let loaded_on = thread::current().id();
+            Ok(Staged::Finalize(Box::new(
+                move |_cache: &Cache<'a, R>, _resources: &R| {
+                    Ok(Uploaded {
+                        loaded_on,
+                        finalized_on: thread::current().id(),
+                    }
+                    .into())
+                },
+            )))
+        }
+    }
+
+    #[test]
+    fn batch_finalizes_on_main_thread() -> Result<()> {
+        let cache = Arc::new(TestCache::new(SharedResources::new()));
+        let loader = Loader::new()?;
+        let mut batch = LoadBatch::new(&loader);
+        let mut uploaded = batch.get::<Uploaded, _>(&cache, &Key::from_path("/a.uploaded"));
+        let mut branch = batch.get::<Branch, _>(&cache, &Key::from_path("/batch.branch"));
+        assert_eq!(batch.len(), 2);
+
+        while batch.poll() < 1. {
+            thread::yield_now();
+        }
+        assert!(batch.is_done());
+
+        let uploaded = uploaded.poll().unwrap()?;
+        assert_ne!(uploaded.load().loaded_on, thread::current().id());
+        assert_eq!(uploaded.load().finalized_on, thread::current().id());
+        assert!(branch.poll().unwrap().is_ok());
+
+        let cached = cache.get::<Uploaded>(&Key::from_path("/a.uploaded"))?;
+        assert_eq!(cached.load().loaded_on, uploaded.load().loaded_on);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dropping_the_loader_fails_staged_loads() -> Result<()> {
+        let cache = Arc::new(TestCache::new(SharedResources::new()));
+        let loader = Loader::new()?;
+        let mut pending = cache.get_async::<Uploaded>(&loader, &Key::from_path("/b.uploaded"));
+        while loader.waiting() == 0 {
+            thread::yield_now();
+        }
+        drop(loader);
+
+        let result = loop {
+            if let Some(result) = pending.poll() {
+                break result;
+            }
+            thread::yield_now();
+        };
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    struct Exploding;
+
+    impl Asset for Exploding {
+        fn load<'a, R: Resources<'a>>(
+            _key: &Key,
+            _cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Loaded<Self>> {
+            Ok(Exploding.into())
+        }
+
+        fn load_staged<'a, R: Resources<'a>>(
+            _key: &Key,
+            _cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Staged<'a, Self, R>> {
+            Ok(Staged::Finalize(Box::new(
+                |_cache: &Cache<'a, R>, _resources: &R| -> Result<Loaded<Self>> {
+                    panic!("the GPU caught fire")
+                },
+            )))
+        }
+    }
+
+    #[test]
+    fn panicking_finalizers_fail_their_loads() -> Result<()> {
+        let cache = Arc::new(TestCache::new(SharedResources::new()));
+        let loader = Loader::new()?;
+        let pending = cache.get_async::<Exploding>(&loader, &Key::from_path("/c.exploding"));
+        while loader.waiting() == 0 {
+            thread::yield_now();
+        }
+
+        let finalized = panic::catch_unwind(panic::AssertUnwindSafe(|| loader.finalize()));
+        assert!(finalized.is_err());
+        assert!(pending.wait(&loader).is_err());
+
+        Ok(())
+    }
+
+    static COUNTED_LOADS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted;
+
+    impl Asset for Counted {
+        fn load<'a, R: Resources<'a>>(
+            _key: &Key,
+            _cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Loaded<Self>> {
+            COUNTED_LOADS.fetch_add(1, Ordering::SeqCst);
+            Ok(Counted.into())
+        }
+    }
+
+    #[test]
+    fn pausing_the_loader_holds_off_loads() -> Result<()> {
+        let cache = Arc::new(TestCache::new(SharedResources::new()));
+        let loader = Loader::new()?;
+        let paused = loader.pause();
+        let mut pending = cache.get_async::<Counted>(&loader, &Key::from_path("/d.counted"));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(COUNTED_LOADS.load(Ordering::SeqCst), 0);
+        assert!(pending.poll().is_none());
+
+        drop(paused);
+        pending.wait(&loader)?;
+        assert_eq!(COUNTED_LOADS.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    struct Blob;
+
+    impl Asset for Blob {
+        fn load<'a, R: Resources<'a>>(
+            _key: &Key,
+            _cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Loaded<Self>> {
+            Ok(Blob.into())
+        }
+
+        fn memory_usage(&self) -> usize {
+            100
+        }
+    }
+
+    #[test]
+    fn unreferenced_assets_are_unloaded() -> Result<()> {
+        let cache = TestCache::new(SharedResources::new());
+        let kept = cache.get::<Blob>(&Key::from_path("/kept.blob"))?;
+        drop(cache.get::<Blob>(&Key::from_path("/dropped.blob"))?);
+        assert_eq!(cache.total_memory_usage(), 200);
+        assert_eq!(cache.memory_usage()[0].unreferenced_bytes, 100);
+
+        assert_eq!(cache.collect_garbage(), 1);
+        assert_eq!(cache.keys(), vec![Key::from_path("/kept.blob")]);
+
+        cache.set_memory_budget(Some(250));
+        for i in 0..3 {
+            drop(cache.get::<Blob>(&Key::from(PathBuf::from(format!("/{}.blob", i))))?);
+        }
+        let mut keys = cache.keys();
+        keys.sort_by_key(|key| key.to_string());
+        assert_eq!(
+            keys,
+            vec![Key::from_path("/2.blob"), Key::from_path("/kept.blob")]
+        );
+
+        assert_eq!(cache.unload(&Key::from_path("/kept.blob")), 1);
+        assert_eq!(cache.total_memory_usage(), 100);
+        drop(kept);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,402 @@
+//! Loading assets in the background.
+//!
+//! A [`Loader`] owns a worker thread which loads assets through [`Cache::get_async`] or
+//! a [`LoadBatch`]. Anything which has to happen on the main thread (see
+//! [`Asset::load_staged`]) is queued up and run by [`Loader::finalize`], which the main
+//! thread has to call regularly while anything is loading; the worker waits for it in
+//! the meantime.
+//!
+//! Loaders borrow shared resources such as the `Filesystem` mutably, and those borrows
+//! panic if they overlap. So the main thread mustn't borrow resources which loaders use
+//! while anything is loading, except from inside [`Loader::finalize`] or while holding
+//! the guard returned by [`Loader::pause`], which keeps the worker from running loaders.
+
+use crate::{
+    assets::{Asset, Cache, Cached, Key, Loaded, Staged},
+    Resources,
+};
+use {
+    anyhow::*,
+    crossbeam_channel::{self as channel, Receiver, Sender, TryRecvError},
+    std::{
+        cell::RefCell,
+        mem,
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Condvar, Mutex,
+        },
+        thread,
+        time::Duration,
+    },
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The main-thread half of a staged load, sent from the worker. The closure borrows
+/// from the worker's stack, so it isn't really `'static` or `Send`; see
+/// [`Worker::run_on_main_thread`] for why this is okay.
+struct FinalizeJob(Box<dyn FnOnce()>);
+
+unsafe impl Send for FinalizeJob {}
+
+/// The receiving end of a finalize job's result. The job owns the only sender, so the
+/// channel disconnects exactly when the job has been run or dropped, and dropping this
+/// blocks until then, whichever way the worker leaves [`Worker::run_on_main_thread`].
+struct Done<U>(Receiver<U>);
+
+impl<U> Drop for Done<U> {
+    fn drop(&mut self) {
+        while self.0.recv().is_ok() {}
+    }
+}
+
+/// Whether the loader is still around to run finalize jobs. The worker holds the lock
+/// while sending a job, and the loader while shutting down, so that no job can be sent
+/// after the loader has dropped the ones it had queued.
+type Open = Arc<Mutex<bool>>;
+
+/// Whether the worker or the main thread gets to use the resources loaders borrow.
+#[derive(Debug, Default)]
+struct Turn {
+    taken: Mutex<bool>,
+    released: Condvar,
+}
+
+impl Turn {
+    fn take(&self) {
+        let mut taken = self.taken.lock().unwrap();
+        while *taken {
+            taken = self.released.wait(taken).unwrap();
+        }
+        *taken = true;
+    }
+
+    fn release(&self) {
+        *self.taken.lock().unwrap() = false;
+        self.released.notify_one();
+    }
+}
+
+#[derive(Clone)]
+struct Worker {
+    finalize: Sender<FinalizeJob>,
+    open: Open,
+    turn: Arc<Turn>,
+}
+
+thread_local! {
+    static WORKER: RefCell<Option<Worker>> = RefCell::new(None);
+}
+
+impl Worker {
+    fn current() -> Option<Self> {
+        WORKER.with(|worker| worker.borrow().clone())
+    }
+
+    fn run(self, jobs: Receiver<Job>) {
+        WORKER.with(|worker| *worker.borrow_mut() = Some(self.clone()));
+
+        for job in jobs {
+            self.turn.take();
+            job();
+            self.turn.release();
+        }
+    }
+
+    /// Run `f` on the main thread, blocking until it has run.
+    fn run_on_main_thread<'f, U, F>(&self, f: F) -> Result<U>
+    where
+        U: Send + 'static,
+        F: FnOnce() -> U + 'f,
+    {
+        let (sender, receiver) = channel::bounded(1);
+        let done = Done(receiver);
+        let job: Box<dyn FnOnce() + 'f> = Box::new(move || {
+            let _ = sender.send(f());
+        });
+
+        // SAFETY: the job owns the only `sender`, and `done` is declared before it, so
+        // however we leave this function, `done` is dropped after the job and blocks
+        // until the job has been run or dropped. So nothing the job borrows can go away
+        // before the job is done with it. If the job were leaked, we'd block forever,
+        // which is also fine; that's why the loader drops every job it hasn't run when
+        // it closes, and only takes jobs while it's open.
+        let job = unsafe { mem::transmute::<Box<dyn FnOnce() + 'f>, Box<dyn FnOnce()>>(job) };
+
+        self.without_turn(|| {
+            {
+                let open = self.open.lock().unwrap();
+                if !*open || self.finalize.send(FinalizeJob(job)).is_err() {
+                    bail!("the loader was dropped before finalizing an asset");
+                }
+            }
+
+            done.0
+                .recv()
+                .map_err(|_| anyhow!("the loader was dropped before finalizing an asset"))
+        })
+    }
+
+    fn without_turn<U>(&self, f: impl FnOnce() -> U) -> U {
+        self.turn.release();
+        let result = f();
+        self.turn.take();
+        result
+    }
+}
+
+/// Give up the worker's turn while `f` blocks on another thread, if we're on a loader
+/// worker at all.
+pub(super) fn without_turn<U>(f: impl FnOnce() -> U) -> U {
+    match Worker::current() {
+        Some(worker) => worker.without_turn(f),
+        None => f(),
+    }
+}
+
+pub(super) fn on_worker_thread() -> bool {
+    WORKER.with(|worker| worker.borrow().is_some())
+}
+
+/// Load an asset for `Cache::get`, handing its finalizer to the main thread if we're on
+/// a loader worker.
+pub(super) fn load<'a, T: Asset, R: Resources<'a>>(
+    cache: &Cache<'a, R>,
+    key: &Key,
+) -> Result<Loaded<T>> {
+    let worker = match Worker::current() {
+        Some(worker) => worker,
+        None => return T::load(key, cache, &cache.resources),
+    };
+
+    match T::load_staged(key, cache, &cache.resources)? {
+        Staged::Loaded(loaded) => Ok(loaded),
+        Staged::Finalize(finalize) => {
+            worker.run_on_main_thread(|| finalize(cache, &cache.resources))?
+        }
+    }
+}
+
+/// A worker thread for loading assets in the background.
+pub struct Loader {
+    jobs: Sender<Job>,
+    finalize: Receiver<FinalizeJob>,
+    open: Open,
+    turn: Arc<Turn>,
+}
+
+impl Loader {
+    /// Spawn a loader and its worker thread. The worker shuts down once the loader is
+    /// dropped and it's finished whatever it was loading.
+    pub fn new() -> Result<Self> {
+        let (jobs, job_receiver) = channel::unbounded();
+        let (finalize_sender, finalize) = channel::unbounded();
+        let open = Arc::new(Mutex::new(true));
+        let turn = Arc::new(Turn::default());
+
+        let worker = Worker {
+            finalize: finalize_sender,
+            open: open.clone(),
+            turn: turn.clone(),
+        };
+        thread::Builder::new()
+            .name("sludge-loader".to_owned())
+            .spawn(move || worker.run(job_receiver))?;
+
+        Ok(Self {
+            jobs,
+            finalize,
+            open,
+            turn,
+        })
+    }
+
+    /// Keep the worker from running loaders until the returned guard is dropped, so that
+    /// the main thread can borrow the resources they use. If the worker is in the middle
+    /// of a load, this waits for it to finish or to wait on [`Loader::finalize`]. Staged
+    /// loads can still be finalized while paused, but nothing else will load, so don't
+    /// wait on a [`Pending`] load until the guard is gone.
+    pub fn pause(&self) -> Paused<'_> {
+        self.turn.take();
+        Paused(&self.turn)
+    }
+
+    /// The number of staged loads waiting on `finalize`.
+    pub fn waiting(&self) -> usize {
+        self.finalize.len()
+    }
+
+    /// Run the main-thread half of every staged load which is waiting on it, returning
+    /// how many were run. This must be called from the main thread.
+    pub fn finalize(&self) -> usize {
+        let mut finalized = 0;
+        for FinalizeJob(job) in self.finalize.try_iter() {
+            job();
+            finalized += 1;
+        }
+        finalized
+    }
+
+    fn spawn<T, R>(
+        &self,
+        cache: &Arc<Cache<'static, R>>,
+        key: &Key,
+        finished: Option<Arc<AtomicUsize>>,
+    ) -> Pending<T>
+    where
+        T: Asset,
+        R: Resources<'static> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = channel::bounded(1);
+        let cache = cache.clone();
+        let key = key.clone_static();
+        let _ = self.jobs.send(Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| cache.get::<T>(&key)))
+                .unwrap_or_else(|_| Err(anyhow!("asset loader panicked while loading {}", key)));
+            let _ = sender.send(result);
+            if let Some(finished) = finished {
+                finished.fetch_add(1, Ordering::SeqCst);
+            }
+        }));
+
+        Pending {
+            receiver,
+            taken: false,
+        }
+    }
+}
+
+/// Keeps a [`Loader`]'s worker from running loaders while it's alive. See
+/// [`Loader::pause`].
+pub struct Paused<'l>(&'l Turn);
+
+impl<'l> Drop for Paused<'l> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl Drop for Loader {
+    /// Fail every staged load which is waiting to be finalized, and any which would be
+    /// later, so that the worker doesn't wait forever on a loader which is gone.
+    fn drop(&mut self) {
+        let mut open = self
+            .open
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *open = false;
+        self.finalize.try_iter().for_each(drop);
+    }
+}
+
+impl<R> Cache<'static, R>
+where
+    R: Resources<'static> + Send + Sync + 'static,
+{
+    /// Load an asset on `loader`'s worker, returning a handle to poll for the
+    /// result. If the asset is already loaded, the handle is ready almost immediately.
+    pub fn get_async<T: Asset>(self: &Arc<Self>, loader: &Loader, key: &Key) -> Pending<T> {
+        loader.spawn(self, key, None)
+    }
+}
+
+/// An asset being loaded in the background.
+pub struct Pending<T: Send + Sync> {
+    receiver: Receiver<Result<Cached<T>>>,
+    taken: bool,
+}
+
+impl<T: Send + Sync> Pending<T> {
+    /// Returns the result of the load once it's finished, and `None` while it's still
+    /// going (or if the result has already been returned.)
+    pub fn poll(&mut self) -> Option<Result<Cached<T>>> {
+        if self.taken {
+            return None;
+        }
+
+        let result = match self.receiver.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(anyhow!("the asset loader shut down")),
+        };
+        self.taken = true;
+        Some(result)
+    }
+
+    /// Block until the load is finished, finalizing staged loads in the meantime. This
+    /// must be called from the main thread.
+    pub fn wait(mut self, loader: &Loader) -> Result<Cached<T>> {
+        ensure!(!self.taken, "the result has already been taken");
+
+        loop {
+            loader.finalize();
+            if let Some(result) = self.poll() {
+                return result;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// A group of assets loading in the background, such as everything a level needs,
+/// which keeps track of how much of it has finished for the sake of loading screens.
+pub struct LoadBatch<'l> {
+    loader: &'l Loader,
+    total: usize,
+    finished: Arc<AtomicUsize>,
+}
+
+impl<'l> LoadBatch<'l> {
+    pub fn new(loader: &'l Loader) -> Self {
+        Self {
+            loader,
+            total: 0,
+            finished: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Start loading an asset as part of this batch.
+    pub fn get<T, R>(&mut self, cache: &Arc<Cache<'static, R>>, key: &Key) -> Pending<T>
+    where
+        T: Asset,
+        R: Resources<'static> + Send + Sync + 'static,
+    {
+        self.total += 1;
+        self.loader.spawn(cache, key, Some(self.finished.clone()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// The number of loads in the batch which have finished, successfully or not.
+    pub fn finished(&self) -> usize {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// Run any finalizers waiting on the main thread, and return the fraction of the
+    /// batch which has finished loading, from 0 to 1. An empty batch is finished. This
+    /// must be called from the main thread.
+    pub fn poll(&mut self) -> f32 {
+        self.loader.finalize();
+        self.progress()
+    }
+
+    /// The fraction of the batch which has finished loading, from 0 to 1.
+    pub fn progress(&self) -> f32 {
+        if self.total == 0 {
+            1.
+        } else {
+            self.finished() as f32 / self.total as f32
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.finished() == self.total
+    }
+}
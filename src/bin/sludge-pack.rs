@@ -0,0 +1,67 @@
+//! Packs a resources directory into a zip archive for release builds, warning about
+//! missing and unreferenced files along the way. See `sludge::pack`.
+
+use {
+    anyhow::*,
+    sludge::pack::Packer,
+    std::{env, fs, process},
+};
+
+const USAGE: &str = "usage: sludge-pack <resources dir> <output zip> [--root <path>]...";
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut roots = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--root" => roots.push(args.next().ok_or_else(|| anyhow!(USAGE))?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let (resources, output) = match &positional[..] {
+        [resources, output] => (resources, output),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut packer = Packer::new(resources)?;
+    for root in &roots {
+        packer.add_root(root)?;
+    }
+
+    let report = packer.scan()?;
+    for (path, referrer) in &report.missing {
+        eprintln!(
+            "warning: {} refers to {}, which doesn't exist",
+            referrer.display(),
+            path.display()
+        );
+    }
+
+    for (path, err) in &report.errors {
+        eprintln!(
+            "warning: couldn't find the dependencies of {}: {:#}",
+            path.display(),
+            err
+        );
+    }
+
+    for path in &report.unreferenced {
+        eprintln!("warning: nothing refers to {}", path.display());
+    }
+
+    let file = fs::File::create(output)
+        .with_context(|| anyhow!("error creating output archive {}", output))?;
+    let manifest = packer.write(&report, file)?;
+    println!("packed {} files into {}", manifest.files.len(), output);
+
+    Ok(())
+}
@@ -52,10 +52,13 @@
 use {
     anyhow::*,
     directories::ProjectDirs,
-    std::{env, fmt, io, path},
+    std::{env, fmt, io, path, time::SystemTime},
 };
 
-use crate::vfs::{self, VFS};
+use crate::{
+    pack,
+    vfs::{self, VFS},
+};
 
 pub use crate::vfs::OpenOptions;
 
@@ -156,6 +159,12 @@ impl Filesystem {
             if resources_zip_path.exists() {
                 log::trace!("Resources zip file: {:?}", resources_zip_path);
                 let zipfs = vfs::ZipFS::new(&resources_zip_path)?;
+                pack::verify_archive(&zipfs).with_context(|| {
+                    anyhow!(
+                        "error verifying resources zip file {:?}",
+                        resources_zip_path
+                    )
+                })?;
                 overlay.push_back(Box::new(zipfs));
             } else {
                 log::trace!("No resources zip file found");
@@ -191,6 +200,37 @@ impl Filesystem {
         Ok(fs)
     }
 
+    /// Create a `Filesystem` backed by nothing but the given VFS, without
+    /// looking up any user directories. This is meant for tests and headless
+    /// tools, which can hand it a `vfs::MemoryFS` to avoid touching the disk.
+    pub fn from_vfs<V: VFS + 'static>(vfs: V) -> Filesystem {
+        let mut overlay = vfs::OverlayFS::new();
+        overlay.push_back(Box::new(vfs));
+
+        Filesystem {
+            vfs: overlay,
+            resources_path: "".into(),
+            zip_path: "".into(),
+            user_config_path: "".into(),
+            user_data_path: "".into(),
+        }
+    }
+
+    /// Create a `Filesystem` which only looks at the given directory, for tools
+    /// which work on a resources directory outside of a game. There are no
+    /// user directories, so nothing can be written unless `readonly` is false.
+    pub fn from_directory<P: AsRef<path::Path>>(path: P, readonly: bool) -> Filesystem {
+        let mut fs = Self::from_vfs(vfs::PhysicalFS::new(path.as_ref(), readonly));
+        fs.resources_path = path.as_ref().to_path_buf();
+        fs
+    }
+
+    /// The per-user data directory, or an empty path if this `Filesystem`
+    /// wasn't created with one.
+    pub fn user_data_path(&self) -> &path::Path {
+        &self.user_data_path
+    }
+
     /// Opens the given `path` and returns the resulting `File`
     /// in read-only mode.
     pub fn open<P: AsRef<path::Path>>(&mut self, path: P) -> Result<File> {
@@ -218,6 +258,25 @@ impl Filesystem {
         self.vfs.create(path.as_ref()).map(|f| File::VfsFile(f))
     }
 
+    /// Replaces the contents of a file in the user directory all at once,
+    /// creating it if it doesn't exist. The new contents are written to a
+    /// temporary file next to it, synced to disk and then renamed over the
+    /// original, so a crash partway through leaves either the old contents or
+    /// the new ones and never a mix.
+    pub fn write_atomic<P: AsRef<path::Path>>(&mut self, path: P, bytes: &[u8]) -> Result<()> {
+        self.vfs.write_atomic(path.as_ref(), bytes)
+    }
+
+    /// Moves a file or directory in the user directory, replacing whatever
+    /// file was at the destination.
+    pub fn rename<P: AsRef<path::Path>, Q: AsRef<path::Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> Result<()> {
+        self.vfs.rename(from.as_ref(), to.as_ref())
+    }
+
     /// Create an empty directory in the user dir
     /// with the given name.  Any parents to that directory
     /// that do not exist will be created.
@@ -257,6 +316,16 @@ impl Filesystem {
             .unwrap_or(false)
     }
 
+    /// Get the last modification time of a file or directory, if the
+    /// filesystem it's found in keeps track of it. Only physical mounts
+    /// do; files in zip archives never report one.
+    pub fn modified<P: AsRef<path::Path>>(&self, path: P) -> Option<SystemTime> {
+        self.vfs
+            .metadata(path.as_ref())
+            .ok()
+            .and_then(|m| m.modified())
+    }
+
     /// Returns a list of all files and directories in the resource directory,
     /// in no particular order.
     ///
@@ -316,6 +385,13 @@ impl Filesystem {
         self.vfs.push_back(Box::new(physfs));
     }
 
+    /// Adds a VFS to the front of the list of places to search, so that it
+    /// takes priority over everything mounted so far.
+    pub(crate) fn push_front(&mut self, vfs: Box<dyn VFS>) {
+        log::trace!("Mounting new VFS in front: {:?}", vfs);
+        self.vfs.push_front(vfs);
+    }
+
     /// Adds any object that implements Read + Seek as a zip file.
     ///
     /// Note: This is not intended for system files for the same reasons as
@@ -431,6 +507,24 @@ mod tests {
         fs.delete(test_file).unwrap();
     }
 
+    #[test]
+    fn headless_test_space_from_memory() -> Result<()> {
+        let memfs = vfs::MemoryFS::new();
+        memfs.insert("/greeting.lua", "return 'hello'")?;
+
+        let space = crate::Space::new()?;
+        space
+            .resources()
+            .borrow_mut()
+            .insert(Filesystem::from_vfs(memfs));
+        let greeting = space
+            .lua()
+            .context(|lua| lua.load("return require('greeting')").eval::<String>())?;
+        assert_eq!(greeting, "hello");
+
+        Ok(())
+    }
+
     // #[test]
     // fn headless_test_file_not_found() {
     //     let mut fs = dummy_fs_for_tests();
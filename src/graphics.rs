@@ -1,8 +1,9 @@
 use crate::{
-    assets::{Asset, Cache, Cached, Key, Loaded},
+    assets::{Asset, Cache, Cached, Key, Loaded, LuaAsset, Staged},
     ecs::{ScContext, SmartComponent},
     filesystem::Filesystem,
     math::*,
+    pack::{self, AssetDependencies},
     resources::Resources,
 };
 use {
@@ -1839,23 +1840,74 @@ impl<T: AnyDrawable + ?Sized, C> From<DrawableId<T, C>> for Option<ErasedDrawabl
 }
 
 impl Asset for Texture {
+    /// Reads, decodes and uploads the image all at once, through `load_staged`.
     fn load<'a, R: Resources<'a>>(
         key: &Key,
-        _cache: &Cache<'a, R>,
+        cache: &Cache<'a, R>,
         resources: &R,
     ) -> Result<Loaded<Self>> {
+        match Self::load_staged(key, cache, resources)? {
+            Staged::Loaded(loaded) => Ok(loaded),
+            Staged::Finalize(finalize) => finalize(cache, resources),
+        }
+    }
+
+    /// Reads and decodes the image on the worker, leaving only the upload for the main
+    /// thread.
+    fn load_staged<'a, R: Resources<'a>>(
+        key: &Key,
+        _cache: &Cache<'a, R>,
+        resources: &R,
+    ) -> Result<Staged<'a, Self, R>> {
         let path = key
             .to_path()
             .with_context(|| anyhow!("bad key for Texture"))?;
-        let mut filesystem = resources.fetch_mut::<Filesystem>();
-        let mut gfx = resources.fetch_mut::<Graphics>();
-        let mut file = filesystem.open(path)?;
-        let texture = Texture::from_reader(&mut *gfx, &mut file)
-            .with_context(|| anyhow!("failed to create a texture using {:?}", path))?;
-        Ok(Loaded::new(texture))
+        let mut buf = Vec::new();
+        resources
+            .fetch_mut::<Filesystem>()
+            .open(path)?
+            .read_to_end(&mut buf)?;
+        let rgba_image = image::load_from_memory(&buf)
+            .with_context(|| anyhow!("failed to decode an image using {:?}", path))?
+            .to_rgba();
+
+        Ok(Staged::Finalize(Box::new(
+            move |_cache: &Cache<'a, R>, resources: &R| {
+                let (width, height) = rgba_image.dimensions();
+                let mut gfx = resources.fetch_mut::<Graphics>();
+                let texture = Texture::from_rgba8(
+                    &mut *gfx,
+                    width as u16,
+                    height as u16,
+                    &rgba_image.into_raw(),
+                );
+                Ok(Loaded::new(texture))
+            },
+        )))
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.width() as usize * self.height() as usize * 4
     }
 }
 
+inventory::submit! {
+    LuaAsset::new::<Texture>("Texture")
+}
+
+// Textures are loaded by name from game code as often as they're referred to by maps,
+// so images are roots. Every format `image` is built with here is listed.
+inventory::submit! {
+    AssetDependencies::new(
+        &[
+            "png", "jpg", "jpeg", "gif", "bmp", "ico", "tga", "tif", "tiff", "webp", "pbm",
+            "pgm", "ppm", "pam", "dds",
+        ],
+        true,
+        pack::no_dependencies,
+    )
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct LuaDrawableIdUserData {
     drawable_id: Index,
@@ -1,14 +1,15 @@
 use crate::{
-    assets::{Asset, Cache, Cached, Key, Loaded},
+    assets::{Asset, Cache, Cached, Key, Loaded, LuaAsset, Staged},
     filesystem::Filesystem,
     graphics::*,
+    pack::{self, AssetDependencies},
     Resources,
 };
 
 use {
     hashbrown::HashMap,
     image::{Rgba, RgbaImage},
-    std::{borrow::Cow, ffi::OsStr, path::Path},
+    std::{borrow::Cow, ffi::OsStr, mem, path::Path},
 };
 
 #[derive(Debug, Clone)]
@@ -468,4 +469,39 @@ impl Asset for FontAtlas {
             vec![Key::from(key.path.into_owned())],
         ))
     }
+
+    /// Loads the font on the worker. Building the atlas uploads it to the GPU, so the rest
+    /// happens on the main thread.
+    fn load_staged<'a, R: Resources<'a>>(
+        key: &Key,
+        cache: &Cache<'a, R>,
+        _resources: &R,
+    ) -> Result<Staged<'a, Self, R>> {
+        let atlas_key = key.to_rust::<FontAtlasKey>()?;
+        cache.get::<Font>(&Key::from_path(&atlas_key.path))?;
+        let key = key.clone_static();
+        Ok(Staged::Finalize(Box::new(
+            move |cache: &Cache<'a, R>, resources: &R| Self::load(&key, cache, resources),
+        )))
+    }
+
+    fn memory_usage(&self) -> usize {
+        let texture = self.font_texture.load();
+        texture.width() as usize * texture.height() as usize * 4
+            + self.font_map.len() * mem::size_of::<(char, CharInfo)>()
+    }
+}
+
+inventory::submit! {
+    LuaAsset::new::<Font>("Font")
+}
+
+inventory::submit! {
+    LuaAsset::new::<FontAtlas>("FontAtlas")
+}
+
+// Atlases are rendered from their font when they're loaded, so there's nothing else to
+// pack, and the fonts themselves are only ever named by game code.
+inventory::submit! {
+    AssetDependencies::new(&["ttf", "otf"], true, pack::no_dependencies)
 }
@@ -13,11 +13,22 @@
 //! fun.
 //! * Take ggez's event-based input API, and present event- or
 //! state-based API so you can do whichever you want.
+//!
+//! Game pads come from a [`Gamepads`], which should be polled
+//! every update. Its events say which player the game pad
+//! belongs to, so each player can have their own bindings
+//! and input state.
 
-// TODO: Handle mice, game pads, joysticks
+// TODO: Handle joysticks
 
 use crate::math::*;
-use {hashbrown::HashMap, std::hash::Hash};
+use {
+    anyhow::*,
+    gilrs::ff,
+    hashbrown::HashMap,
+    serde::{Deserialize, Serialize},
+    std::{fmt, hash::Hash, time::Duration},
+};
 
 // Okay, but how does it actually work?
 // Basically we have to bind input events to buttons and axes.
@@ -336,19 +347,132 @@ impl From<miniquad::MouseButton> for MouseButton {
     }
 }
 
+/// Game pad buttons, named by where they are on the pad following
+/// gilrs' layout: `South` is A on an Xbox controller and cross on
+/// a PlayStation controller.
+#[derive(Debug, Hash, Eq, PartialEq, Copy, Clone)]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    C,
+    Z,
+    LeftTrigger,
+    LeftTrigger2,
+    RightTrigger,
+    RightTrigger2,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+impl GamepadButton {
+    fn from_gilrs(button: gilrs::Button) -> Option<Self> {
+        use gilrs::Button as GiBt;
+        use GamepadButton as SlBt;
+
+        Some(match button {
+            GiBt::South => SlBt::South,
+            GiBt::East => SlBt::East,
+            GiBt::North => SlBt::North,
+            GiBt::West => SlBt::West,
+            GiBt::C => SlBt::C,
+            GiBt::Z => SlBt::Z,
+            GiBt::LeftTrigger => SlBt::LeftTrigger,
+            GiBt::LeftTrigger2 => SlBt::LeftTrigger2,
+            GiBt::RightTrigger => SlBt::RightTrigger,
+            GiBt::RightTrigger2 => SlBt::RightTrigger2,
+            GiBt::Select => SlBt::Select,
+            GiBt::Start => SlBt::Start,
+            GiBt::Mode => SlBt::Mode,
+            GiBt::LeftThumb => SlBt::LeftThumb,
+            GiBt::RightThumb => SlBt::RightThumb,
+            GiBt::DPadUp => SlBt::DPadUp,
+            GiBt::DPadDown => SlBt::DPadDown,
+            GiBt::DPadLeft => SlBt::DPadLeft,
+            GiBt::DPadRight => SlBt::DPadRight,
+            GiBt::Unknown => return None,
+        })
+    }
+}
+
+/// Analog game pad inputs. Sticks are in [-1, 1], with up and
+/// right positive. `LeftTrigger` and `RightTrigger` are how far
+/// the `LeftTrigger2` and `RightTrigger2` buttons are pulled, in
+/// [0, 1], on pads which report it.
+#[derive(Debug, Hash, Eq, PartialEq, Copy, Clone)]
+pub enum GamepadAxis {
+    LeftStickX,
+    LeftStickY,
+    LeftZ,
+    RightStickX,
+    RightStickY,
+    RightZ,
+    DPadX,
+    DPadY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl GamepadAxis {
+    fn from_gilrs(axis: gilrs::Axis) -> Option<Self> {
+        use gilrs::Axis as GiAx;
+        use GamepadAxis as SlAx;
+
+        Some(match axis {
+            GiAx::LeftStickX => SlAx::LeftStickX,
+            GiAx::LeftStickY => SlAx::LeftStickY,
+            GiAx::LeftZ => SlAx::LeftZ,
+            GiAx::RightStickX => SlAx::RightStickX,
+            GiAx::RightStickY => SlAx::RightStickY,
+            GiAx::RightZ => SlAx::RightZ,
+            GiAx::DPadX => SlAx::DPadX,
+            GiAx::DPadY => SlAx::DPadY,
+            GiAx::Unknown => return None,
+        })
+    }
+}
+
 #[derive(Debug, Hash, Eq, PartialEq, Copy, Clone)]
 enum InputType {
     KeyEvent(KeyCode),
     MouseButtonEvent(MouseButton),
+    GamepadButtonEvent(GamepadButton),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
+struct AnalogBinding<Axes> {
+    axis: Axes,
+    dead_zone: f32,
+}
+
+/// Zero out values within the dead zone, and rescale the rest so
+/// that the axis still covers its whole range.
+fn apply_dead_zone(value: f32, dead_zone: f32) -> f32 {
+    let magnitude = value.abs();
+    if magnitude <= dead_zone {
+        0.0
+    } else {
+        value.signum() * f32::min((magnitude - dead_zone) / (1.0 - dead_zone), 1.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum InputEffect<Axes, Buttons>
 where
     Axes: Eq + Hash + Clone,
     Buttons: Eq + Hash + Clone,
 {
     Axis(Axes, bool),
+    /// Set an axis straight to a position, for analog inputs.
+    AnalogAxis(Axes, f32),
     Button(Buttons, Option<Point2<f32>>),
     Cursor(Point2<f32>),
 }
@@ -390,9 +514,8 @@ impl Default for CursorState {
 struct AxisState {
     // Where the axis currently is, in [-1, 1]
     position: f32,
-    // Where the axis is moving towards.  Possible
+    // Where keys are moving the axis towards.  Possible
     // values are -1, 0, +1
-    // (or a continuous range for analog devices I guess)
     direction: f32,
     // Speed in units per second that the axis
     // moves towards the target value.
@@ -400,6 +523,10 @@ struct AxisState {
     // Speed in units per second that the axis will
     // fall back toward 0 if the input stops.
     gravity: f32,
+    // Where an analog input is holding the axis, in
+    // [-1, 1]. While no keys are held, the axis sits
+    // here instead of gravitating back to 0.
+    analog: f32,
 }
 
 impl Default for AxisState {
@@ -409,6 +536,7 @@ impl Default for AxisState {
             direction: 0.0,
             acceleration: 16.0,
             gravity: 12.0,
+            analog: 0.0,
         }
     }
 }
@@ -421,8 +549,8 @@ struct ButtonState {
 }
 
 /// A struct that contains a mapping from physical input events
-/// (currently just `KeyCode`s) to whatever your logical Axis/Button
-/// types are.
+/// (keys, mouse buttons and game pad buttons and axes) to whatever
+/// your logical Axis/Button types are.
 pub struct InputBinding<Axes, Buttons>
 where
     Axes: Hash + Eq + Clone,
@@ -432,6 +560,8 @@ where
     // instead of BTreeMap. ♥?
     // Binding of keys to input values.
     bindings: HashMap<InputType, InputEffect<Axes, Buttons>>,
+    // Binding of analog game pad inputs to axes.
+    analog_bindings: HashMap<GamepadAxis, AnalogBinding<Axes>>,
 }
 
 impl<Axes, Buttons> InputBinding<Axes, Buttons>
@@ -442,6 +572,7 @@ where
     pub fn new() -> Self {
         InputBinding {
             bindings: HashMap::new(),
+            analog_bindings: HashMap::new(),
         }
     }
 
@@ -473,6 +604,53 @@ where
         self
     }
 
+    pub fn bind_gamepad_button_to_button(
+        mut self,
+        gamepad_button: GamepadButton,
+        button: Buttons,
+    ) -> Self {
+        self.bindings.insert(
+            InputType::GamepadButtonEvent(gamepad_button),
+            InputEffect::Button(button, None),
+        );
+        self
+    }
+
+    /// Adds a binding connecting a game pad button, such as one
+    /// on the D-pad, to the given logical axis. The axis moves
+    /// the same way it does for keys.
+    pub fn bind_gamepad_button_to_axis(
+        mut self,
+        gamepad_button: GamepadButton,
+        axis: Axes,
+        positive: bool,
+    ) -> Self {
+        self.bindings.insert(
+            InputType::GamepadButtonEvent(gamepad_button),
+            InputEffect::Axis(axis, positive),
+        );
+        self
+    }
+
+    /// Adds a binding connecting an analog game pad input to the
+    /// given logical axis. Values closer to 0 than `dead_zone`
+    /// count as 0, and the rest are rescaled to still reach ±1.
+    pub fn bind_gamepad_axis(
+        mut self,
+        gamepad_axis: GamepadAxis,
+        axis: Axes,
+        dead_zone: f32,
+    ) -> Self {
+        self.analog_bindings.insert(
+            gamepad_axis,
+            AnalogBinding {
+                axis,
+                dead_zone: dead_zone.clamp(0.0, 1.0),
+            },
+        );
+        self
+    }
+
     /// Takes an physical input type and turns it into a logical input type (keycode -> axis/button).
     pub fn resolve_keycode(&self, keycode: KeyCode) -> Option<InputEffect<Axes, Buttons>> {
         self.bindings.get(&InputType::KeyEvent(keycode)).cloned()
@@ -488,6 +666,75 @@ where
             .cloned()
             .map(|eff| eff.with_point(point))
     }
+
+    pub fn resolve_gamepad_button(
+        &self,
+        gamepad_button: GamepadButton,
+    ) -> Option<InputEffect<Axes, Buttons>> {
+        self.bindings
+            .get(&InputType::GamepadButtonEvent(gamepad_button))
+            .cloned()
+    }
+
+    /// Resolves an analog game pad input to an `AnalogAxis`
+    /// effect, with the binding's dead zone applied.
+    pub fn resolve_gamepad_axis(
+        &self,
+        gamepad_axis: GamepadAxis,
+        value: f32,
+    ) -> Option<InputEffect<Axes, Buttons>> {
+        self.analog_bindings.get(&gamepad_axis).map(|binding| {
+            InputEffect::AnalogAxis(
+                binding.axis.clone(),
+                apply_dead_zone(value, binding.dead_zone),
+            )
+        })
+    }
+
+    /// The effects which let go of everything bound to a game
+    /// pad, for when it's disconnected: every bound button is
+    /// released and every bound analog axis goes back to 0.
+    pub fn resolve_gamepad_disconnect(&self) -> Vec<(InputEffect<Axes, Buttons>, bool)> {
+        let buttons = self
+            .bindings
+            .iter()
+            .filter(|(input, _)| matches!(input, InputType::GamepadButtonEvent(_)))
+            .map(|(_, effect)| (effect.clone(), false));
+        let axes = self
+            .analog_bindings
+            .values()
+            .map(|binding| (InputEffect::AnalogAxis(binding.axis.clone(), 0.0), true));
+        buttons.chain(axes).collect()
+    }
+
+    /// Resolves a game pad event to an effect, and whether it
+    /// started or stopped, ready for `InputState::update_effect`.
+    /// Disconnecting resolves to several effects at once, so it
+    /// goes through `resolve_gamepad_disconnect` instead.
+    pub fn resolve_gamepad_event(
+        &self,
+        event: &GamepadEvent,
+    ) -> Option<(InputEffect<Axes, Buttons>, bool)> {
+        self.resolve_gamepad_event_kind(event.kind)
+    }
+
+    fn resolve_gamepad_event_kind(
+        &self,
+        kind: GamepadEventKind,
+    ) -> Option<(InputEffect<Axes, Buttons>, bool)> {
+        match kind {
+            GamepadEventKind::ButtonPressed(button) => {
+                self.resolve_gamepad_button(button).map(|eff| (eff, true))
+            }
+            GamepadEventKind::ButtonReleased(button) => {
+                self.resolve_gamepad_button(button).map(|eff| (eff, false))
+            }
+            GamepadEventKind::AxisChanged(axis, value) => self
+                .resolve_gamepad_axis(axis, value)
+                .map(|eff| (eff, true)),
+            GamepadEventKind::Connected | GamepadEventKind::Disconnected => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -502,6 +749,8 @@ where
     buttons: HashMap<Buttons, ButtonState>,
     // Input state for the mouse cursor
     mouse: CursorState,
+    // Effects applied since the recording was last drained, if recording.
+    recording: Option<Vec<(InputEffect<Axes, Buttons>, bool)>>,
 }
 
 impl<Axes, Buttons> InputState<Axes, Buttons>
@@ -514,16 +763,45 @@ where
             axes: HashMap::new(),
             buttons: HashMap::new(),
             mouse: CursorState::default(),
+            recording: None,
         }
     }
 
+    /// Start recording every effect passed to `update_effect`, for
+    /// later retrieval with `drain_recorded`.
+    pub fn start_recording(&mut self) {
+        self.recording.get_or_insert_with(Vec::new);
+    }
+
+    /// Stop recording effects, discarding any which haven't been drained.
+    pub fn stop_recording(&mut self) {
+        self.recording = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Take all effects recorded since the last call, in the order
+    /// they were applied, along with whether they were started or stopped.
+    pub fn drain_recorded(&mut self) -> Vec<(InputEffect<Axes, Buttons>, bool)> {
+        self.recording
+            .as_mut()
+            .map(|recorded| recorded.drain(..).collect())
+            .unwrap_or_default()
+    }
+
     /// Updates the logical input state based on the actual
     /// physical input state.  Should be called in your update()
     /// handler.
     /// So, it will do things like move the axes and so on.
     pub fn update(&mut self, dt: f32) {
         for (_axis, axis_status) in self.axes.iter_mut() {
-            if axis_status.direction != 0.0 {
+            if axis_status.direction == 0.0 && axis_status.analog != 0.0 {
+                // Analog inputs set the position directly,
+                // unless a key is overriding them.
+                axis_status.position = axis_status.analog;
+            } else if axis_status.direction != 0.0 {
                 // Accelerate the axis towards the
                 // input'ed direction.
                 let vel = axis_status.acceleration * dt;
@@ -579,6 +857,37 @@ where
         self.update_effect(InputEffect::Axis(axis, positive), false);
     }
 
+    /// Sets an axis to the position of an analog input, which
+    /// should already be in [-1, 1] with any dead zone applied.
+    pub fn update_analog_axis(&mut self, axis: Axes, position: f32) {
+        self.update_effect(InputEffect::AnalogAxis(axis, position), true);
+    }
+
+    /// Resolves and applies a game pad event with the given
+    /// bindings. Call this with each event from `Gamepads::poll`
+    /// for the player whose bindings and state these are.
+    pub fn update_gamepad_event(
+        &mut self,
+        binding: &InputBinding<Axes, Buttons>,
+        event: &GamepadEvent,
+    ) {
+        self.update_gamepad_event_kind(binding, event.kind);
+    }
+
+    fn update_gamepad_event_kind(
+        &mut self,
+        binding: &InputBinding<Axes, Buttons>,
+        kind: GamepadEventKind,
+    ) {
+        if kind == GamepadEventKind::Disconnected {
+            for (effect, started) in binding.resolve_gamepad_disconnect() {
+                self.update_effect(effect, started);
+            }
+        } else if let Some((effect, started)) = binding.resolve_gamepad_event_kind(kind) {
+            self.update_effect(effect, started);
+        }
+    }
+
     /// This method should be called by your mouse_motion_event handler.
     pub fn update_mouse_position(&mut self, position: Point2<f32>) {
         self.update_effect(InputEffect::Cursor(position), false);
@@ -586,6 +895,10 @@ where
 
     /// Takes an InputEffect and actually applies it.
     pub fn update_effect(&mut self, effect: InputEffect<Axes, Buttons>, started: bool) {
+        if let Some(recorded) = self.recording.as_mut() {
+            recorded.push((effect.clone(), started));
+        }
+
         match effect {
             InputEffect::Axis(axis, positive) => {
                 let f = || AxisState::default();
@@ -599,6 +912,14 @@ where
                     axis_status.direction = 0.0;
                 }
             }
+            InputEffect::AnalogAxis(axis, position) => {
+                let f = || AxisState::default();
+                let axis_status = self.axes.entry(axis).or_insert_with(f);
+                axis_status.analog = position.clamp(-1.0, 1.0);
+                if axis_status.direction == 0.0 {
+                    axis_status.position = axis_status.analog;
+                }
+            }
             InputEffect::Button(button, point) => {
                 let f = || ButtonState::default();
                 let button_status = self.buttons.entry(button).or_insert_with(f);
@@ -620,7 +941,11 @@ where
     pub fn get_axis_raw(&self, axis: Axes) -> f32 {
         let d = AxisState::default();
         let axis_status = self.axes.get(&axis).unwrap_or(&d);
-        axis_status.direction
+        if axis_status.direction != 0.0 {
+            axis_status.direction
+        } else {
+            axis_status.analog
+        }
     }
 
     fn get_button(&self, button: Buttons) -> ButtonState {
@@ -669,6 +994,7 @@ where
         for (_axis, axis_status) in self.axes.iter_mut() {
             axis_status.position = 0.0;
             axis_status.direction = 0.0;
+            axis_status.analog = 0.0;
         }
 
         for (_button, button_status) in self.buttons.iter_mut() {
@@ -682,6 +1008,350 @@ where
     }
 }
 
+/// A connected (or once connected) game pad.
+#[derive(Debug, Hash, Eq, PartialEq, Copy, Clone)]
+pub struct GamepadId(gilrs::GamepadId);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GamepadEventKind {
+    Connected,
+    Disconnected,
+    ButtonPressed(GamepadButton),
+    ButtonReleased(GamepadButton),
+    /// The raw value of an analog input, before any dead zone.
+    AxisChanged(GamepadAxis, f32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GamepadEvent {
+    pub id: GamepadId,
+    /// The player the game pad is assigned to, if any.
+    pub player: Option<usize>,
+    pub kind: GamepadEventKind,
+}
+
+/// Game pads, through gilrs, and which player each of them
+/// belongs to.
+///
+/// There's a fixed number of player slots. Game pads are
+/// assigned to the first free slot when they're connected, and
+/// keep their slot when they're disconnected, so a player who
+/// reconnects their game pad gets it back. Use `assign` to move
+/// game pads between players or free up slots.
+pub struct Gamepads {
+    gilrs: gilrs::Gilrs,
+    players: Vec<Option<GamepadId>>,
+    // Rumble effects which are playing. Dropping an effect stops it.
+    rumble: HashMap<GamepadId, ff::Effect>,
+}
+
+impl fmt::Debug for Gamepads {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Gamepads")
+            .field("players", &self.players)
+            .finish()
+    }
+}
+
+impl Gamepads {
+    /// Start listening for game pads, with slots for `players`
+    /// players. Game pads which are already connected are
+    /// assigned in the order gilrs lists them. On platforms gilrs
+    /// doesn't support, no game pads are ever connected.
+    pub fn new(players: usize) -> Result<Self> {
+        let gilrs = match gilrs::Gilrs::new() {
+            Ok(gilrs) => gilrs,
+            Err(gilrs::Error::NotImplemented(dummy)) => {
+                log::warn!("game pads aren't supported on this platform");
+                dummy
+            }
+            Err(err) => bail!("error initializing game pads: {}", err),
+        };
+
+        let mut gamepads = Self {
+            gilrs,
+            players: vec![None; players],
+            rumble: HashMap::new(),
+        };
+
+        let connected = gamepads.connected().collect::<Vec<_>>();
+        for id in connected {
+            gamepads.assign_free_slot(id);
+        }
+
+        Ok(gamepads)
+    }
+
+    /// Take the next game pad event, or `None` once there are no
+    /// more this update.
+    pub fn poll(&mut self) -> Option<GamepadEvent> {
+        while let Some(gilrs::Event { id, event, .. }) = self.gilrs.next_event() {
+            let id = GamepadId(id);
+            let kind = match event {
+                gilrs::EventType::Connected => {
+                    self.assign_free_slot(id);
+                    GamepadEventKind::Connected
+                }
+                gilrs::EventType::Disconnected => {
+                    self.rumble.remove(&id);
+                    GamepadEventKind::Disconnected
+                }
+                gilrs::EventType::ButtonPressed(button, _) => {
+                    match GamepadButton::from_gilrs(button) {
+                        Some(button) => GamepadEventKind::ButtonPressed(button),
+                        None => continue,
+                    }
+                }
+                gilrs::EventType::ButtonReleased(button, _) => {
+                    match GamepadButton::from_gilrs(button) {
+                        Some(button) => GamepadEventKind::ButtonReleased(button),
+                        None => continue,
+                    }
+                }
+                gilrs::EventType::ButtonChanged(gilrs::Button::LeftTrigger2, value, _) => {
+                    GamepadEventKind::AxisChanged(GamepadAxis::LeftTrigger, value)
+                }
+                gilrs::EventType::ButtonChanged(gilrs::Button::RightTrigger2, value, _) => {
+                    GamepadEventKind::AxisChanged(GamepadAxis::RightTrigger, value)
+                }
+                gilrs::EventType::AxisChanged(axis, value, _) => {
+                    match GamepadAxis::from_gilrs(axis) {
+                        Some(axis) => GamepadEventKind::AxisChanged(axis, value),
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+
+            return Some(GamepadEvent {
+                id,
+                player: self.player(id),
+                kind,
+            });
+        }
+
+        None
+    }
+
+    /// The game pads which are currently connected.
+    pub fn connected(&self) -> impl Iterator<Item = GamepadId> + '_ {
+        self.gilrs.gamepads().map(|(id, _)| GamepadId(id))
+    }
+
+    pub fn is_connected(&self, id: GamepadId) -> bool {
+        self.gilrs.connected_gamepad(id.0).is_some()
+    }
+
+    /// The name of a connected game pad.
+    pub fn name(&self, id: GamepadId) -> Option<&str> {
+        self.gilrs
+            .connected_gamepad(id.0)
+            .map(|gamepad| gamepad.name())
+    }
+
+    pub fn players(&self) -> usize {
+        self.players.len()
+    }
+
+    /// The player a game pad is assigned to.
+    pub fn player(&self, id: GamepadId) -> Option<usize> {
+        self.players.iter().position(|&slot| slot == Some(id))
+    }
+
+    /// The game pad assigned to a player.
+    pub fn gamepad(&self, player: usize) -> Option<GamepadId> {
+        self.players.get(player).copied().flatten()
+    }
+
+    /// Assign a game pad to a player, taking it away from
+    /// whichever player had it before, or free up the player's
+    /// slot with `None`.
+    pub fn assign(&mut self, player: usize, id: Option<GamepadId>) -> Result<()> {
+        ensure!(
+            player < self.players.len(),
+            "player {} is out of range; there are only {} players",
+            player,
+            self.players.len()
+        );
+
+        if let Some(id) = id {
+            if let Some(previous) = self.player(id) {
+                self.players[previous] = None;
+            }
+        }
+
+        self.players[player] = id;
+        Ok(())
+    }
+
+    fn assign_free_slot(&mut self, id: GamepadId) {
+        if self.player(id).is_some() {
+            return;
+        }
+
+        if let Some(slot) = self.players.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(id);
+        }
+    }
+
+    /// Rumble a player's game pad for `duration`, with the strong
+    /// (low frequency) and weak (high frequency) motors at the
+    /// given strengths in [0, 1]. Replaces any rumble which is
+    /// already playing on it. Returns `false` without doing
+    /// anything if the player has no connected game pad or it
+    /// doesn't support force feedback.
+    pub fn rumble(
+        &mut self,
+        player: usize,
+        strong: f32,
+        weak: f32,
+        duration: Duration,
+    ) -> Result<bool> {
+        let id = match self.gamepad(player) {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+
+        match self.gilrs.connected_gamepad(id.0) {
+            Some(gamepad) if gamepad.is_ff_supported() => {}
+            _ => return Ok(false),
+        }
+
+        let magnitude = |strength: f32| (strength.clamp(0.0, 1.0) * u16::MAX as f32) as u16;
+        let motor = |kind| ff::BaseEffect {
+            kind,
+            scheduling: ff::Replay {
+                play_for: ff::Ticks::from_ms(duration.as_millis() as u32),
+                ..ff::Replay::default()
+            },
+            envelope: ff::Envelope::default(),
+        };
+
+        let effect = ff::EffectBuilder::new()
+            .add_effect(motor(ff::BaseEffectType::Strong {
+                magnitude: magnitude(strong),
+            }))
+            .add_effect(motor(ff::BaseEffectType::Weak {
+                magnitude: magnitude(weak),
+            }))
+            .gamepads(&[id.0])
+            .finish(&mut self.gilrs)?;
+        effect.play()?;
+
+        self.rumble.insert(id, effect);
+        Ok(true)
+    }
+
+    /// Stop any rumble playing on a player's game pad.
+    pub fn stop_rumble(&mut self, player: usize) {
+        if let Some(id) = self.gamepad(player) {
+            self.rumble.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod gamepad_tests {
+    use super::*;
+
+    #[derive(Hash, Eq, PartialEq, Copy, Clone, Debug)]
+    enum Axes {
+        Horz,
+    }
+
+    #[test]
+    fn analog_axes_skip_acceleration() {
+        let ib = InputBinding::<Axes, ()>::new()
+            .bind_gamepad_axis(GamepadAxis::LeftStickX, Axes::Horz, 0.2)
+            .bind_key_to_axis(KeyCode::Right, Axes::Horz, true);
+
+        assert_eq!(
+            ib.resolve_gamepad_axis(GamepadAxis::LeftStickX, 0.1),
+            Some(InputEffect::AnalogAxis(Axes::Horz, 0.0))
+        );
+        assert_eq!(ib.resolve_gamepad_axis(GamepadAxis::LeftStickY, 0.5), None);
+
+        let mut im = InputState::new();
+        match ib.resolve_gamepad_axis(GamepadAxis::LeftStickX, -0.6) {
+            Some(effect) => im.update_effect(effect, true),
+            None => panic!("expected the stick to be bound"),
+        }
+        assert!((im.get_axis(Axes::Horz) + 0.5).abs() < 1e-6);
+        im.update(0.16);
+        assert!((im.get_axis(Axes::Horz) + 0.5).abs() < 1e-6);
+
+        // Back in the dead zone, the axis is released immediately.
+        im.update_analog_axis(Axes::Horz, 0.0);
+        im.update(0.16);
+        assert_eq!(im.get_axis(Axes::Horz), 0.0);
+
+        // Keys still accelerate as usual.
+        im.update_axis_start(Axes::Horz, true);
+        im.update(0.01);
+        assert!(im.get_axis(Axes::Horz) < 1.0);
+    }
+
+    #[test]
+    fn disconnecting_releases_the_game_pad() {
+        #[derive(Hash, Eq, PartialEq, Copy, Clone, Debug)]
+        enum Buttons {
+            Fire,
+        }
+
+        let ib = InputBinding::<Axes, Buttons>::new()
+            .bind_gamepad_axis(GamepadAxis::LeftStickX, Axes::Horz, 0.0)
+            .bind_gamepad_button_to_button(GamepadButton::South, Buttons::Fire);
+        let mut im = InputState::new();
+
+        // gilrs has no way to make up a game pad ID, so this skips
+        // straight to the event's kind.
+        im.update_gamepad_event_kind(
+            &ib,
+            GamepadEventKind::AxisChanged(GamepadAxis::LeftStickX, 0.75),
+        );
+        im.update_gamepad_event_kind(&ib, GamepadEventKind::ButtonPressed(GamepadButton::South));
+        im.update(0.16);
+        assert_eq!(im.get_axis(Axes::Horz), 0.75);
+        assert!(im.get_button_down(Buttons::Fire));
+
+        im.update_gamepad_event_kind(&ib, GamepadEventKind::Disconnected);
+        im.update(0.16);
+        assert_eq!(im.get_axis(Axes::Horz), 0.0);
+        assert_eq!(im.get_axis_raw(Axes::Horz), 0.0);
+        assert!(!im.get_button_down(Buttons::Fire));
+    }
+
+    #[test]
+    fn keys_and_sticks_share_an_axis() {
+        let mut im = InputState::<Axes, ()>::new();
+        im.update_analog_axis(Axes::Horz, -0.5);
+
+        // A key overrides the stick while it's held...
+        im.update_axis_start(Axes::Horz, true);
+        assert_eq!(im.get_axis_raw(Axes::Horz), 1.0);
+        im.update(1.0);
+        assert_eq!(im.get_axis(Axes::Horz), 1.0);
+
+        // ...and the axis goes back to where the stick is held once it's released,
+        // rather than gravitating to 0.
+        im.update_axis_stop(Axes::Horz, true);
+        assert_eq!(im.get_axis_raw(Axes::Horz), -0.5);
+        for _ in 0..10 {
+            im.update(0.16);
+            assert_eq!(im.get_axis(Axes::Horz), -0.5);
+        }
+
+        // Letting go of the stick while a key is held leaves the key in charge.
+        im.update_axis_start(Axes::Horz, false);
+        im.update_analog_axis(Axes::Horz, 0.0);
+        im.update(1.0);
+        assert_eq!(im.get_axis(Axes::Horz), -1.0);
+        im.update_axis_stop(Axes::Horz, false);
+        im.update(1.0);
+        assert_eq!(im.get_axis(Axes::Horz), 0.0);
+    }
+}
+
 #[cfg(feature = "ggez")]
 #[cfg(test)]
 mod tests {
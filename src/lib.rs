@@ -8,9 +8,10 @@
 
 use {
     anyhow::*,
+    atomic_refcell::AtomicRefCell,
     crossbeam_channel::{Receiver, Sender},
     derivative::*,
-    hashbrown::HashMap,
+    hashbrown::{HashMap, HashSet},
     nalgebra as na,
     rlua::prelude::*,
     serde::{Deserialize, Serialize},
@@ -22,7 +23,13 @@ use {
         error::Error as StdError,
         fmt,
         io::{Read, Write},
-        iter,
+        iter, mem,
+        panic::Location,
+        sync::{
+            atomic::{self, AtomicI64},
+            Arc,
+        },
+        time::{Duration, Instant},
     },
     string_cache::DefaultAtom,
     thunderdome::{Arena, Index},
@@ -44,8 +51,11 @@ pub mod graphics;
 pub mod hierarchy;
 pub mod input;
 pub mod math;
+pub mod mods;
+pub mod pack;
 pub mod path_clean;
 pub mod persist;
+pub mod replay;
 pub mod resources;
 pub mod scene;
 pub mod sprite;
@@ -87,7 +97,10 @@ pub mod sludge {
 #[doc(hidden)]
 pub use crate::sludge::*;
 
-use crate::{api::EntityUserDataRegistry, dispatcher::Dispatcher, ecs::World, resources::*};
+use crate::{
+    api::EntityUserDataRegistry, dispatcher::Dispatcher, ecs::World, filesystem::Filesystem,
+    persist::Migrations, resources::*,
+};
 
 pub trait SludgeResultExt: Sized {
     type Ok;
@@ -130,15 +143,80 @@ impl<T, E: fmt::Debug> SludgeResultExt for Result<T, E> {
 }
 
 const RESOURCES_REGISTRY_KEY: &'static str = "sludge.resources";
+const SCHEDULER_SLOTS_REGISTRY_KEY: &'static str = "sludge.scheduler_slots";
+const THREAD_ERROR_HANDLER_REGISTRY_KEY: &'static str = "sludge.thread_error_handler";
 
 pub trait SludgeLuaContextExt<'lua> {
     fn resources(self) -> UnifiedResources<'static>;
     fn spawn<T: ToLua<'lua>>(self, task: T) -> LuaResult<LuaThread<'lua>>;
+    fn spawn_in<G: Into<ThreadGroup>, T: ToLua<'lua>>(
+        self,
+        group: G,
+        task: T,
+    ) -> LuaResult<LuaThread<'lua>>;
+    fn kill(self, thread: LuaThread<'lua>) -> LuaResult<()>;
+    fn kill_group<G: Into<ThreadGroup>>(self, group: G) -> LuaResult<()>;
     fn broadcast<S: AsRef<str>, T: ToLuaMulti<'lua>>(self, event_name: S, args: T)
         -> LuaResult<()>;
     fn notify<T: ToLuaMulti<'lua>>(self, thread: LuaThread<'lua>, args: T) -> LuaResult<()>;
 }
 
+/// Look up the statistics of a thread from inside Lua, where the scheduler itself
+/// can't be fetched.
+pub(crate) fn lua_thread_stats<'lua>(
+    lua: LuaContext<'lua>,
+    thread: LuaThread<'lua>,
+) -> LuaResult<Option<LuaTable<'lua>>> {
+    let slots = lua.named_registry_value::<_, LuaTable>(SCHEDULER_SLOTS_REGISTRY_KEY)?;
+    let slot = match slots.get::<_, Option<u32>>(thread)? {
+        Some(slot) => slot,
+        None => return Ok(None),
+    };
+
+    let resources = lua.resources();
+    let channel = resources.fetch::<SchedulerQueueChannel>();
+    let stats = channel.stats.borrow();
+    stats
+        .get(&slot)
+        .map(|stats| stats.to_lua_table(lua))
+        .transpose()
+}
+
+pub(crate) fn spawn_thread<'lua, T: ToLua<'lua>>(
+    lua: LuaContext<'lua>,
+    group: Option<ThreadGroup>,
+    spawned_at: Option<String>,
+    task: T,
+) -> LuaResult<LuaThread<'lua>> {
+    let (thread, entry) = match task.to_lua(lua)? {
+        LuaValue::Function(f) => (
+            lua.create_thread(f.clone())?,
+            Some(lua.create_registry_value(f)?),
+        ),
+        LuaValue::Thread(th) => (th, None),
+        _ => {
+            return Err(LuaError::FromLuaConversionError {
+                to: "thread or function",
+                from: "lua value",
+                message: None,
+            })
+        }
+    };
+
+    let key = lua.create_registry_value(thread.clone())?;
+    lua.resources()
+        .fetch::<SchedulerQueueChannel>()
+        .spawn
+        .try_send(Spawn {
+            thread: key,
+            entry,
+            group,
+            spawned_at,
+        })
+        .unwrap();
+    Ok(thread)
+}
+
 impl<'lua> SludgeLuaContextExt<'lua> for LuaContext<'lua> {
     fn resources(self) -> UnifiedResources<'static> {
         self.named_registry_value::<_, UnifiedResources>(RESOURCES_REGISTRY_KEY)
@@ -146,26 +224,39 @@ impl<'lua> SludgeLuaContextExt<'lua> for LuaContext<'lua> {
             .unwrap()
     }
 
+    #[track_caller]
     fn spawn<T: ToLua<'lua>>(self, task: T) -> LuaResult<LuaThread<'lua>> {
-        let thread = match task.to_lua(self)? {
-            LuaValue::Function(f) => self.create_thread(f)?,
-            LuaValue::Thread(th) => th,
-            _ => {
-                return Err(LuaError::FromLuaConversionError {
-                    to: "thread or function",
-                    from: "lua value",
-                    message: None,
-                })
-            }
-        };
+        let spawned_at = Location::caller().to_string();
+        spawn_thread(self, None, Some(spawned_at), task)
+    }
+
+    #[track_caller]
+    fn spawn_in<G: Into<ThreadGroup>, T: ToLua<'lua>>(
+        self,
+        group: G,
+        task: T,
+    ) -> LuaResult<LuaThread<'lua>> {
+        let spawned_at = Location::caller().to_string();
+        spawn_thread(self, Some(group.into()), Some(spawned_at), task)
+    }
 
-        let key = self.create_registry_value(thread.clone())?;
+    fn kill(self, thread: LuaThread<'lua>) -> LuaResult<()> {
+        let key = self.create_registry_value(thread)?;
         self.resources()
             .fetch::<SchedulerQueueChannel>()
-            .spawn
-            .try_send(key)
+            .kill
+            .try_send(Kill::Thread(key))
             .unwrap();
-        Ok(thread)
+        Ok(())
+    }
+
+    fn kill_group<G: Into<ThreadGroup>>(self, group: G) -> LuaResult<()> {
+        self.resources()
+            .fetch::<SchedulerQueueChannel>()
+            .kill
+            .try_send(Kill::Group(group.into()))
+            .unwrap();
+        Ok(())
     }
 
     fn broadcast<S: AsRef<str>, T: ToLuaMulti<'lua>>(
@@ -269,6 +360,7 @@ impl Space {
         local.insert(scheduler);
         local.insert(queue_handle);
         local.insert(EntityUserDataRegistry::new());
+        local.insert(Migrations::new());
 
         let local = SharedResources::from(local);
         let resources = UnifiedResources { local, global };
@@ -364,7 +456,15 @@ impl Space {
             .context(|lua| dispatcher.refresh(lua, local_resources, Some(global_resources)))
     }
 
+    /// Run all systems in the dispatcher. If the scheduler's error handler decided to
+    /// propagate any Lua thread errors since the last dispatch, the first of them is
+    /// returned instead.
     pub fn dispatch(&self, dispatcher: &mut Dispatcher) -> Result<()> {
+        let propagated = self.scheduler_mut().take_propagated_errors();
+        if let Some(error) = propagated.into_iter().next() {
+            return Err(Error::new(error));
+        }
+
         self.lua
             .context(|lua| dispatcher.update(lua, &self.resources))
     }
@@ -389,13 +489,26 @@ impl Space {
         self.fetch_mut()
     }
 
+    /// Set the scheduler's watchdog budget. See [`Scheduler::set_watchdog_budget`].
+    ///
+    /// The watchdog's instruction count hook is only installed while a budget is set.
+    /// Threads inherit the hook when they're created, so only threads spawned after
+    /// the budget is set are held to it.
+    pub fn set_watchdog_budget(&self, budget: Option<u64>) {
+        let mut scheduler = self.scheduler_mut();
+        scheduler.set_watchdog_budget(budget);
+        scheduler.update_watchdog_hook(&self.lua);
+    }
+
+    /// Write a versioned save of this space. See [`persist::save`].
     pub fn save<W: Write>(&self, writer: W) -> Result<()> {
-        self.lua.context(|lua| persist::persist(lua, self, writer))
+        self.lua.context(|lua| persist::save(lua, self, writer))
     }
 
+    /// Load a versioned save into this space, migrating it if it was written by an
+    /// older version. See [`persist::load`].
     pub fn load<R: Read>(&self, reader: R) -> Result<()> {
-        self.lua
-            .context(|lua| persist::unpersist(lua, self, reader))
+        self.lua.context(|lua| persist::load(lua, self, reader))
     }
 }
 
@@ -411,6 +524,10 @@ impl Space {
 /// previous indices referring to it become invalidated. Popping a wakeup
 /// which no longer has a valid thread is not an error, but simply to be
 /// ignored.
+///
+/// A `Timeout` is a timed wakeup for a thread which yielded a table of events
+/// and a timeout. Unlike a `Timed` wakeup, it resumes the thread with no
+/// arguments, so that it can tell a timeout apart from an event.
 #[derive(Debug)]
 pub enum Wakeup {
     Notify {
@@ -426,13 +543,19 @@ pub enum Wakeup {
         thread: Index,
         scheduled_for: u64,
     },
+    Timeout {
+        thread: Index,
+        scheduled_for: u64,
+    },
 }
 
 impl Wakeup {
     pub fn scheduled_for(&self) -> u64 {
         match self {
             Self::Notify { .. } | Self::Broadcast { .. } => 0,
-            Self::Timed { scheduled_for, .. } => *scheduled_for,
+            Self::Timed { scheduled_for, .. } | Self::Timeout { scheduled_for, .. } => {
+                *scheduled_for
+            }
         }
     }
 
@@ -440,7 +563,8 @@ impl Wakeup {
         match self {
             Self::Notify { thread, .. }
             | Self::Broadcast { thread, .. }
-            | Self::Timed { thread, .. } => *thread,
+            | Self::Timed { thread, .. }
+            | Self::Timeout { thread, .. } => *thread,
         }
     }
 }
@@ -484,6 +608,23 @@ impl Ord for Wakeup {
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct EventName(Atom);
 
+/// A named group of threads, which can be killed all at once; for example, all of
+/// the threads belonging to a boss or to a scene.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ThreadGroup(Atom);
+
+impl ThreadGroup {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a str> for ThreadGroup {
+    fn from(s: &'a str) -> Self {
+        Self(Atom::from(s))
+    }
+}
+
 pub type EventArgs = SmallVec<[LuaRegistryKey; 3]>;
 
 #[derive(Debug)]
@@ -498,10 +639,235 @@ pub enum Event {
     },
 }
 
+#[derive(Debug)]
+pub struct Spawn {
+    thread: LuaRegistryKey,
+    entry: Option<LuaRegistryKey>,
+    group: Option<ThreadGroup>,
+    spawned_at: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Kill {
+    Thread(LuaRegistryKey),
+    Group(ThreadGroup),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistRequestKind {
+    Save,
+    Load,
+}
+
+/// A request from a Lua thread to save to or load from a save slot. Requests are
+/// serviced by the scheduler between ticks, when no thread is running.
+#[derive(Debug)]
+pub struct PersistRequest {
+    kind: PersistRequestKind,
+    slot: String,
+    thread: LuaRegistryKey,
+}
+
+/// Profiling statistics for a single Lua thread.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadStats {
+    resumes: u64,
+    total_time: Duration,
+    last_resumed: Option<u64>,
+    spawned_at: Option<String>,
+
+    /// Resumes and (approximate) instructions executed during `last_resumed`.
+    tick_resumes: u32,
+    tick_instructions: u64,
+}
+
+impl ThreadStats {
+    fn new(spawned_at: Option<String>) -> Self {
+        Self {
+            spawned_at,
+            ..Self::default()
+        }
+    }
+
+    /// How many times the thread has been resumed.
+    pub fn resumes(&self) -> u64 {
+        self.resumes
+    }
+
+    /// Total wall time spent running the thread.
+    pub fn total_time(&self) -> Duration {
+        self.total_time
+    }
+
+    /// The tick on which the thread was last resumed, if it has been resumed at all.
+    pub fn last_resumed(&self) -> Option<u64> {
+        self.last_resumed
+    }
+
+    /// Where the thread was spawned from, as `chunk:line` for threads spawned from Lua
+    /// and `file:line:column` for threads spawned from Rust.
+    pub fn spawned_at(&self) -> Option<&str> {
+        self.spawned_at.as_deref()
+    }
+
+    /// How many times the thread was resumed on the tick it was last resumed on.
+    pub fn tick_resumes(&self) -> u32 {
+        self.tick_resumes
+    }
+
+    /// Roughly how many Lua instructions the thread executed on the tick it was last
+    /// resumed on, to a granularity of `Scheduler::WATCHDOG_GRANULARITY`.
+    pub fn tick_instructions(&self) -> u64 {
+        self.tick_instructions
+    }
+
+    fn to_lua_table<'lua>(&self, lua: LuaContext<'lua>) -> LuaResult<LuaTable<'lua>> {
+        let table = lua.create_table()?;
+        table.set("resumes", self.resumes)?;
+        table.set("total_time", self.total_time.as_secs_f64())?;
+        table.set("last_resumed", self.last_resumed)?;
+        table.set("spawned_at", self.spawned_at.as_deref())?;
+        table.set("tick_resumes", self.tick_resumes)?;
+        table.set("tick_instructions", self.tick_instructions)?;
+        Ok(table)
+    }
+}
+
+pub(crate) type SharedThreadStats = Arc<AtomicRefCell<HashMap<u32, ThreadStats>>>;
+
+/// Instruction budget shared between the scheduler and the watchdog hook. `remaining`
+/// is only meaningful while a thread is being resumed; otherwise, it's `i64::MAX` so
+/// that code running outside of the scheduler is never interrupted.
+#[derive(Debug)]
+struct WatchdogFuel {
+    remaining: AtomicI64,
+}
+
+impl WatchdogFuel {
+    fn new() -> Self {
+        Self {
+            remaining: AtomicI64::new(i64::MAX),
+        }
+    }
+
+    fn fill(&self, amount: u64) {
+        let amount = amount.min(i64::MAX as u64) as i64;
+        self.remaining.store(amount, atomic::Ordering::Relaxed);
+    }
+
+    fn drain(&self) -> i64 {
+        self.remaining.swap(i64::MAX, atomic::Ordering::Relaxed)
+    }
+
+    /// Called every `Scheduler::WATCHDOG_GRANULARITY` instructions, returning `false`
+    /// if the running thread is out of fuel.
+    fn burn(&self) -> bool {
+        let granularity = Scheduler::WATCHDOG_GRANULARITY as i64;
+        self.remaining
+            .fetch_sub(granularity, atomic::Ordering::Relaxed)
+            .saturating_sub(granularity)
+            >= 0
+    }
+}
+
+/// An error raised by a Lua thread, along with where the thread came from.
+#[derive(Debug, Clone)]
+pub struct ThreadError {
+    message: String,
+    spawned_at: Option<String>,
+    group: Option<ThreadGroup>,
+}
+
+impl ThreadError {
+    /// The error message, including a Lua traceback.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn spawned_at(&self) -> Option<&str> {
+        self.spawned_at.as_deref()
+    }
+
+    pub fn group(&self) -> Option<&ThreadGroup> {
+        self.group.as_ref()
+    }
+}
+
+impl fmt::Display for ThreadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "error in Lua thread spawned at {}: {}",
+            self.spawned_at.as_deref().unwrap_or("<unknown>"),
+            self.message
+        )
+    }
+}
+
+impl StdError for ThreadError {}
+
+/// What to do with a thread which raised an error, as decided by an error handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadErrorAction {
+    /// Kill the thread. This is the default.
+    Kill,
+    /// Kill the thread and spawn a new one in its place from the same function, in the
+    /// same group. Threads spawned from coroutines rather than functions are killed.
+    Restart,
+    /// Kill the thread and return the error from the next `Space::dispatch`.
+    Propagate,
+}
+
+impl<'lua> FromLua<'lua> for ThreadErrorAction {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        match Option::<LuaString>::from_lua(lua_value, lua)? {
+            None => Ok(Self::Kill),
+            Some(s) => match s.to_str()? {
+                "kill" => Ok(Self::Kill),
+                "restart" => Ok(Self::Restart),
+                "propagate" => Ok(Self::Propagate),
+                other => Err(LuaError::FromLuaConversionError {
+                    from: "string",
+                    to: "thread error action",
+                    message: Some(format!(
+                        "expected one of 'kill', 'restart', or 'propagate'; found '{}'",
+                        other
+                    )),
+                }),
+            },
+        }
+    }
+}
+
+pub type ThreadErrorHandler = Box<
+    dyn for<'lua> FnMut(LuaContext<'lua>, LuaThread<'lua>, &ThreadError) -> ThreadErrorAction
+        + Send
+        + Sync,
+>;
+
+/// Format a Lua error along with its traceback. rlua attaches a traceback to errors
+/// raised from resumed coroutines; errors raised from Rust callbacks carry theirs
+/// alongside the error which caused them.
+fn format_lua_error(error: &LuaError) -> String {
+    match error {
+        LuaError::CallbackError { traceback, cause } => {
+            let mut cause = &**cause;
+            while let LuaError::CallbackError { cause: inner, .. } = cause {
+                cause = &**inner;
+            }
+            format!("{}\n{}", cause, traceback)
+        }
+        other => other.to_string(),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SchedulerQueueChannel {
-    spawn: Sender<LuaRegistryKey>,
+    spawn: Sender<Spawn>,
+    kill: Sender<Kill>,
     event: Sender<Event>,
+    persist: Sender<PersistRequest>,
+    stats: SharedThreadStats,
 }
 
 /// The scheduler controls the execution of Lua "threads", under a cooperative
@@ -543,7 +909,65 @@ pub struct SchedulerQueueChannel {
 /// by setting a flag, yielding from the requesting thread, breaking from the
 /// scheduler, and then immediately serializing the resulting state, with the
 /// requesting thread given a special wakeup priority.
-#[derive(Debug)]
+///
+/// This is what `sludge.save(slot)` and `sludge.load(slot)` do. The requesting
+/// thread sends a `PersistRequest` and yields; at the end of the tick, the
+/// scheduler queues a `Notify` wakeup for it and then writes the save, so that
+/// the requesting thread is the first thing to run both on the next tick and
+/// after the save is loaded. Loading tears down all threads and `Persistent`
+/// entities and plays back the save in their place.
+///
+/// # Waiting on events
+///
+/// A thread yields an integer to sleep for that many ticks, and a string to
+/// wait for the event of that name; it's resumed with the event name followed by
+/// the broadcast arguments. To wait for whichever of several events happens
+/// first, a thread can yield a table of event names, optionally with a
+/// `timeout` field giving a number of ticks to wait at most:
+///
+/// ```lua
+/// local event, button = sludge.thread.yield { "confirm", "cancel", timeout = 300 }
+/// if event == nil then
+///     -- five seconds passed without any input.
+/// end
+/// ```
+///
+/// If the timeout expires first, the thread is resumed with no arguments. However
+/// the thread is woken, it's deregistered from every event it was waiting on, so
+/// that it's never woken twice by the same wait.
+///
+/// # Profiling
+///
+/// The scheduler keeps `ThreadStats` for every live thread, which can be queried
+/// through `Scheduler::thread_stats` or `sludge.thread.stats(thread)`. When the
+/// trampoline loop cap is exceeded, the threads resumed most often on that tick
+/// are logged along with where they were spawned from.
+///
+/// Setting a watchdog budget with `Space::set_watchdog_budget` limits how many
+/// Lua instructions a single thread may execute per tick. A thread which goes over
+/// its budget is aborted with an error, and killed if it catches the error and
+/// yields. A thread which keeps catching the error without ever yielding can't be
+/// stopped this way.
+///
+/// # Killing threads
+///
+/// Threads can be killed individually, or all at once by spawning them into a
+/// named `ThreadGroup`. Kills requested from Lua are processed the next time the
+/// scheduler regains control, so a thread which kills itself keeps running until
+/// it next yields. Killing a thread removes all of its pending wakeups and event
+/// registrations, and its slot is cleared so that it can no longer be notified.
+///
+/// # Errors
+///
+/// When a thread raises an error, it's logged along with its Lua traceback and the
+/// place the thread was spawned from. By default, the thread is then killed. An
+/// error handler set with `Scheduler::set_error_handler` or, from Lua, with
+/// `sludge.thread.set_error_handler(function(thread, message, spawned_at) ... end)`
+/// can instead choose to restart the thread, or to propagate the error out of the
+/// next `Space::dispatch`. Lua handlers return `"kill"`, `"restart"`, or
+/// `"propagate"`. A Rust handler takes precedence over a Lua one.
+#[derive(Derivative)]
+#[derivative(Debug)]
 pub struct Scheduler {
     /// Priority queue of scheduled threads, ordered by wakeup.
     queue: BinaryHeap<Wakeup>,
@@ -574,8 +998,41 @@ pub struct Scheduler {
     /// Shared channel for sending events to wake up sleeping threads.
     event_channel: Receiver<Event>,
 
+    /// Maps thread slots to the events the threads are registered in `waiting`
+    /// for, so that a woken thread can be deregistered from all of them.
+    registrations: HashMap<u32, SmallVec<[EventName; 2]>>,
+
+    /// Maps thread slots to the groups the threads were spawned into.
+    groups: HashMap<u32, ThreadGroup>,
+
+    /// Maps thread slots to profiling statistics. This is shared with the Lua API,
+    /// which can't fetch the scheduler while it's running.
+    stats: SharedThreadStats,
+
+    /// The per-tick instruction budget of a single thread, if the watchdog is enabled.
+    watchdog_budget: Option<u64>,
+
+    /// Fuel for the watchdog hook installed by `Space`.
+    watchdog_fuel: Arc<WatchdogFuel>,
+
+    /// Maps thread slots to the functions the threads were spawned from, so that
+    /// they can be restarted.
+    entry_points: HashMap<u32, LuaRegistryKey>,
+
+    #[derivative(Debug = "ignore")]
+    error_handler: Option<ThreadErrorHandler>,
+
+    /// Errors to be returned from the next `Space::dispatch`.
+    propagated_errors: Vec<ThreadError>,
+
     /// Shared channel for sending new threads to be scheduled.
-    spawn_channel: Receiver<LuaRegistryKey>,
+    spawn_channel: Receiver<Spawn>,
+
+    /// Shared channel for killing threads and thread groups.
+    kill_channel: Receiver<Kill>,
+
+    /// Shared channel for requesting saves and loads from Lua threads.
+    persist_channel: Receiver<PersistRequest>,
 
     /// "Discrete" time in "ticks" (60ths of a second, 60FPS)
     discrete: u64,
@@ -587,13 +1044,33 @@ pub struct Scheduler {
     continuous: f32,
 }
 
+/// The threads of a `Scheduler` and everything it knows about them, set aside while a
+/// save is played back in case playback fails.
+struct ScheduledThreads {
+    queue: BinaryHeap<Wakeup>,
+    waiting: HashMap<EventName, Vec<Index>>,
+    threads: Arena<LuaRegistryKey>,
+    slots: LuaRegistryKey,
+    event_args: Arena<EventArgs>,
+    registrations: HashMap<u32, SmallVec<[EventName; 2]>>,
+    groups: HashMap<u32, ThreadGroup>,
+    stats: HashMap<u32, ThreadStats>,
+    entry_points: HashMap<u32, LuaRegistryKey>,
+}
+
 impl Scheduler {
     pub const CHANNEL_BOUND: usize = 4096;
 
+    /// How many Lua instructions run between checks of the watchdog budget.
+    pub const WATCHDOG_GRANULARITY: u32 = 1000;
+
     pub(crate) fn new(lua: LuaContext) -> Result<(Self, SchedulerQueueChannel)> {
         let (spawn_sender, spawn_channel) = crossbeam_channel::bounded(Self::CHANNEL_BOUND);
+        let (kill_sender, kill_channel) = crossbeam_channel::bounded(Self::CHANNEL_BOUND);
         let (event_sender, event_channel) = crossbeam_channel::bounded(Self::CHANNEL_BOUND);
-        let slots = lua.create_registry_value(lua.create_table()?)?;
+        let (persist_sender, persist_channel) = crossbeam_channel::bounded(Self::CHANNEL_BOUND);
+        let slots = Self::create_slots(lua)?;
+        let stats = SharedThreadStats::default();
 
         Ok((
             Self {
@@ -603,20 +1080,41 @@ impl Scheduler {
                 threads: Arena::new(),
                 slots,
                 event_args: Arena::new(),
+                registrations: HashMap::new(),
+                groups: HashMap::new(),
+                stats: stats.clone(),
+                watchdog_budget: None,
+                watchdog_fuel: Arc::new(WatchdogFuel::new()),
+                entry_points: HashMap::new(),
+                error_handler: None,
+                propagated_errors: Vec::new(),
 
                 event_channel,
                 spawn_channel,
+                kill_channel,
+                persist_channel,
 
                 discrete: 0,
                 continuous: 0.,
             },
             SchedulerQueueChannel {
                 spawn: spawn_sender,
+                kill: kill_sender,
                 event: event_sender,
+                persist: persist_sender,
+                stats,
             },
         ))
     }
 
+    /// Create a new slots table. It's also stored under a named registry key, so that
+    /// the Lua API can look up threads' statistics while the scheduler is running.
+    fn create_slots(lua: LuaContext) -> LuaResult<LuaRegistryKey> {
+        let slots = lua.create_table()?;
+        lua.set_named_registry_value(SCHEDULER_SLOTS_REGISTRY_KEY, slots.clone())?;
+        lua.create_registry_value(slots)
+    }
+
     pub fn is_idle(&self) -> bool {
         self.queue.is_empty() || self.queue.peek().unwrap().scheduled_for() > self.discrete
     }
@@ -626,10 +1124,19 @@ impl Scheduler {
         lua: LuaContext<'lua>,
         slots: &LuaTable<'lua>,
     ) -> Result<()> {
-        for key in self.spawn_channel.try_iter() {
-            let thread = lua.registry_value::<LuaThread>(&key)?;
-            let index = self.threads.insert(key);
+        for spawn in self.spawn_channel.try_iter() {
+            let thread = lua.registry_value::<LuaThread>(&spawn.thread)?;
+            let index = self.threads.insert(spawn.thread);
             slots.set(thread, index.slot())?;
+            if let Some(group) = spawn.group {
+                self.groups.insert(index.slot(), group);
+            }
+            if let Some(entry) = spawn.entry {
+                self.entry_points.insert(index.slot(), entry);
+            }
+            self.stats
+                .borrow_mut()
+                .insert(index.slot(), ThreadStats::new(spawn.spawned_at));
             self.queue.push(Wakeup::Timed {
                 thread: index,
                 scheduled_for: 0,
@@ -691,22 +1198,429 @@ impl Scheduler {
         Ok(())
     }
 
+    /// Register a thread as waiting on an event.
+    fn register(&mut self, index: Index, event_name: EventName) {
+        let threads = self.waiting.entry(event_name.clone()).or_default();
+        if let Err(i) = threads.binary_search(&index) {
+            threads.insert(i, index);
+            self.registrations
+                .entry(index.slot())
+                .or_default()
+                .push(event_name);
+        }
+    }
+
+    /// Remove every event registration of the thread in the given slot.
+    fn deregister(&mut self, slot: u32) {
+        for event_name in self.registrations.remove(&slot).into_iter().flatten() {
+            if let Some(threads) = self.waiting.get_mut(&event_name) {
+                threads.retain(|index| index.slot() != slot);
+                if threads.is_empty() {
+                    self.waiting.remove(&event_name);
+                }
+            }
+        }
+    }
+
+    /// Remove a thread from the scheduler. Any wakeups or event registrations still
+    /// referring to it become stale, and are skipped or purged later.
+    fn remove_thread<'lua>(
+        &mut self,
+        slots: &LuaTable<'lua>,
+        thread: LuaThread<'lua>,
+        index: Index,
+    ) -> Result<()> {
+        slots.set(thread, LuaValue::Nil)?;
+        self.threads.remove(index);
+        self.deregister(index.slot());
+        self.groups.remove(&index.slot());
+        self.stats.borrow_mut().remove(&index.slot());
+        self.entry_points.remove(&index.slot());
+        Ok(())
+    }
+
+    /// Kill a thread, returning whether it was alive.
+    fn kill_thread<'lua>(
+        &mut self,
+        slots: &LuaTable<'lua>,
+        thread: LuaThread<'lua>,
+    ) -> Result<bool> {
+        let maybe_index = slots
+            .get::<_, Option<u32>>(thread.clone())?
+            .and_then(|slot| self.threads.contains_slot(slot));
+
+        match maybe_index {
+            Some(index) => {
+                log::trace!("killing Lua thread {:?}", index);
+                self.remove_thread(slots, thread, index)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Kill every thread in a group, returning how many threads were killed.
+    fn kill_thread_group<'lua>(
+        &mut self,
+        lua: LuaContext<'lua>,
+        slots: &LuaTable<'lua>,
+        group: &ThreadGroup,
+    ) -> Result<usize> {
+        let members = self
+            .groups
+            .iter()
+            .filter(|(_, g)| *g == group)
+            .map(|(&slot, _)| slot)
+            .collect::<Vec<_>>();
+
+        let mut killed = 0;
+        for slot in members {
+            match self.threads.contains_slot(slot) {
+                Some(index) => {
+                    let thread = lua.registry_value::<LuaThread>(&self.threads[index])?;
+                    self.remove_thread(slots, thread, index)?;
+                    killed += 1;
+                }
+                None => {
+                    self.groups.remove(&slot);
+                }
+            }
+        }
+
+        Ok(killed)
+    }
+
+    /// Drop all wakeups and event registrations which refer to dead threads.
+    fn purge_dead_wakeups(&mut self) {
+        let threads = &self.threads;
+        for registered in self.waiting.values_mut() {
+            registered.retain(|&index| threads.contains(index));
+        }
+        self.waiting.retain(|_, registered| !registered.is_empty());
+
+        let mut wakeups = mem::take(&mut self.queue).into_vec();
+        wakeups.retain(|wakeup| threads.contains(wakeup.thread()));
+        self.queue = BinaryHeap::from(wakeups);
+    }
+
+    /// Process all pending kills. Threads which were spawned but not yet scheduled are
+    /// scheduled first, so that they can be killed too.
+    pub(crate) fn poll_kills<'lua>(
+        &mut self,
+        lua: LuaContext<'lua>,
+        slots: &LuaTable<'lua>,
+    ) -> Result<()> {
+        let kills = self.kill_channel.try_iter().collect::<Vec<_>>();
+        if kills.is_empty() {
+            return Ok(());
+        }
+
+        self.queue_all_spawned(lua, slots)?;
+        for kill in kills {
+            match kill {
+                Kill::Thread(key) => {
+                    let thread = lua.registry_value::<LuaThread>(&key)?;
+                    self.kill_thread(slots, thread)?;
+                }
+                Kill::Group(group) => {
+                    self.kill_thread_group(lua, slots, &group)?;
+                }
+            }
+        }
+        self.purge_dead_wakeups();
+
+        Ok(())
+    }
+
+    /// Kill a thread immediately, removing all of its pending wakeups. Returns
+    /// `false` if the thread was already dead or was never spawned.
+    pub fn kill<'lua>(&mut self, lua: LuaContext<'lua>, thread: LuaThread<'lua>) -> Result<bool> {
+        let slots = lua.registry_value::<LuaTable>(&self.slots)?;
+        self.queue_all_spawned(lua, &slots)?;
+        let killed = self.kill_thread(&slots, thread)?;
+        self.purge_dead_wakeups();
+        Ok(killed)
+    }
+
+    /// Kill every thread in a group immediately, removing all of their pending
+    /// wakeups. Returns the number of threads killed.
+    pub fn kill_group(&mut self, lua: LuaContext, group: impl Into<ThreadGroup>) -> Result<usize> {
+        let slots = lua.registry_value::<LuaTable>(&self.slots)?;
+        self.queue_all_spawned(lua, &slots)?;
+        let killed = self.kill_thread_group(lua, &slots, &group.into())?;
+        self.purge_dead_wakeups();
+        Ok(killed)
+    }
+
+    /// Get the group a thread was spawned into, if it's alive and in one.
+    pub fn group_of<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        thread: LuaThread<'lua>,
+    ) -> Result<Option<ThreadGroup>> {
+        let slots = lua.registry_value::<LuaTable>(&self.slots)?;
+        Ok(slots
+            .get::<_, Option<u32>>(thread)?
+            .and_then(|slot| self.groups.get(&slot).cloned()))
+    }
+
+    /// Get the profiling statistics of a thread, if it's alive.
+    pub fn thread_stats<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        thread: LuaThread<'lua>,
+    ) -> Result<Option<ThreadStats>> {
+        let slots = lua.registry_value::<LuaTable>(&self.slots)?;
+        Ok(slots
+            .get::<_, Option<u32>>(thread)?
+            .and_then(|slot| self.stats.borrow().get(&slot).cloned()))
+    }
+
+    /// Get the profiling statistics of every live thread.
+    pub fn all_thread_stats<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+    ) -> Result<Vec<(LuaThread<'lua>, ThreadStats)>> {
+        let stats = self.stats.borrow();
+        self.threads
+            .iter()
+            .filter_map(|(index, key)| Some((key, stats.get(&index.slot())?.clone())))
+            .map(|(key, stats)| Ok((lua.registry_value(key)?, stats)))
+            .collect()
+    }
+
+    /// Set the number of Lua instructions a single thread may execute per tick
+    /// before it's aborted, or `None` to disable the watchdog. The budget is checked
+    /// every `WATCHDOG_GRANULARITY` instructions, by a hook which
+    /// `Space::set_watchdog_budget` installs along with the budget.
+    pub(crate) fn set_watchdog_budget(&mut self, budget: Option<u64>) {
+        self.watchdog_budget = budget;
+    }
+
+    pub fn watchdog_budget(&self) -> Option<u64> {
+        self.watchdog_budget
+    }
+
+    /// Install the watchdog's instruction count hook if there's a budget, or disable
+    /// it if there isn't. Coroutines inherit hooks from the thread which creates them.
+    ///
+    /// Threads created while the hook was installed keep it after it's disabled, so
+    /// rather than removing the callback, it's kept around with no triggers. Without
+    /// a budget the fuel tank is never drained, so those threads always pass.
+    fn update_watchdog_hook(&self, lua: &Lua) {
+        let triggers = match self.watchdog_budget {
+            Some(_) => rlua::HookTriggers {
+                every_nth_instruction: Some(Self::WATCHDOG_GRANULARITY),
+                ..rlua::HookTriggers::default()
+            },
+            None => rlua::HookTriggers::default(),
+        };
+
+        let fuel = self.watchdog_fuel.clone();
+        lua.set_hook(triggers, move |_lua, debug| {
+            if fuel.burn() {
+                return Ok(());
+            }
+
+            let source = debug.source();
+            let location = source
+                .short_src
+                .map(String::from_utf8_lossy)
+                .unwrap_or_else(|| "?".into());
+            let function = debug
+                .names()
+                .name
+                .map(String::from_utf8_lossy)
+                .unwrap_or_else(|| "?".into());
+            Err(LuaError::RuntimeError(format!(
+                "watchdog: thread exceeded its instruction budget in function `{}` at {}:{}",
+                function,
+                location,
+                debug.curr_line()
+            )))
+        });
+    }
+
+    /// Update a thread's statistics and fill the watchdog's fuel tank before
+    /// resuming it.
+    fn begin_resume(&mut self, slot: u32) {
+        let mut stats = self.stats.borrow_mut();
+        let stats = stats.entry(slot).or_default();
+        if stats.last_resumed != Some(self.discrete) {
+            stats.tick_resumes = 0;
+            stats.tick_instructions = 0;
+        }
+        stats.resumes += 1;
+        stats.tick_resumes += 1;
+        stats.last_resumed = Some(self.discrete);
+
+        if let Some(budget) = self.watchdog_budget {
+            self.watchdog_fuel
+                .fill(budget.saturating_sub(stats.tick_instructions));
+        }
+    }
+
+    /// Update a thread's statistics after resuming it, returning `true` if it ran out
+    /// of watchdog fuel.
+    fn end_resume(&mut self, slot: u32, elapsed: Duration) -> bool {
+        let remaining = self.watchdog_fuel.drain();
+        let mut stats = self.stats.borrow_mut();
+        let stats = stats.entry(slot).or_default();
+        stats.total_time += elapsed;
+
+        match self.watchdog_budget {
+            Some(budget) => {
+                let filled = budget.saturating_sub(stats.tick_instructions);
+                let used = (filled as i64).saturating_sub(remaining).max(0) as u64;
+                stats.tick_instructions += used;
+                remaining < 0
+            }
+            None => false,
+        }
+    }
+
+    /// Log the threads which were resumed most often on this tick.
+    fn log_busiest_threads(&self) {
+        let stats = self.stats.borrow();
+        let mut busiest = stats
+            .iter()
+            .filter(|(_, stats)| stats.last_resumed == Some(self.discrete))
+            .collect::<Vec<_>>();
+        busiest.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.tick_resumes));
+
+        for (slot, stats) in busiest.into_iter().take(3) {
+            log::warn!(
+                "thread in slot {} was resumed {} times this tick (spawned at {})",
+                slot,
+                stats.tick_resumes,
+                stats.spawned_at.as_deref().unwrap_or("<unknown>")
+            );
+        }
+    }
+
+    /// Set a handler deciding what happens to threads which raise errors. See the
+    /// documentation of `Scheduler` for details.
+    pub fn set_error_handler<F>(&mut self, handler: F)
+    where
+        F: for<'lua> FnMut(LuaContext<'lua>, LuaThread<'lua>, &ThreadError) -> ThreadErrorAction
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.error_handler = Some(Box::new(handler));
+    }
+
+    pub fn clear_error_handler(&mut self) {
+        self.error_handler = None;
+    }
+
+    /// Take all errors propagated by the error handler since this was last called.
+    pub fn take_propagated_errors(&mut self) -> Vec<ThreadError> {
+        mem::take(&mut self.propagated_errors)
+    }
+
+    fn handle_thread_error<'lua>(
+        &mut self,
+        lua: LuaContext<'lua>,
+        slots: &LuaTable<'lua>,
+        thread: LuaThread<'lua>,
+        index: Index,
+        lua_error: LuaError,
+    ) -> Result<()> {
+        let slot = index.slot();
+        let error = ThreadError {
+            message: format_lua_error(&lua_error),
+            spawned_at: self
+                .stats
+                .borrow()
+                .get(&slot)
+                .and_then(|stats| stats.spawned_at.clone()),
+            group: self.groups.get(&slot).cloned(),
+        };
+        let entry = self.entry_points.remove(&slot);
+        self.remove_thread(slots, thread.clone(), index)?;
+        log::error!("fatal {}", error);
+
+        let lua_handler =
+            lua.named_registry_value::<_, Option<LuaFunction>>(THREAD_ERROR_HANDLER_REGISTRY_KEY)?;
+        let action = match &mut self.error_handler {
+            Some(handler) => handler(lua, thread, &error),
+            None => match lua_handler {
+                Some(handler) => handler
+                    .call::<_, ThreadErrorAction>((
+                        thread,
+                        error.message.as_str(),
+                        error.spawned_at.as_deref(),
+                    ))
+                    .unwrap_or_else(|err| {
+                        log::error!("error in Lua thread error handler: {}", err);
+                        ThreadErrorAction::Kill
+                    }),
+                None => ThreadErrorAction::Kill,
+            },
+        };
+
+        match action {
+            ThreadErrorAction::Kill => {}
+            ThreadErrorAction::Restart => match entry {
+                Some(entry) => {
+                    let function = lua.registry_value::<LuaFunction>(&entry)?;
+                    let thread = lua.create_thread(function)?;
+                    let key = lua.create_registry_value(thread.clone())?;
+                    let index = self.threads.insert(key);
+                    slots.set(thread, index.slot())?;
+                    if let Some(group) = error.group {
+                        self.groups.insert(index.slot(), group);
+                    }
+                    self.stats
+                        .borrow_mut()
+                        .insert(index.slot(), ThreadStats::new(error.spawned_at));
+                    self.entry_points.insert(index.slot(), entry);
+
+                    // Restarted threads wait for the next tick, so that a thread which
+                    // fails immediately can't spin.
+                    self.queue.push(Wakeup::Timed {
+                        thread: index,
+                        scheduled_for: self.discrete + 1,
+                    });
+                }
+                None => log::warn!(
+                    "can't restart a Lua thread which wasn't spawned from a function; killing it"
+                ),
+            },
+            ThreadErrorAction::Propagate => self.propagated_errors.push(error),
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn run_all_queued<'lua>(
         &mut self,
         lua: LuaContext<'lua>,
         slots: &LuaTable<'lua>,
     ) -> Result<()> {
-        while let Some(top) = self.queue.peek() {
+        loop {
+            // A thread which ran earlier may have killed some of the queued threads.
+            self.poll_kills(lua, slots)?;
+
             // If this thread isn't ready to wake up on this tick, then
             // none of the other threads in this queue are.
-            if top.scheduled_for() > self.discrete {
-                break;
+            match self.queue.peek() {
+                Some(top) if top.scheduled_for() <= self.discrete => {}
+                _ => break,
             }
 
             let sleeping = self.queue.pop().unwrap();
+
             if let Some(key) = self.threads.get(sleeping.thread()) {
                 let thread = lua.registry_value::<LuaThread>(key)?;
 
+                // Whatever woke the thread, it's no longer waiting on any of the events
+                // it yielded.
+                self.deregister(sleeping.thread().slot());
+                self.begin_resume(sleeping.thread().slot());
+                let started = Instant::now();
+
                 let resumed = match &sleeping {
                     Wakeup::Notify {
                         args: Some(args), ..
@@ -721,6 +1635,7 @@ impl Scheduler {
                     Wakeup::Timed { scheduled_for, .. } => {
                         thread.resume::<_, LuaMultiValue>(*scheduled_for)
                     }
+                    Wakeup::Timeout { .. } => thread.resume::<_, LuaMultiValue>(()),
                     Wakeup::Broadcast {
                         name,
                         args: Some(args),
@@ -741,7 +1656,17 @@ impl Scheduler {
                     } => thread.resume::<_, LuaMultiValue>(name.0.as_ref()),
                 };
 
+                let out_of_fuel = self.end_resume(sleeping.thread().slot(), started.elapsed());
+
                 match resumed {
+                    // A thread which caught its watchdog error and yielded anyways is killed.
+                    Ok(_) if out_of_fuel && thread.status() == LuaThreadStatus::Resumable => {
+                        log::error!(
+                            "Lua thread {:?} exceeded its instruction budget and was killed",
+                            sleeping.thread()
+                        );
+                        self.remove_thread(slots, thread, sleeping.thread())?;
+                    }
                     Ok(mv) if thread.status() == LuaThreadStatus::Resumable => {
                         let new_index = self.threads.invalidate(sleeping.thread()).unwrap();
 
@@ -765,16 +1690,31 @@ impl Scheduler {
                                 // wants to listen for.
                                 LuaValue::String(lua_str) => {
                                     if let Ok(s) = lua_str.to_str() {
-                                        let threads = self
-                                            .waiting
-                                            .entry(EventName(Atom::from(s)))
-                                            .or_default();
-                                        match threads.binary_search(&sleeping.thread()) {
-                                            Ok(i) => threads[i] = new_index,
-                                            Err(i) if threads.get(i) != Some(&new_index) => {
-                                                threads.insert(i, new_index)
-                                            }
-                                            _ => {}
+                                        self.register(new_index, EventName(Atom::from(s)));
+                                    }
+                                }
+                                // If we see a table, then the thread wants to wait for whichever
+                                // of the events in it happens first, or its timeout.
+                                LuaValue::Table(select) => {
+                                    let event_names = select.clone().sequence_values::<LuaString>();
+                                    for event_name in event_names {
+                                        match event_name.and_then(|s| s.to_str().map(Atom::from)) {
+                                            Ok(atom) => self.register(new_index, EventName(atom)),
+                                            Err(err) => log::error!(
+                                                "bad event name in yielded table: {}",
+                                                err
+                                            ),
+                                        }
+                                    }
+
+                                    match select.get::<_, Option<i64>>("timeout") {
+                                        Ok(Some(i)) => self.queue.push(Wakeup::Timeout {
+                                            thread: new_index,
+                                            scheduled_for: self.discrete + na::max(i, 1) as u64,
+                                        }),
+                                        Ok(None) => {}
+                                        Err(err) => {
+                                            log::error!("bad timeout in yielded table: {}", err)
                                         }
                                     }
                                 }
@@ -785,35 +1725,224 @@ impl Scheduler {
                         }
                     }
                     Ok(_) => {
-                        slots.set(thread, LuaValue::Nil)?;
+                        self.remove_thread(slots, thread, sleeping.thread())?;
                     }
                     Err(lua_error) => {
-                        slots.set(thread, LuaValue::Nil)?;
-                        self.threads.remove(sleeping.thread());
-                        match lua_error.source() {
-                            Some(src) => log::error!(
-                                "fatal error in Lua thread {:?}: {}",
-                                sleeping.thread(),
-                                src
-                            ),
-                            None => log::error!(
-                                "fatal error in Lua thread {:?}: {}",
-                                sleeping.thread(),
-                                lua_error
-                            ),
-                        }
+                        self.handle_thread_error(lua, slots, thread, sleeping.thread(), lua_error)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Service all pending save and load requests. If a request fails, the
+    /// requesting thread is woken with `nil` and an error message.
+    ///
+    /// Once a save is loaded, the rest of the requests are dropped, since the threads
+    /// which made them no longer exist.
+    pub(crate) fn service_persist_requests<'lua>(&mut self, lua: LuaContext<'lua>) -> Result<()> {
+        let requests = self.persist_channel.try_iter().collect::<Vec<_>>();
+        let count = requests.len();
+        for (i, request) in requests.into_iter().enumerate() {
+            let thread = lua.registry_value::<LuaThread>(&request.thread)?;
+            let result = match request.kind {
+                PersistRequestKind::Save => self.service_save(lua, &request.slot, thread.clone()),
+                PersistRequestKind::Load => self.service_load(lua, &request.slot),
+            };
+
+            if result.is_ok() && request.kind == PersistRequestKind::Load {
+                let dropped = count - i - 1;
+                if dropped > 0 {
+                    log::warn!(
+                        "dropping {} save/load requests made before save slot `{}` was loaded",
+                        dropped,
+                        request.slot
+                    );
+                }
+                break;
+            }
+
+            if let Err(err) = result {
+                log::error!(
+                    "error servicing {:?} request for save slot `{}`: {:#}",
+                    request.kind,
+                    request.slot,
+                    err
+                );
+                lua.notify(thread, (LuaValue::Nil, err.to_string()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn service_save<'lua>(
+        &mut self,
+        lua: LuaContext<'lua>,
+        slot: &str,
+        thread: LuaThread<'lua>,
+    ) -> Result<()> {
+        let slots = lua.registry_value::<LuaTable>(&self.slots)?;
+        let index = slots
+            .get::<_, Option<u32>>(thread)?
+            .and_then(|slot| self.threads.contains_slot(slot))
+            .ok_or_else(|| anyhow!("thread requesting a save is not scheduled"))?;
+
+        // The wakeup has to be queued before persisting, so that the requesting thread
+        // is resumed after the save is loaded; it's woken with "loaded" in the save and
+        // "saved" here.
+        let args = self
+            .event_args
+            .insert(iter::once(lua.create_registry_value("loaded")?).collect());
+        self.queue.push(Wakeup::Notify {
+            thread: self.threads.invalidate(index).unwrap(),
+            args: Some(args),
+        });
+
+        let mut bytes = Vec::new();
+        let result = persist::save_with(lua, self, &mut bytes).and_then(|()| {
+            let resources = lua.resources();
+            let mut fs = resources
+                .try_fetch_mut::<Filesystem>()
+                .ok_or_else(|| anyhow!("no filesystem to write saves to"))?;
+            persist::write_save_slot(&mut fs, slot, &bytes, persist::SAVE_BACKUP_COUNT)
+        });
+
+        self.event_args[args] = match &result {
+            Ok(()) => iter::once(lua.create_registry_value("saved")?).collect(),
+            Err(err) => vec![
+                lua.create_registry_value(LuaValue::Nil)?,
+                lua.create_registry_value(format!("{:#}", err))?,
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        if let Err(err) = result {
+            log::error!("error writing save slot `{}`: {:#}", slot, err);
+        }
+
+        Ok(())
+    }
+
+    fn service_load<'lua>(&mut self, lua: LuaContext<'lua>, slot: &str) -> Result<()> {
+        let bytes = {
+            let resources = lua.resources();
+            let mut fs = resources
+                .try_fetch_mut::<Filesystem>()
+                .ok_or_else(|| anyhow!("no filesystem to read saves from"))?;
+            persist::read_save_slot(&mut fs, slot)?
+        };
+
+        // Unpersist before tearing anything down, so that a bad save leaves the current
+        // state intact and the requesting thread can be told about it.
+        let persisted_table = persist::undump_save(lua, &bytes[..])?;
+
+        // Play the save back alongside the current state, which is only thrown away once
+        // playback succeeds. If it fails, everything playback spawned is removed and the
+        // current state is put back.
+        let (existing, persistent) = {
+            let resources = lua.resources();
+            let world = resources.fetch::<World>();
+            let existing = world.iter().map(|(e, _)| e).collect::<HashSet<_>>();
+            (existing, persist::persistent_entities(&world))
+        };
+        let previous = self.take_threads(lua)?;
+        let pending = (
+            self.spawn_channel.try_iter().collect::<Vec<_>>(),
+            self.kill_channel.try_iter().collect::<Vec<_>>(),
+            self.event_channel.try_iter().collect::<Vec<_>>(),
+        );
+
+        match persist::playback(lua, self, persisted_table) {
+            Ok(()) => {
+                let resources = lua.resources();
+                let mut world = resources.fetch_mut::<World>();
+                for entity in persistent {
+                    world.despawn(entity)?;
+                }
+
+                Ok(())
+            }
+            Err(err) => {
+                {
+                    let resources = lua.resources();
+                    let mut world = resources.fetch_mut::<World>();
+                    let spawned = world
+                        .iter()
+                        .map(|(e, _)| e)
+                        .filter(|e| !existing.contains(e))
+                        .collect::<Vec<_>>();
+                    for entity in spawned {
+                        world.despawn(entity)?;
                     }
                 }
+
+                self.restore_threads(lua, previous)?;
+                let resources = lua.resources();
+                let channel = resources.fetch::<SchedulerQueueChannel>();
+                let (spawns, kills, events) = pending;
+                fn full<T>(_: T) -> Error {
+                    anyhow!("scheduler channel full while restoring pending messages")
+                }
+                spawns
+                    .into_iter()
+                    .try_for_each(|s| channel.spawn.try_send(s).map_err(full))?;
+                kills
+                    .into_iter()
+                    .try_for_each(|k| channel.kill.try_send(k).map_err(full))?;
+                events
+                    .into_iter()
+                    .try_for_each(|e| channel.event.try_send(e).map_err(full))?;
+
+                Err(err)
             }
         }
+    }
+
+    /// Swap the scheduled threads out for an empty set, for playing back a save into.
+    fn take_threads(&mut self, lua: LuaContext) -> Result<ScheduledThreads> {
+        let slots = Self::create_slots(lua)?;
+        Ok(ScheduledThreads {
+            queue: mem::take(&mut self.queue),
+            waiting: mem::take(&mut self.waiting),
+            threads: mem::replace(&mut self.threads, Arena::new()),
+            slots: mem::replace(&mut self.slots, slots),
+            event_args: mem::replace(&mut self.event_args, Arena::new()),
+            registrations: mem::take(&mut self.registrations),
+            groups: mem::take(&mut self.groups),
+            stats: mem::take(&mut *self.stats.borrow_mut()),
+            entry_points: mem::take(&mut self.entry_points),
+        })
+    }
+
+    /// Put back threads swapped out by `take_threads`, dropping whatever replaced them.
+    fn restore_threads(&mut self, lua: LuaContext, previous: ScheduledThreads) -> Result<()> {
+        let slots = lua.registry_value::<LuaTable>(&previous.slots)?;
+        lua.set_named_registry_value(SCHEDULER_SLOTS_REGISTRY_KEY, slots)?;
+
+        self.queue = previous.queue;
+        self.waiting = previous.waiting;
+        self.threads = previous.threads;
+        self.slots = previous.slots;
+        self.event_args = previous.event_args;
+        self.registrations = previous.registrations;
+        self.groups = previous.groups;
+        *self.stats.borrow_mut() = previous.stats;
+        self.entry_points = previous.entry_points;
 
         Ok(())
     }
 
     pub fn update(&mut self, lua: LuaContext, dt: f32) -> Result<()> {
         self.continuous += dt;
-        let slots = lua.registry_value(&self.slots)?;
         while self.continuous > 0. {
+            // Loading a save replaces the slots table, so it has to be fetched anew every
+            // tick.
+            let slots = lua.registry_value(&self.slots)?;
+
             // Our core update step consists of two steps:
             // 1. Run all threads scheduled to run on or before the current tick.
             // 2. Check for threads spawned/woken by newly run threads. If there are new
@@ -827,15 +1956,19 @@ impl Scheduler {
                 self.run_all_queued(lua, &slots)?;
                 self.event_args.clear();
                 self.queue_all_spawned(lua, &slots)?;
+                self.poll_kills(lua, &slots)?;
                 self.poll_events_and_queue_all_notified(lua, &slots)?;
 
                 if self.is_idle() {
                     break;
                 } else if i == LOOP_CAP - 1 {
                     log::warn!("trampoline loop cap exceeded");
+                    self.log_busiest_threads();
                 }
             }
 
+            self.service_persist_requests(lua)?;
+
             self.continuous -= 1.;
             self.discrete += 1;
         }
@@ -0,0 +1,436 @@
+//! Mods, mounted over the game's own resources.
+//!
+//! A mod is a directory or zip file in the `mods` directory of the user data
+//! directory, with a [`ModManifest`] at `/mod.json` naming it and listing what it
+//! depends on. Mods are mounted in dependency order at the front of the
+//! `Filesystem`, so a mod's files take priority over the game's and over those of
+//! every mod mounted before it. Since Lua's `require` goes through the `Filesystem`
+//! too, a mod can replace scripts just like any other file.
+//!
+//! Mods with missing dependencies, broken manifests, duplicate names or dependency
+//! cycles are skipped rather than failing the whole game, and reported in
+//! [`Mods::skipped`].
+
+use {
+    anyhow::*,
+    hashbrown::{HashMap, HashSet},
+    petgraph::graphmap::DiGraphMap,
+    serde::{Deserialize, Serialize},
+    std::{
+        fs,
+        path::{Path, PathBuf},
+    },
+};
+
+use crate::{
+    dependency_graph::DependencyGraph,
+    filesystem::Filesystem,
+    vfs::{PhysicalFS, ZipFS, VFS},
+};
+
+/// Directory in the user data directory which mods are discovered in.
+pub const MOD_DIRECTORY: &str = "mods";
+
+/// Where a mod's manifest lives, relative to the mod's root.
+pub const MOD_MANIFEST_PATH: &str = "/mod.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModManifest {
+    /// The name other mods refer to this one by.
+    pub name: String,
+    pub version: String,
+    /// Mods which have to be present for this one to work. They're always mounted
+    /// before it.
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    /// Mods which this one should be mounted after if they're present, so that it
+    /// can override their files.
+    #[serde(default)]
+    pub load_after: Vec<String>,
+}
+
+/// A file which a mod provides in place of another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Override {
+    pub path: PathBuf,
+    /// The mod which provided the file before, or `None` if it was one of the game's
+    /// own files.
+    pub previous: Option<String>,
+}
+
+/// A mod which has been mounted.
+#[derive(Debug)]
+pub struct Mod {
+    manifest: ModManifest,
+    source: PathBuf,
+    overrides: Vec<Override>,
+}
+
+impl Mod {
+    pub fn manifest(&self) -> &ModManifest {
+        &self.manifest
+    }
+
+    pub fn name(&self) -> &str {
+        &self.manifest.name
+    }
+
+    /// The directory or zip file the mod was mounted from.
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Files which this mod provides in place of the game's or another mod's.
+    pub fn overrides(&self) -> &[Override] {
+        &self.overrides
+    }
+}
+
+/// A mod which has been found but not mounted yet.
+struct Candidate {
+    manifest: ModManifest,
+    source: PathBuf,
+    vfs: Box<dyn VFS>,
+    files: Vec<PathBuf>,
+}
+
+impl Candidate {
+    fn open(source: PathBuf) -> Result<Self> {
+        let (vfs, files): (Box<dyn VFS>, _) = if source.is_dir() {
+            let physfs = PhysicalFS::new(&source, true);
+            let mut files = Vec::new();
+            walk(&physfs, Path::new("/"), &mut files)?;
+            (Box::new(physfs), files)
+        } else {
+            let zipfs = ZipFS::new(&source)?;
+            let files = zipfs.files().collect();
+            (Box::new(zipfs), files)
+        };
+
+        let manifest = serde_json::from_reader(vfs.open(Path::new(MOD_MANIFEST_PATH))?)
+            .context("error parsing mod manifest")?;
+
+        Ok(Self {
+            manifest,
+            source,
+            vfs,
+            files,
+        })
+    }
+}
+
+/// Find the mods on a cycle of dependencies or `load_after`s, if there are any.
+fn dependency_cycle(candidates: &HashMap<String, Candidate>) -> Option<Vec<String>> {
+    let mut graph = DiGraphMap::<&str, ()>::new();
+    for (name, candidate) in candidates {
+        graph.add_node(name.as_str());
+        let manifest = &candidate.manifest;
+        for dep in manifest.dependencies.iter().chain(&manifest.load_after) {
+            if candidates.contains_key(dep) {
+                graph.add_edge(dep.as_str(), name.as_str(), ());
+            }
+        }
+    }
+
+    let cycle = petgraph::algo::tarjan_scc(&graph)
+        .into_iter()
+        .find(|scc| scc.len() > 1 || graph.contains_edge(scc[0], scc[0]))?;
+    let mut names = cycle.into_iter().map(str::to_owned).collect::<Vec<_>>();
+    names.sort();
+    Some(names)
+}
+
+fn walk(vfs: &dyn VFS, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in vfs.read_dir(dir)? {
+        let path = entry?;
+        if vfs.metadata(&path)?.is_dir() {
+            walk(vfs, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// The mods mounted into a `Filesystem`, in the order they were mounted.
+#[derive(Debug, Default)]
+pub struct Mods {
+    mounted: Vec<Mod>,
+    skipped: Vec<(PathBuf, Error)>,
+}
+
+impl Mods {
+    /// Discover the mods in the user data directory and mount them. A `Filesystem`
+    /// without a user data directory gets no mods.
+    pub fn mount(filesystem: &mut Filesystem) -> Result<Self> {
+        if filesystem.user_data_path().as_os_str().is_empty() {
+            return Ok(Self::default());
+        }
+
+        let directory = filesystem.user_data_path().join(MOD_DIRECTORY);
+        Self::mount_from(filesystem, &directory)
+    }
+
+    /// Discover the mods in the given directory and mount them. Entries which aren't
+    /// directories or zip files are ignored.
+    pub fn mount_from(filesystem: &mut Filesystem, directory: &Path) -> Result<Self> {
+        let mut mods = Self::default();
+        if !directory.is_dir() {
+            return Ok(mods);
+        }
+
+        let mut sources = fs::read_dir(directory)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        sources.retain(|path| path.is_dir() || path.extension().map_or(false, |e| e == "zip"));
+        sources.sort();
+
+        let mut candidates = HashMap::new();
+        for source in sources {
+            match Candidate::open(source.clone()) {
+                Ok(candidate) if candidates.contains_key(&candidate.manifest.name) => {
+                    let err = anyhow!("a mod named `{}` already exists", candidate.manifest.name);
+                    mods.skipped.push((source, err));
+                }
+                Ok(candidate) => {
+                    candidates.insert(candidate.manifest.name.clone(), candidate);
+                }
+                Err(err) => mods.skipped.push((source, err)),
+            }
+        }
+
+        // Skipping a mod can leave others without their dependencies, so keep going
+        // until nothing else is missing anything and no mods are left on a cycle.
+        loop {
+            let missing = candidates.values().find_map(|candidate| {
+                let dep = candidate
+                    .manifest
+                    .dependencies
+                    .iter()
+                    .find(|dep| !candidates.contains_key(*dep))?;
+                Some((candidate.manifest.name.clone(), dep.clone()))
+            });
+
+            if let Some((name, dep)) = missing {
+                let candidate = candidates.remove(&name).unwrap();
+                let err = anyhow!("mod `{}` depends on `{}`, which is missing", name, dep);
+                mods.skipped.push((candidate.source, err));
+                continue;
+            }
+
+            let cycle = match dependency_cycle(&candidates) {
+                Some(cycle) => cycle,
+                None => break,
+            };
+            let listed = cycle
+                .iter()
+                .map(|name| format!("`{}`", name))
+                .collect::<Vec<_>>()
+                .join(", ");
+            for name in &cycle {
+                let candidate = candidates.remove(name).unwrap();
+                let err = anyhow!("mods {} depend on each other in a cycle", listed);
+                mods.skipped.push((candidate.source, err));
+            }
+        }
+
+        let mut names = candidates.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+
+        let mut graph = DependencyGraph::new();
+        for name in &names {
+            let manifest = &candidates[name].manifest;
+            let deps = manifest.dependencies.iter().chain(&manifest.load_after);
+            graph.insert((), name.as_str(), deps.map(String::as_str))?;
+        }
+        graph.update().context("error resolving mod load order")?;
+        let order = graph
+            .sorted()
+            .map(|(name, _)| name.to_owned())
+            .collect::<Vec<_>>();
+
+        let mut providers = HashMap::<PathBuf, String>::new();
+        let manifest_path = Path::new(MOD_MANIFEST_PATH);
+        for name in order {
+            let candidate = candidates.remove(&name).unwrap();
+            let files = candidate
+                .files
+                .into_iter()
+                .filter(|path| path != manifest_path)
+                .collect::<HashSet<_>>();
+
+            let mut overrides = Vec::new();
+            for path in files {
+                let previous = providers.insert(path.clone(), name.clone());
+                if previous.is_some() || filesystem.exists(&path) {
+                    overrides.push(Override { path, previous });
+                }
+            }
+            overrides.sort_by(|a, b| a.path.cmp(&b.path));
+
+            log::trace!("Mounting mod `{}` from {:?}", name, candidate.source);
+            filesystem.push_front(candidate.vfs);
+            mods.mounted.push(Mod {
+                manifest: candidate.manifest,
+                source: candidate.source,
+                overrides,
+            });
+        }
+
+        Ok(mods)
+    }
+
+    /// Mounted mods, from lowest to highest priority.
+    pub fn mounted(&self) -> &[Mod] {
+        &self.mounted
+    }
+
+    /// Mods which were found but couldn't be mounted, and why.
+    pub fn skipped(&self) -> &[(PathBuf, Error)] {
+        &self.skipped
+    }
+
+    /// Log which mods were mounted, which files they override, and which mods were
+    /// skipped.
+    pub fn log_report(&self) {
+        for m in &self.mounted {
+            log::info!(
+                "Mounted mod `{}` version {} from {:?}",
+                m.name(),
+                m.manifest.version,
+                m.source
+            );
+
+            for o in &m.overrides {
+                match &o.previous {
+                    Some(previous) => log::info!(
+                        "  {} overrides {:?} from mod `{}`",
+                        m.name(),
+                        o.path,
+                        previous
+                    ),
+                    None => log::info!("  {} overrides {:?}", m.name(), o.path),
+                }
+            }
+        }
+
+        for (source, err) in &self.skipped {
+            log::warn!("Skipped mod at {:?}: {:#}", source, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFS;
+    use std::{env, io::Read};
+
+    fn write_mod(directory: &Path, manifest: &str, files: &[(&str, &str)]) -> Result<()> {
+        fs::create_dir_all(directory)?;
+        fs::write(directory.join("mod.json"), manifest)?;
+        for (path, contents) in files {
+            fs::write(directory.join(path), contents)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mods_mount_in_dependency_order() -> Result<()> {
+        let directory = env::temp_dir().join(format!("sludge-mods-test-{}", std::process::id()));
+        // `b` sorts before `a`, so it only ends up on top because of its dependency.
+        write_mod(
+            &directory.join("b"),
+            r#"{ "name": "b", "version": "1.0.0", "dependencies": ["a"] }"#,
+            &[("data.txt", "b"), ("main.lua", "return 'b'")],
+        )?;
+        write_mod(
+            &directory.join("a"),
+            r#"{ "name": "a", "version": "0.1.0", "load_after": ["nobody"] }"#,
+            &[("data.txt", "a")],
+        )?;
+        write_mod(
+            &directory.join("c"),
+            r#"{ "name": "c", "version": "0.1.0", "dependencies": ["missing"] }"#,
+            &[("data.txt", "c")],
+        )?;
+
+        let memfs = MemoryFS::new();
+        memfs.insert("/data.txt", "base")?;
+        let mut filesystem = Filesystem::from_vfs(memfs);
+        let mods = Mods::mount_from(&mut filesystem, &directory)?;
+        fs::remove_dir_all(&directory)?;
+
+        let names = mods.mounted().iter().map(Mod::name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(mods.skipped().len(), 1);
+
+        let a = &mods.mounted()[0];
+        assert_eq!(
+            a.overrides(),
+            &[Override {
+                path: PathBuf::from("/data.txt"),
+                previous: None,
+            }]
+        );
+
+        let b = &mods.mounted()[1];
+        assert_eq!(
+            b.overrides(),
+            &[Override {
+                path: PathBuf::from("/data.txt"),
+                previous: Some("a".to_owned()),
+            }]
+        );
+
+        let mut data = String::new();
+        filesystem.open("/data.txt")?.read_to_string(&mut data)?;
+        assert_eq!(data, "b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn mods_on_a_cycle_are_skipped() -> Result<()> {
+        let directory =
+            env::temp_dir().join(format!("sludge-mods-cycle-test-{}", std::process::id()));
+        write_mod(
+            &directory.join("x"),
+            r#"{ "name": "x", "version": "1.0.0", "dependencies": ["y"] }"#,
+            &[],
+        )?;
+        write_mod(
+            &directory.join("y"),
+            r#"{ "name": "y", "version": "1.0.0", "load_after": ["x"] }"#,
+            &[],
+        )?;
+        write_mod(
+            &directory.join("z"),
+            r#"{ "name": "z", "version": "1.0.0", "dependencies": ["x"] }"#,
+            &[],
+        )?;
+        write_mod(
+            &directory.join("w"),
+            r#"{ "name": "w", "version": "1.0.0", "load_after": ["y"] }"#,
+            &[("data.txt", "w")],
+        )?;
+
+        let mut filesystem = Filesystem::from_vfs(MemoryFS::new());
+        let mods = Mods::mount_from(&mut filesystem, &directory)?;
+        fs::remove_dir_all(&directory)?;
+
+        let names = mods.mounted().iter().map(Mod::name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["w"]);
+
+        let mut skipped = mods
+            .skipped()
+            .iter()
+            .map(|(source, _)| source.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        skipped.sort();
+        assert_eq!(skipped, vec!["x", "y", "z"]);
+
+        Ok(())
+    }
+}
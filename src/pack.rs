@@ -0,0 +1,339 @@
+//! Packed resource archives for release builds.
+//!
+//! The `sludge-pack` binary walks a resources directory, follows the references between
+//! assets the same way their `Asset::load` implementations do, and writes everything
+//! into a zip archive along with a [`Manifest`] of content hashes. When
+//! `Filesystem::new` mounts a `resources.zip` which has a manifest, it checks that every
+//! file in it is present, so that a truncated archive fails loudly at startup instead
+//! of halfway through a level. Debug builds hash every file against the manifest as
+//! well; release builds skip that, since it means reading the whole archive.
+//!
+//! Formats which refer to other files register an [`AssetDependencies`] with
+//! `inventory::submit!`, next to their `Asset` impl.
+
+use {
+    anyhow::*,
+    serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+    std::{
+        collections::{BTreeMap, BTreeSet, VecDeque},
+        fmt::Write as _,
+        fs,
+        io::{Read, Seek, Write},
+        path::{Path, PathBuf},
+    },
+    zip::{write::FileOptions, ZipWriter},
+};
+
+use crate::{filesystem::Filesystem, path_clean::PathClean, vfs::VFS};
+
+/// Where the manifest lives inside a packed archive.
+pub const MANIFEST_PATH: &str = "/manifest.json";
+
+/// Version of the manifest format written by `sludge-pack`.
+pub const MANIFEST_FORMAT_VERSION: u32 = 1;
+
+/// The hex-encoded SHA-256 hash of some file contents.
+pub fn content_hash(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in Sha256::digest(bytes).iter() {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+/// The table of contents of a packed archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// The content hash of every file in the archive except the manifest itself, keyed
+    /// by its path in the `Filesystem`.
+    pub files: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Read the manifest of a mounted archive, if it has one.
+    pub fn read(vfs: &dyn VFS) -> Result<Option<Self>> {
+        let path = Path::new(MANIFEST_PATH);
+        if !vfs.exists(path) {
+            return Ok(None);
+        }
+
+        let manifest = serde_json::from_reader::<_, Self>(vfs.open(path)?)
+            .context("error parsing resource manifest")?;
+        ensure!(
+            manifest.version == MANIFEST_FORMAT_VERSION,
+            "unsupported resource manifest version {} (expected {})",
+            manifest.version,
+            MANIFEST_FORMAT_VERSION
+        );
+
+        Ok(Some(manifest))
+    }
+
+    /// Check that every file in the manifest is present, without reading any of them.
+    pub fn verify_present(&self, vfs: &dyn VFS) -> Result<()> {
+        for path in self.files.keys() {
+            ensure!(
+                vfs.exists(Path::new(path)),
+                "`{}` is in the manifest but not the archive",
+                path
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check that every file in the manifest is present and has the right contents.
+    pub fn verify(&self, vfs: &dyn VFS) -> Result<()> {
+        for (path, hash) in &self.files {
+            let mut file = vfs
+                .open(Path::new(path))
+                .with_context(|| anyhow!("`{}` is in the manifest but not the archive", path))?;
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf)?;
+            ensure!(
+                content_hash(&buf) == *hash,
+                "`{}` doesn't match its hash in the manifest",
+                path
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Check a mounted archive against its manifest, hashing every file in debug builds and
+/// only checking that they're present in release builds. Archives without a manifest,
+/// such as those zipped up by hand, are let through with a warning.
+pub fn verify_archive(vfs: &dyn VFS) -> Result<()> {
+    match Manifest::read(vfs)? {
+        Some(manifest) if cfg!(debug_assertions) => manifest.verify(vfs),
+        Some(manifest) => manifest.verify_present(vfs),
+        None => {
+            log::warn!(
+                "resource archive {:?} has no manifest; not verifying it",
+                vfs.to_path_buf()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// How `sludge-pack` finds the files which a format refers to.
+pub struct AssetDependencies {
+    /// File extensions of the format, without the dot.
+    pub extensions: &'static [&'static str],
+    /// Whether game code loads files of this format directly, rather than only through
+    /// other assets. Files of root formats are never reported as unreferenced.
+    pub root: bool,
+    /// Find the paths a file refers to, resolved against the `Filesystem`.
+    pub find: fn(&mut Filesystem, &Path) -> Result<Vec<PathBuf>>,
+}
+
+inventory::collect!(AssetDependencies);
+
+impl AssetDependencies {
+    pub fn new(
+        extensions: &'static [&'static str],
+        root: bool,
+        find: fn(&mut Filesystem, &Path) -> Result<Vec<PathBuf>>,
+    ) -> Self {
+        Self {
+            extensions,
+            root,
+            find,
+        }
+    }
+
+    /// The registered format of a path, going by its extension.
+    pub fn of(path: &Path) -> Option<&'static Self> {
+        let extension = path.extension()?.to_str()?;
+        inventory::iter::<Self>
+            .into_iter()
+            .find(|deps| deps.extensions.contains(&extension))
+    }
+}
+
+/// For formats which don't refer to any other files.
+pub fn no_dependencies(_fs: &mut Filesystem, _path: &Path) -> Result<Vec<PathBuf>> {
+    Ok(Vec::new())
+}
+
+inventory::submit! {
+    AssetDependencies::new(&["lua"], true, no_dependencies)
+}
+
+/// What `sludge-pack` found in a resources directory.
+#[derive(Debug, Default)]
+pub struct PackReport {
+    /// Every file in the directory, by its path in the `Filesystem`.
+    pub files: BTreeSet<PathBuf>,
+    /// Files which are referred to but don't exist, along with what referred to them.
+    pub missing: Vec<(PathBuf, PathBuf)>,
+    /// Files which couldn't be read to find their dependencies, and why.
+    pub errors: Vec<(PathBuf, Error)>,
+    /// Files which nothing refers to and which aren't roots.
+    pub unreferenced: BTreeSet<PathBuf>,
+}
+
+/// Builds a packed archive out of a resources directory.
+#[derive(Debug)]
+pub struct Packer {
+    directory: PathBuf,
+    fs: Filesystem,
+    roots: BTreeSet<PathBuf>,
+}
+
+impl Packer {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        ensure!(
+            directory.is_dir(),
+            "resources directory {:?} doesn't exist",
+            directory
+        );
+
+        Ok(Self {
+            fs: Filesystem::from_directory(&directory, true),
+            directory,
+            roots: BTreeSet::new(),
+        })
+    }
+
+    /// Mark a file as loaded directly by game code, on top of the files of root formats,
+    /// such as textures which are only ever referred to from Rust.
+    pub fn add_root<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = Path::new("/").join(path).clean();
+        ensure!(self.fs.is_file(&path), "root {:?} doesn't exist", path);
+        self.roots.insert(path);
+        Ok(())
+    }
+
+    /// Walk the resources directory and follow the dependencies of every root.
+    pub fn scan(&mut self) -> Result<PackReport> {
+        let mut report = PackReport::default();
+        walk(&self.directory, &self.directory, &mut report.files)?;
+        ensure!(
+            !report.files.contains(Path::new(MANIFEST_PATH)),
+            "`{}` is reserved for the archive's manifest",
+            MANIFEST_PATH
+        );
+
+        let mut queue = report
+            .files
+            .iter()
+            .filter(|path| {
+                self.roots.contains(*path) || AssetDependencies::of(path).map_or(false, |d| d.root)
+            })
+            .cloned()
+            .collect::<VecDeque<_>>();
+        let mut referenced = queue.iter().cloned().collect::<BTreeSet<_>>();
+
+        while let Some(path) = queue.pop_front() {
+            let deps = match AssetDependencies::of(&path) {
+                Some(deps) => deps,
+                None => continue,
+            };
+
+            let found = match (deps.find)(&mut self.fs, &path) {
+                Ok(found) => found,
+                Err(err) => {
+                    report.errors.push((path, err));
+                    continue;
+                }
+            };
+
+            for dep in found {
+                let dep = dep.clean();
+                if !report.files.contains(&dep) {
+                    report.missing.push((dep, path.clone()));
+                } else if referenced.insert(dep.clone()) {
+                    queue.push_back(dep);
+                }
+            }
+        }
+
+        report.unreferenced = report.files.difference(&referenced).cloned().collect();
+
+        Ok(report)
+    }
+
+    /// Write every file found by `scan`, referenced or not, into a zip archive along
+    /// with its manifest.
+    pub fn write<W: Write + Seek>(&mut self, report: &PackReport, writer: W) -> Result<Manifest> {
+        let mut zip = ZipWriter::new(writer);
+        let options = FileOptions::default();
+        let mut manifest = Manifest {
+            version: MANIFEST_FORMAT_VERSION,
+            files: BTreeMap::new(),
+        };
+
+        // Zip entries are named without the leading slash; `ZipFS` strips it off of
+        // the paths it's asked for.
+        for path in &report.files {
+            let name = path
+                .to_str()
+                .ok_or_else(|| anyhow!("path {:?} isn't valid UTF-8", path))?;
+            let mut buf = Vec::new();
+            self.fs.open(path)?.read_to_end(&mut buf)?;
+
+            zip.start_file(name.trim_start_matches('/'), options)?;
+            zip.write_all(&buf)?;
+            manifest.files.insert(name.to_owned(), content_hash(&buf));
+        }
+
+        zip.start_file(MANIFEST_PATH.trim_start_matches('/'), options)?;
+        serde_json::to_writer_pretty(&mut zip, &manifest)?;
+        zip.finish()?;
+
+        Ok(manifest)
+    }
+}
+
+fn walk(directory: &Path, current: &Path, files: &mut BTreeSet<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(current)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(directory, &path, files)?;
+        } else {
+            files.insert(Path::new("/").join(path.strip_prefix(directory)?));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::ZipFS;
+    use std::{env, io::Cursor};
+
+    #[test]
+    fn packed_archive_verifies() -> Result<()> {
+        let directory = env::temp_dir().join(format!("sludge-pack-test-{}", std::process::id()));
+        fs::create_dir_all(directory.join("scripts"))?;
+        fs::write(directory.join("scripts/main.lua"), "return 1")?;
+        fs::write(directory.join("orphan.txt"), "nobody loads me")?;
+        fs::write(directory.join("sprite.png"), "loaded by name")?;
+
+        let mut packer = Packer::new(&directory)?;
+        let report = packer.scan()?;
+        assert_eq!(report.files.len(), 3);
+        assert!(report.missing.is_empty());
+        assert_eq!(
+            report.unreferenced.iter().collect::<Vec<_>>(),
+            vec![Path::new("/orphan.txt")]
+        );
+
+        let mut buf = Cursor::new(Vec::new());
+        let manifest = packer.write(&report, &mut buf)?;
+        fs::remove_dir_all(&directory)?;
+
+        let zipfs = ZipFS::from_read(Cursor::new(buf.into_inner()))?;
+        assert_eq!(Manifest::read(&zipfs)?, Some(manifest.clone()));
+        manifest.verify(&zipfs)?;
+        verify_archive(&zipfs)
+    }
+}
@@ -1,17 +1,25 @@
 use {
     anyhow::*,
+    derivative::*,
     hashbrown::HashMap,
     rlua::prelude::*,
-    std::io::{Read, Write},
+    serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+    std::{
+        collections::BTreeMap,
+        io::{Read, Write},
+        sync::Arc,
+    },
 };
 
 use crate::{
-    api::*, components::Persistent, ecs::*, EventArgs, EventName, Resources, Scheduler,
-    SludgeLuaContextExt, Space, Wakeup,
+    api::*, components::Persistent, ecs::*, filesystem::Filesystem, EventArgs, EventName,
+    Resources, Scheduler, SludgeLuaContextExt, Space, ThreadStats, Wakeup,
 };
 
-/// Create a new table under the `WORLD_TABLE_REGISTRY_KEY` and fill it with a mapping from
-/// 32-bit transient hecs entity IDs to serializer thunks.
+/// Create a new table and fill it with a record for every `Persistent` entity, containing
+/// its 32-bit transient hecs entity ID, its components as tables, and its `EntityTable`
+/// deserialization hook if it has one.
 pub fn record_world_table<'lua>(lua: LuaContext<'lua>, world: &World) -> LuaResult<LuaTable<'lua>> {
     let resources = lua.resources();
     let entity_ud_registry = resources.fetch::<EntityUserDataRegistry>();
@@ -22,13 +30,6 @@ pub fn record_world_table<'lua>(lua: LuaContext<'lua>, world: &World) -> LuaResu
         .eval::<LuaFunction>()?;
 
     let world_table = lua.create_table()?;
-    let world_metatable = lua.create_table()?;
-    world_metatable.set(
-        "__persist",
-        lua.named_registry_value::<_, LuaFunction>(PLAYBACK_THUNK_REGISTRY_KEY)?,
-    )?;
-    world_table.set_metatable(Some(world_metatable));
-    lua.set_named_registry_value(WORLD_TABLE_REGISTRY_KEY, world_table.clone())?;
 
     for (e, (maybe_et,)) in world
         .query::<(Option<&EntityTable>,)>()
@@ -69,7 +70,7 @@ pub fn record_world_table<'lua>(lua: LuaContext<'lua>, world: &World) -> LuaResu
     Ok(world_table)
 }
 
-/// Create a new table under the fill it with entries for queued and waiting threads.
+/// Create a new table and fill it with entries for queued and waiting threads.
 pub fn record_scheduler_table<'lua>(
     lua: LuaContext<'lua>,
     scheduler: &Scheduler,
@@ -81,24 +82,43 @@ pub fn record_scheduler_table<'lua>(
     for (i, thread) in scheduler.threads.iter() {
         let thread = lua.registry_value::<LuaThread>(thread)?;
         threads.insert(i, thread.clone());
-        waiting_table.set(thread, lua.create_table()?)?;
+        let thread_entry = lua.create_table()?;
+        if let Some(group) = scheduler.groups.get(&i.slot()) {
+            thread_entry.set("group", group.as_str())?;
+        }
+        if let Some(stats) = scheduler.stats.borrow().get(&i.slot()) {
+            thread_entry.set("spawned_at", stats.spawned_at())?;
+        }
+        if let Some(entry) = scheduler.entry_points.get(&i.slot()) {
+            thread_entry.set("entry", lua.registry_value::<LuaFunction>(entry)?)?;
+        }
+        waiting_table.set(thread, thread_entry)?;
     }
 
+    // Indices in `waiting` and `queue` may have been invalidated by the thread being
+    // woken through some other event; those entries are stale and are skipped.
     for (event_name, waiting_thread) in scheduler
         .waiting
         .iter()
         .flat_map(|(ev, ts)| ts.iter().map(move |t| (ev, t)))
     {
-        let thread_entry = waiting_table.get::<_, LuaTable>(threads[waiting_thread].clone())?;
-        thread_entry.set(thread_entry.len()? + 1, &*event_name.0)?;
+        if let Some(thread) = threads.get(waiting_thread) {
+            let thread_entry = waiting_table.get::<_, LuaTable>(thread.clone())?;
+            thread_entry.set(thread_entry.len()? + 1, &*event_name.0)?;
+        }
     }
 
     for wakeup in scheduler.queue.iter() {
+        let thread = match threads.get(&wakeup.thread()) {
+            Some(thread) => thread.clone(),
+            None => continue,
+        };
+
         let wakeup_table = lua.create_table()?;
         match wakeup {
-            Wakeup::Notify { thread: i, args } => {
+            Wakeup::Notify { args, .. } => {
                 wakeup_table.set("type", "immediate")?;
-                wakeup_table.set("thread", threads[i].clone())?;
+                wakeup_table.set("thread", thread.clone())?;
 
                 if let Some(args_i) = *args {
                     let tmp = scheduler.event_args[args_i]
@@ -108,13 +128,9 @@ pub fn record_scheduler_table<'lua>(
                     wakeup_table.set("args", tmp)?;
                 }
             }
-            Wakeup::Broadcast {
-                thread: i,
-                name,
-                args,
-            } => {
+            Wakeup::Broadcast { name, args, .. } => {
                 wakeup_table.set("type", "event")?;
-                wakeup_table.set("thread", threads[i].clone())?;
+                wakeup_table.set("thread", thread.clone())?;
                 wakeup_table.set("event", &*name.0)?;
 
                 if let Some(args_i) = *args {
@@ -125,12 +141,17 @@ pub fn record_scheduler_table<'lua>(
                     wakeup_table.set("args", tmp)?;
                 }
             }
-            Wakeup::Timed {
-                thread: i,
-                scheduled_for,
-            } => {
+            Wakeup::Timed { scheduled_for, .. } => {
                 wakeup_table.set("type", "timed")?;
-                wakeup_table.set("thread", threads[i].clone())?;
+                wakeup_table.set("thread", thread.clone())?;
+                wakeup_table.set(
+                    "scheduled_for",
+                    scheduled_for.saturating_sub(scheduler.discrete),
+                )?;
+            }
+            Wakeup::Timeout { scheduled_for, .. } => {
+                wakeup_table.set("type", "timeout")?;
+                wakeup_table.set("thread", thread.clone())?;
                 wakeup_table.set(
                     "scheduled_for",
                     scheduled_for.saturating_sub(scheduler.discrete),
@@ -149,20 +170,94 @@ pub fn record_scheduler_table<'lua>(
     Ok(scheduler_table)
 }
 
+/// Respawn every entity recorded by `record_world_table`.
+///
+/// New entities are reserved for every record before any components are inserted, so
+/// that entity userdata unpersisted from the old IDs can be retargeted first. This way
+/// components and deserialization hooks are free to refer to other persisted entities.
+pub fn playback_world_table<'lua>(
+    lua: LuaContext<'lua>,
+    world_table: LuaTable<'lua>,
+) -> Result<()> {
+    let records = world_table
+        .sequence_values::<LuaTable>()
+        .collect::<LuaResult<Vec<_>>>()?;
+
+    let mut remap = HashMap::new();
+    let mut entities = Vec::with_capacity(records.len());
+    {
+        let resources = lua.resources();
+        let mut world = resources.fetch_mut::<World>();
+        for record in &records {
+            let id = record.get::<_, LuaLightUserData>("id")?;
+            let entity = world.spawn(());
+            remap.insert(id.0 as usize, entity);
+            entities.push(entity);
+        }
+    }
+
+    retarget_entity_placeholders(lua, &remap)?;
+
+    for (record, entity) in records.into_iter().zip(entities) {
+        let components = record.get::<_, LuaValue>("components")?;
+        match record.get::<_, Option<LuaFunction>>("deserialize")? {
+            Some(deserialize) => {
+                deserialize.call::<_, ()>((LuaEntity::from(entity), components))?;
+            }
+            None => {
+                let table = LuaTable::from_lua(components, lua)?;
+                crate::api::insert(lua, (LuaEntity::from(entity), table))?;
+            }
+        }
+    }
+
+    finish_entity_placeholders(lua)?;
+
+    Ok(())
+}
+
 pub fn playback_scheduler_table<'lua>(
     lua: LuaContext<'lua>,
     scheduler_table: LuaTable<'lua>,
     scheduler: &mut Scheduler,
 ) -> Result<()> {
+    let waiting_table = scheduler_table.get::<_, LuaTable>("waiting")?;
     let queue_table = scheduler_table.get::<_, LuaTable>("queue")?;
-    // FIXME(sleffy): persisting the scheduler table does not yet persist threads which
-    // exist outside the queue
-    let _slots_table = lua.registry_value::<LuaTable>(&scheduler.slots)?;
+    let slots = lua.registry_value::<LuaTable>(&scheduler.slots)?;
+
+    // Every live thread has an entry in the waiting table, even if it isn't waiting
+    // on any events, so this is where threads get their slots back.
+    for pair in waiting_table.pairs::<LuaThread, LuaTable>() {
+        let (thread, event_names) = pair?;
+        let key = lua.create_registry_value(thread.clone())?;
+        let i = scheduler.threads.insert(key);
+        slots.set(thread, i.slot())?;
+
+        if let Some(group) = event_names.get::<_, Option<LuaString>>("group")? {
+            scheduler.groups.insert(i.slot(), group.to_str()?.into());
+        }
+        if let Some(entry) = event_names.get::<_, Option<LuaFunction>>("entry")? {
+            let entry = lua.create_registry_value(entry)?;
+            scheduler.entry_points.insert(i.slot(), entry);
+        }
+        let spawned_at = event_names.get::<_, Option<String>>("spawned_at")?;
+        scheduler
+            .stats
+            .borrow_mut()
+            .insert(i.slot(), ThreadStats::new(spawned_at));
+
+        for event_name in event_names.sequence_values::<LuaString>() {
+            scheduler.register(i, EventName(event_name?.to_str()?.into()));
+        }
+    }
+
     for item in queue_table.sequence_values::<LuaTable>() {
         let table = item?;
         let thread = table.get::<_, LuaThread>("thread")?;
-        let key = lua.create_registry_value(thread.clone())?;
-        let i = scheduler.threads.insert(key);
+        let i = slots
+            .get::<_, Option<u32>>(thread)?
+            .and_then(|slot| scheduler.threads.contains_slot(slot))
+            .ok_or_else(|| anyhow!("queued thread missing from the waiting table"))?;
         match table.get::<_, LuaString>("type")?.to_str()? {
             "immediate" => {
                 let event_args =
@@ -206,7 +301,13 @@ pub fn playback_scheduler_table<'lua>(
                     scheduled_for: scheduler.discrete + table.get::<_, u64>("scheduled_for")?,
                 });
             }
-            _ => unreachable!(),
+            "timeout" => {
+                scheduler.queue.push(Wakeup::Timeout {
+                    thread: i,
+                    scheduled_for: scheduler.discrete + table.get::<_, u64>("scheduled_for")?,
+                });
+            }
+            other => bail!("unknown wakeup type `{}` in persisted scheduler", other),
         }
     }
 
@@ -214,8 +315,16 @@ pub fn playback_scheduler_table<'lua>(
 }
 
 pub fn persist<'lua, W: Write>(lua: LuaContext<'lua>, space: &Space, writer: W) -> Result<()> {
-    let world_table = record_world_table(lua, &*space.world())?;
-    let scheduler_table = record_scheduler_table(lua, &*space.scheduler())?;
+    persist_with(lua, &*space.scheduler(), writer)
+}
+
+fn persist_with<'lua, W: Write>(
+    lua: LuaContext<'lua>,
+    scheduler: &Scheduler,
+    writer: W,
+) -> Result<()> {
+    let world_table = record_world_table(lua, &*lua.resources().fetch::<World>())?;
+    let scheduler_table = record_scheduler_table(lua, scheduler)?;
     let permanents = lua.named_registry_value::<_, LuaTable>(PERMANENTS_SER_TABLE_REGISTRY_KEY)?;
 
     let persisted_table =
@@ -228,15 +337,486 @@ pub fn persist<'lua, W: Write>(lua: LuaContext<'lua>, space: &Space, writer: W)
 }
 
 pub fn unpersist<'lua, R: Read>(lua: LuaContext<'lua>, space: &Space, reader: R) -> Result<()> {
+    let persisted_table = undump(lua, reader)?;
+    playback(lua, &mut *space.scheduler_mut(), persisted_table)
+}
+
+fn undump<'lua, R: Read>(lua: LuaContext<'lua>, reader: R) -> Result<LuaTable<'lua>> {
     let permanents = lua.named_registry_value::<_, LuaTable>(PERMANENTS_DE_TABLE_REGISTRY_KEY)?;
     lua.set_dump_setting("path", true)?;
-    let persisted_table = lua.undump_value::<_, _, LuaTable>(reader, permanents)?;
+    lua.set_named_registry_value(ENTITY_REMAP_TABLE_REGISTRY_KEY, lua.create_table()?)?;
+    Ok(lua.undump_value::<_, _, LuaTable>(reader, permanents)?)
+}
+
+pub(crate) fn playback<'lua>(
+    lua: LuaContext<'lua>,
+    scheduler: &mut Scheduler,
+    persisted_table: LuaTable<'lua>,
+) -> Result<()> {
+    playback_world_table(lua, persisted_table.get("world")?)?;
+    playback_scheduler_table(lua, persisted_table.get("scheduler")?, scheduler)?;
+
+    Ok(())
+}
+
+/// Magic number found at the start of every save written by [`save`].
+pub const SAVE_MAGIC: [u8; 8] = *b"SLUDGESV";
+
+/// Version of the save container format written by [`save`]. This describes the
+/// layout of the container itself, and is unrelated to [`Migrations::version`].
+pub const SAVE_FORMAT_VERSION: u32 = 1;
 
-    playback_scheduler_table(
-        lua,
-        persisted_table.get("scheduler")?,
-        &mut *space.scheduler_mut(),
-    )?;
+/// The longest [`SaveHeader`] we'll read, so that a corrupt length can't make us
+/// allocate gigabytes before we even get to parse it.
+pub const MAX_SAVE_HEADER_LEN: usize = 64 * 1024;
+
+/// Schema information written ahead of the persisted Lua state in a save.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SaveHeader {
+    /// The [`Migrations::version`] of the space which wrote the save.
+    pub version: u32,
+    /// The type names of every component registered with the
+    /// [`EntityUserDataRegistry`] of the space which wrote the save.
+    pub components: Vec<String>,
+}
+
+impl SaveHeader {
+    pub fn new<'a, R: Resources<'a>>(resources: &R) -> Self {
+        Self {
+            version: resources.fetch::<Migrations>().version(),
+            components: resources
+                .fetch::<EntityUserDataRegistry>()
+                .component_names()
+                .map(str::to_owned)
+                .collect(),
+        }
+    }
+
+    /// Write the magic number, the container format version, and then the header
+    /// itself.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        let encoded = serde_json::to_vec(self)?;
+        ensure!(
+            encoded.len() <= MAX_SAVE_HEADER_LEN,
+            "save header is too long ({} bytes, at most {} allowed)",
+            encoded.len(),
+            MAX_SAVE_HEADER_LEN
+        );
+        writer.write_all(&SAVE_MAGIC)?;
+        writer.write_all(&SAVE_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&(encoded.len() as u32).to_le_bytes())?;
+        writer.write_all(&encoded)?;
+        Ok(())
+    }
+
+    /// Read and check the magic number and the container format version, and
+    /// then read the header. The reader is left at the start of the persisted
+    /// Lua state.
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader
+            .read_exact(&mut magic)
+            .context("error reading save magic number")?;
+        ensure!(magic == SAVE_MAGIC, "not a sludge save (bad magic number)");
+
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf)?;
+        let format_version = u32::from_le_bytes(buf);
+        ensure!(
+            format_version == SAVE_FORMAT_VERSION,
+            "unsupported save format version {} (expected {})",
+            format_version,
+            SAVE_FORMAT_VERSION
+        );
+
+        reader.read_exact(&mut buf)?;
+        let len = u32::from_le_bytes(buf) as usize;
+        if len > MAX_SAVE_HEADER_LEN {
+            bail!(
+                "save header is too long ({} bytes, at most {} allowed)",
+                len,
+                MAX_SAVE_HEADER_LEN
+            );
+        }
+        let mut encoded = vec![0; len];
+        reader.read_exact(&mut encoded)?;
+        Ok(serde_json::from_slice(&encoded).context("error parsing save header")?)
+    }
+}
+
+pub type RustMigration = Arc<
+    dyn for<'lua> Fn(LuaContext<'lua>, LuaValue<'lua>) -> LuaResult<LuaValue<'lua>> + Send + Sync,
+>;
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub enum Migration {
+    Rust(#[derivative(Debug = "ignore")] RustMigration),
+    Lua(LuaRegistryKey),
+}
+
+/// Registry of functions which upgrade the persisted state of old saves.
+///
+/// Every space has a schema version, starting at zero, which is written into
+/// the header of its saves. A migration registered for version `n` upgrades
+/// from `n` to `n + 1`: it is called with the persisted components of every
+/// entity in the save (the table produced by the component accessors' `to_table`
+/// methods, or whatever an `EntityTable.serialize` hook returned instead) and
+/// returns the value to play back in its place. When loading a save, all
+/// migrations from the save's version up to the current version are run in
+/// order, after the Lua state is unpersisted but before any entities are
+/// respawned.
+///
+/// Renamed or removed permanents (functions and tables from the Lua API) can
+/// be handled with [`Migrations::alias_permanent`].
+#[derive(Debug, Default)]
+pub struct Migrations {
+    version: u32,
+    steps: BTreeMap<u32, Vec<Migration>>,
+    aliases: HashMap<String, String>,
+}
+
+impl Migrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    /// Register a Rust function migrating persisted components from version `from`
+    /// to version `from + 1`.
+    pub fn register<F>(&mut self, from: u32, migration: F)
+    where
+        F: for<'lua> Fn(LuaContext<'lua>, LuaValue<'lua>) -> LuaResult<LuaValue<'lua>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.steps
+            .entry(from)
+            .or_default()
+            .push(Migration::Rust(Arc::new(migration)));
+    }
+
+    /// Register a Lua function migrating persisted components from version `from`
+    /// to version `from + 1`.
+    pub fn register_lua<'lua>(
+        &mut self,
+        lua: LuaContext<'lua>,
+        from: u32,
+        migration: LuaFunction<'lua>,
+    ) -> Result<()> {
+        let key = lua.create_registry_value(migration)?;
+        self.steps
+            .entry(from)
+            .or_default()
+            .push(Migration::Lua(key));
+        Ok(())
+    }
+
+    /// Unpersist references to the permanent `old` as the permanent now registered
+    /// under `new`.
+    pub fn alias_permanent(&mut self, old: impl Into<String>, new: impl Into<String>) {
+        self.aliases.insert(old.into(), new.into());
+    }
+
+    fn apply_aliases<'lua>(&self, lua: LuaContext<'lua>) -> Result<()> {
+        let permanents =
+            lua.named_registry_value::<_, LuaTable>(PERMANENTS_DE_TABLE_REGISTRY_KEY)?;
+        for (old, new) in self.aliases.iter() {
+            match permanents.get::<_, LuaValue>(new.as_str())? {
+                LuaValue::Nil => bail!("permanent alias target `{}` is not registered", new),
+                value => permanents.set(old.as_str(), value)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run every migration from version `from` up to the current version over the
+    /// persisted components of each entity in `world_table`.
+    pub fn migrate_world_table<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        from: u32,
+        world_table: LuaTable<'lua>,
+    ) -> Result<()> {
+        ensure!(
+            from <= self.version,
+            "save version {} is newer than the current version {}",
+            from,
+            self.version
+        );
+
+        for (version, migrations) in self.steps.range(from..self.version) {
+            for migration in migrations {
+                for record in world_table.clone().sequence_values::<LuaTable>() {
+                    let record = record?;
+                    let components = record.get::<_, LuaValue>("components")?;
+                    let migrated = match migration {
+                        Migration::Rust(f) => f(lua, components),
+                        Migration::Lua(key) => lua
+                            .registry_value::<LuaFunction>(key)
+                            .and_then(|f| f.call::<_, LuaValue>(components)),
+                    }
+                    .with_context(|| anyhow!("error migrating save from version {}", version))?;
+                    record.set("components", migrated)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Persist a `Space` into a versioned save: a [`SaveHeader`] followed by the
+/// output of [`persist`].
+pub fn save<'lua, W: Write>(lua: LuaContext<'lua>, space: &Space, writer: W) -> Result<()> {
+    save_with(lua, &*space.scheduler(), writer)
+}
+
+pub(crate) fn save_with<'lua, W: Write>(
+    lua: LuaContext<'lua>,
+    scheduler: &Scheduler,
+    mut writer: W,
+) -> Result<()> {
+    SaveHeader::new(&lua.resources()).write(&mut writer)?;
+    persist_with(lua, scheduler, writer)
+}
+
+/// Load a save written by [`save`], running any [`Migrations`] needed to bring it
+/// up to the current version before playing it back into the `Space`.
+pub fn load<'lua, R: Read>(lua: LuaContext<'lua>, space: &Space, reader: R) -> Result<()> {
+    load_with(lua, &mut *space.scheduler_mut(), reader)
+}
+
+pub(crate) fn load_with<'lua, R: Read>(
+    lua: LuaContext<'lua>,
+    scheduler: &mut Scheduler,
+    reader: R,
+) -> Result<()> {
+    let persisted_table = undump_save(lua, reader)?;
+    playback(lua, scheduler, persisted_table)
+}
+
+/// Read the header of a save and unpersist its Lua state, running any migrations
+/// needed, without playing anything back.
+pub(crate) fn undump_save<'lua, R: Read>(
+    lua: LuaContext<'lua>,
+    mut reader: R,
+) -> Result<LuaTable<'lua>> {
+    let header = SaveHeader::read(&mut reader)?;
+    let resources = lua.resources();
+
+    let unregistered = {
+        let registry = resources.fetch::<EntityUserDataRegistry>();
+        header
+            .components
+            .iter()
+            .filter(|name| !registry.is_registered(name))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    if !unregistered.is_empty() {
+        log::warn!(
+            "save (version {}) was written with components which are no longer registered: {}",
+            header.version,
+            unregistered.join(", ")
+        );
+    }
+
+    let migrations = resources.fetch::<Migrations>();
+    migrations.apply_aliases(lua)?;
+    let persisted_table = undump(lua, reader).with_context(|| {
+        anyhow!(
+            "error unpersisting save (version {}, current version {})",
+            header.version,
+            migrations.version()
+        )
+    })?;
+    migrations.migrate_world_table(lua, header.version, persisted_table.get("world")?)?;
+
+    Ok(persisted_table)
+}
+
+/// Directory in the user directory of the `Filesystem` which save slots are
+/// written to.
+pub const SAVE_DIRECTORY: &'static str = "/saves";
+
+/// Get the path of the file backing a save slot. Slot names may only contain
+/// ASCII alphanumerics, `-` and `_`.
+pub fn save_slot_path(slot: &str) -> Result<String> {
+    ensure!(
+        !slot.is_empty()
+            && slot
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "invalid save slot name `{}`",
+        slot
+    );
+
+    Ok(format!("{}/{}.sav", SAVE_DIRECTORY, slot))
+}
+
+/// Number of older copies kept around for every save slot, which loading falls back
+/// to if the save itself is corrupt.
+pub const SAVE_BACKUP_COUNT: usize = 3;
+
+/// Magic number found at the start of every save slot written by
+/// [`write_save_slot`], followed by the SHA-256 hash of the save.
+pub const SAVE_SLOT_MAGIC: [u8; 8] = *b"SLUDGECK";
+
+/// Get the path of the `n`th most recent backup of a save slot, counting from 1.
+pub fn save_slot_backup_path(slot: &str, n: usize) -> Result<String> {
+    Ok(format!("{}.{}", save_slot_path(slot)?, n))
+}
+
+/// Write a save to a save slot, along with a checksum. The write is atomic, and
+/// the previous contents of the slot are kept as its first backup, shifting older
+/// backups along and dropping any past the `backups`th.
+pub fn write_save_slot(
+    fs: &mut Filesystem,
+    slot: &str,
+    bytes: &[u8],
+    backups: usize,
+) -> Result<()> {
+    let path = save_slot_path(slot)?;
+    fs.create_dir(SAVE_DIRECTORY)?;
+
+    // The current save is copied rather than moved into the first backup, so that there's
+    // always a save in the slot itself, even if writing the new one fails partway.
+    if backups > 0 && fs.exists(&path) {
+        let oldest = save_slot_backup_path(slot, backups)?;
+        if fs.exists(&oldest) {
+            fs.delete(&oldest)?;
+        }
+
+        for n in (1..backups).rev() {
+            let backup = save_slot_backup_path(slot, n)?;
+            if fs.exists(&backup) {
+                fs.rename(&backup, save_slot_backup_path(slot, n + 1)?)?;
+            }
+        }
+
+        let mut current = Vec::new();
+        fs.open(&path)?.read_to_end(&mut current)?;
+        fs.write_atomic(save_slot_backup_path(slot, 1)?, &current)?;
+    }
+
+    let mut checked = Vec::with_capacity(SAVE_SLOT_MAGIC.len() + 32 + bytes.len());
+    checked.extend_from_slice(&SAVE_SLOT_MAGIC);
+    checked.extend_from_slice(&Sha256::digest(bytes));
+    checked.extend_from_slice(bytes);
+    fs.write_atomic(&path, &checked)
+}
+
+/// Check a save slot file against its checksum, returning the save inside it. Save
+/// slots written before checksums were added are passed through unchecked.
+fn verify_save_slot(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if bytes.starts_with(&SAVE_MAGIC) {
+        return Ok(bytes);
+    }
+
+    let header_len = SAVE_SLOT_MAGIC.len() + 32;
+    ensure!(
+        bytes.len() >= header_len && bytes.starts_with(&SAVE_SLOT_MAGIC),
+        "not a sludge save slot (bad magic number)"
+    );
+
+    let (hash, save) = bytes[SAVE_SLOT_MAGIC.len()..].split_at(32);
+    ensure!(
+        Sha256::digest(save).as_slice() == hash,
+        "save is corrupt (checksum mismatch)"
+    );
+
+    Ok(save.to_vec())
+}
+
+/// The numbers of every backup of a save slot which exists, from newest to oldest.
+/// There may be gaps, if saving was interrupted while backups were being shifted.
+fn save_slot_backups(fs: &mut Filesystem, slot: &str) -> Result<Vec<usize>> {
+    if !fs.is_dir(SAVE_DIRECTORY) {
+        return Ok(Vec::new());
+    }
+
+    let prefix = format!("{}.sav.", slot);
+    let mut backups = fs
+        .read_dir(SAVE_DIRECTORY)?
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?.to_owned();
+            name.strip_prefix(&prefix)?.parse::<usize>().ok()
+        })
+        .filter(|&n| n > 0)
+        .collect::<Vec<_>>();
+    backups.sort_unstable();
+    Ok(backups)
+}
+
+/// Read the save in a save slot, checking it against its checksum. If the save is
+/// missing or corrupt, every one of its backups is tried from newest to oldest, and
+/// the first valid one is returned instead.
+pub fn read_save_slot(fs: &mut Filesystem, slot: &str) -> Result<Vec<u8>> {
+    let path = save_slot_path(slot)?;
+    let read = |fs: &mut Filesystem, path: &str| -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        fs.open(path)?.read_to_end(&mut bytes)?;
+        verify_save_slot(bytes)
+    };
+
+    let err = match read(fs, &path) {
+        Ok(bytes) => return Ok(bytes),
+        Err(err) => err,
+    };
+
+    for n in save_slot_backups(fs, slot)? {
+        let backup = save_slot_backup_path(slot, n)?;
+        match read(fs, &backup) {
+            Ok(bytes) => {
+                log::warn!(
+                    "save slot `{}` couldn't be read ({:#}); falling back to backup {}",
+                    slot,
+                    err,
+                    n
+                );
+                return Ok(bytes);
+            }
+            Err(backup_err) => {
+                log::warn!(
+                    "backup {} of save slot `{}` is bad: {:#}",
+                    n,
+                    slot,
+                    backup_err
+                )
+            }
+        }
+    }
+
+    Err(err.context(format!("error reading save slot `{}`", slot)))
+}
+
+/// Every `Persistent` entity, which is everything a save replaces.
+pub fn persistent_entities(world: &World) -> Vec<Entity> {
+    world
+        .query::<()>()
+        .with::<Persistent>()
+        .iter()
+        .map(|(e, _)| e)
+        .collect()
+}
+
+/// Despawn every `Persistent` entity, in preparation for playing back a save in
+/// place of the current state.
+pub fn despawn_persistent(world: &mut World) -> Result<()> {
+    for entity in persistent_entities(world) {
+        world.despawn(entity)?;
+    }
 
     Ok(())
 }
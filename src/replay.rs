@@ -0,0 +1,356 @@
+//! Deterministic recording and playback of runs.
+//!
+//! The scheduler runs on discrete ticks, so as long as every source of
+//! nondeterminism is recorded, a run can be reproduced exactly by feeding the
+//! same inputs back in the same order. A [`Replay`] records, for every update:
+//!
+//! * the RNG seeds the game (re)seeded its random number generators with,
+//! * the input effects applied through [`InputState::update_effect`],
+//! * the step passed to `Scheduler::update`, in ticks, and
+//! * the `dt` passed to [`InputState::update`], in seconds.
+//!
+//! The scheduler and the input state count time in different units, so both are
+//! recorded as they were passed rather than converting one into the other, which
+//! would make played back axes drift away from the recorded ones.
+//!
+//! Playback assumes that the game's update loop does things in that order:
+//! reseed, apply input, then update the scheduler and the input state.
+
+use {
+    anyhow::*,
+    rlua::prelude::*,
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    std::{
+        hash::Hash,
+        io::{Read, Write},
+    },
+};
+
+use crate::{
+    input::{InputEffect, InputState},
+    Space,
+};
+
+/// Magic number found at the start of every replay written by [`Replay::write`].
+pub const REPLAY_MAGIC: [u8; 8] = *b"SLUDGERP";
+
+/// Version of the replay format written by [`Replay::write`].
+pub const REPLAY_FORMAT_VERSION: u32 = 2;
+
+/// Everything which happened during a single update.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "Axes: Serialize, Buttons: Serialize",
+    deserialize = "Axes: DeserializeOwned, Buttons: DeserializeOwned"
+))]
+pub struct ReplayTick<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    /// Seeds which RNGs were reseeded with before this update.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub seeds: Vec<u64>,
+    /// Input effects applied before this update, and whether they were started.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<(InputEffect<Axes, Buttons>, bool)>,
+    /// The step passed to `Scheduler::update`, in ticks.
+    pub dt: f32,
+    /// The `dt` passed to `InputState::update`, in seconds.
+    pub input_dt: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "Axes: Serialize, Buttons: Serialize",
+    deserialize = "Axes: DeserializeOwned, Buttons: DeserializeOwned"
+))]
+pub struct Replay<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    ticks: Vec<ReplayTick<Axes, Buttons>>,
+}
+
+impl<Axes, Buttons> Replay<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    pub fn new() -> Self {
+        Self { ticks: Vec::new() }
+    }
+
+    pub fn ticks(&self) -> &[ReplayTick<Axes, Buttons>] {
+        &self.ticks
+    }
+
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    pub fn player(&self) -> ReplayPlayer<'_, Axes, Buttons> {
+        ReplayPlayer {
+            replay: self,
+            position: 0,
+        }
+    }
+
+    /// Play the whole replay back headlessly. For every recorded update, `reseed`
+    /// is called with each recorded seed, the recorded input effects are fed to
+    /// `input`, and then the scheduler of `space` and `input` are updated with
+    /// their recorded steps.
+    pub fn play<F>(
+        &self,
+        space: &Space,
+        input: &mut InputState<Axes, Buttons>,
+        mut reseed: F,
+    ) -> Result<()>
+    where
+        F: for<'lua> FnMut(LuaContext<'lua>, u64) -> Result<()>,
+    {
+        let mut player = self.player();
+        space.lua().context(|lua| {
+            while let Some(tick) = player.next_tick(input) {
+                for &seed in &tick.seeds {
+                    reseed(lua, seed)?;
+                }
+
+                space.scheduler_mut().update(lua, tick.dt)?;
+                input.update(tick.input_dt);
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl<Axes, Buttons> Replay<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone + Serialize + DeserializeOwned,
+    Buttons: Eq + Hash + Clone + Serialize + DeserializeOwned,
+{
+    /// Write the magic number and format version, followed by the
+    /// zstd-compressed replay.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        let encoded = serde_json::to_vec(self)?;
+        writer.write_all(&REPLAY_MAGIC)?;
+        writer.write_all(&REPLAY_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&zstd::stream::encode_all(&encoded[..], 0)?)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader
+            .read_exact(&mut magic)
+            .context("error reading replay magic number")?;
+        ensure!(
+            magic == REPLAY_MAGIC,
+            "not a sludge replay (bad magic number)"
+        );
+
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf)?;
+        let format_version = u32::from_le_bytes(buf);
+        ensure!(
+            format_version == REPLAY_FORMAT_VERSION,
+            "unsupported replay format version {} (expected {})",
+            format_version,
+            REPLAY_FORMAT_VERSION
+        );
+
+        let encoded = zstd::stream::decode_all(reader)?;
+        Ok(serde_json::from_slice(&encoded).context("error parsing replay")?)
+    }
+}
+
+/// Records a [`Replay`] as a game runs.
+///
+/// Call [`ReplayRecorder::start`] to make the `InputState` start recording its
+/// effects, [`ReplayRecorder::record_seed`] whenever an RNG is (re)seeded, and
+/// [`ReplayRecorder::end_tick`] every update, with the step passed to
+/// `Scheduler::update` and the `dt` passed to `InputState::update`.
+#[derive(Debug)]
+pub struct ReplayRecorder<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    replay: Replay<Axes, Buttons>,
+    seeds: Vec<u64>,
+}
+
+impl<Axes, Buttons> ReplayRecorder<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    pub fn start(input: &mut InputState<Axes, Buttons>) -> Self {
+        input.start_recording();
+        Self {
+            replay: Replay::new(),
+            seeds: Vec::new(),
+        }
+    }
+
+    pub fn record_seed(&mut self, seed: u64) {
+        self.seeds.push(seed);
+    }
+
+    pub fn end_tick(&mut self, input: &mut InputState<Axes, Buttons>, dt: f32, input_dt: f32) {
+        self.replay.ticks.push(ReplayTick {
+            seeds: self.seeds.drain(..).collect(),
+            inputs: input.drain_recorded(),
+            dt,
+            input_dt,
+        });
+    }
+
+    pub fn finish(self, input: &mut InputState<Axes, Buttons>) -> Replay<Axes, Buttons> {
+        input.stop_recording();
+        self.replay
+    }
+}
+
+/// Steps through a [`Replay`] one update at a time, for games which need to
+/// drive playback through their own update loop.
+#[derive(Debug)]
+pub struct ReplayPlayer<'a, Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    replay: &'a Replay<Axes, Buttons>,
+    position: usize,
+}
+
+impl<'a, Axes, Buttons> ReplayPlayer<'a, Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    /// Feed the next update's recorded input effects to `input`, and return the
+    /// update so that the caller can reseed and update with its recorded steps.
+    pub fn next_tick(
+        &mut self,
+        input: &mut InputState<Axes, Buttons>,
+    ) -> Option<&'a ReplayTick<Axes, Buttons>> {
+        let tick = self.replay.ticks.get(self.position)?;
+        self.position += 1;
+
+        for (effect, started) in &tick.inputs {
+            input.update_effect(effect.clone(), *started);
+        }
+
+        Some(tick)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.replay.ticks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::*;
+
+    #[derive(Debug, Hash, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+    enum Axes {
+        Horz,
+    }
+
+    #[derive(Debug, Hash, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+    enum Buttons {
+        Fire,
+    }
+
+    #[test]
+    fn replay_roundtrip() -> Result<()> {
+        let mut input = InputState::<Axes, Buttons>::new();
+        let mut recorder = ReplayRecorder::start(&mut input);
+
+        recorder.record_seed(0xdead_beef_cafe_f00d);
+        input.update_axis_start(Axes::Horz, true);
+        recorder.end_tick(&mut input, 1., 1. / 60.);
+        input.update_button_down(Buttons::Fire);
+        input.update_mouse_position(Point2::new(0.1, 0.2));
+        recorder.end_tick(&mut input, 2., 1. / 30.);
+
+        let replay = recorder.finish(&mut input);
+        assert!(!input.is_recording());
+
+        let mut bytes = Vec::new();
+        replay.write(&mut bytes)?;
+        let read = Replay::<Axes, Buttons>::read(&bytes[..])?;
+        assert_eq!(read, replay);
+
+        let mut played = InputState::new();
+        let mut player = read.player();
+        let tick = player.next_tick(&mut played).unwrap();
+        assert_eq!(tick.seeds, vec![0xdead_beef_cafe_f00d]);
+        assert_eq!(tick.dt, 1.);
+        assert_eq!(tick.input_dt, 1. / 60.);
+        assert_eq!(played.get_axis_raw(Axes::Horz), 1.);
+
+        let tick = player.next_tick(&mut played).unwrap();
+        assert!(tick.seeds.is_empty());
+        assert!(played.get_button_down(Buttons::Fire));
+        assert_eq!(played.mouse_position(), Point2::new(0.1, 0.2));
+        assert!(player.next_tick(&mut played).is_none());
+        assert!(player.is_finished());
+
+        Ok(())
+    }
+
+    #[test]
+    fn replayed_axes_match_recorded() -> Result<()> {
+        let mut input = InputState::<Axes, Buttons>::new();
+        let mut recorder = ReplayRecorder::start(&mut input);
+        let mut recorded = Vec::new();
+
+        for tick in 0..20 {
+            match tick {
+                0 => input.update_axis_start(Axes::Horz, true),
+                8 => input.update_axis_stop(Axes::Horz, true),
+                12 => input.update_axis_start(Axes::Horz, false),
+                _ => {}
+            }
+            recorder.end_tick(&mut input, 1., 1. / 60.);
+            input.update(1. / 60.);
+            recorded.push(input.get_axis(Axes::Horz));
+        }
+
+        let replay = recorder.finish(&mut input);
+        let space = Space::new()?;
+        let mut played = InputState::new();
+        let mut positions = Vec::new();
+        let mut player = replay.player();
+        space.lua().context(|lua| -> Result<()> {
+            while let Some(tick) = player.next_tick(&mut played) {
+                assert_eq!(tick.dt, 1.);
+                space.scheduler_mut().update(lua, tick.dt)?;
+                played.update(tick.input_dt);
+                positions.push(played.get_axis(Axes::Horz));
+            }
+            Ok(())
+        })?;
+
+        // The axis takes several ticks to accelerate, so a replay which updated the
+        // input with the scheduler's step would saturate it immediately.
+        assert!(recorded[0] > 0. && recorded[0] < 1.);
+        assert_eq!(positions, recorded);
+
+        let mut whole = InputState::new();
+        replay.play(&space, &mut whole, |_, _| Ok(()))?;
+        assert_eq!(whole.get_axis(Axes::Horz), recorded[19]);
+
+        Ok(())
+    }
+}
@@ -9,7 +9,7 @@ use {
 
 use crate::{
     api::{LuaComponent, LuaComponentInterface},
-    assets::{Asset, Cache, Cached, DefaultCache, Key, Loaded},
+    assets::{Asset, Cache, Cached, DefaultCache, Key, Loaded, LuaAsset},
     ecs::*,
     filesystem::Filesystem,
     math::*,
@@ -40,6 +40,13 @@ impl<'lua> FromLua<'lua> for TagId {
 #[serde(transparent)]
 pub struct FrameId(u32);
 
+impl FrameId {
+    /// The ID of the frame at the given index into a sheet's frames.
+    pub fn new(index: u32) -> Self {
+        FrameId(index)
+    }
+}
+
 impl<'lua> ToLua<'lua> for FrameId {
     fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
         self.0.to_lua(lua)
@@ -348,6 +355,10 @@ impl Asset for SpriteSheet {
     }
 }
 
+inventory::submit! {
+    LuaAsset::new::<SpriteSheet>("SpriteSheet")
+}
+
 #[derive(Debug, Clone)]
 pub struct SpriteAnimation {
     pub frame: SpriteFrame,
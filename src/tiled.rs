@@ -13,16 +13,48 @@ use {
 
 use crate::{
     api::LuaComponentInterface,
-    assets::{Asset, Cache, Key, Loaded},
+    assets::{Asset, Cache, Key, Loaded, LuaAsset},
     ecs::*,
     filesystem::Filesystem,
+    graphics::{Color, InstanceParam},
     math::*,
-    tiled::xml_parser::LayerData,
+    pack::AssetDependencies,
+    sprite::{self, SpriteSheet},
+    tiled::xml_parser::{
+        LayerData, ALL_FLIP_FLAGS, FLIPPED_DIAGONALLY_FLAG, FLIPPED_HORIZONTALLY_FLAG,
+        FLIPPED_VERTICALLY_FLAG,
+    },
     Resources, SludgeLuaContextExt, SludgeResultExt,
 };
 
+pub mod collision;
+mod json_parser;
+pub mod orientation;
+pub mod prefab;
+pub mod renderer;
 mod xml_parser;
 
+pub use orientation::{Orientation, StaggerAxis, StaggerIndex};
+
+/// Parse a map in whichever of Tiled's formats its extension says it's in.
+fn parse_map(fs: &mut Filesystem, path: &Path) -> Result<xml_parser::Map> {
+    if json_parser::is_json(path) {
+        json_parser::parse_file(fs, path)
+    } else {
+        xml_parser::parse_file(fs, path)
+    }
+}
+
+/// Parse a standalone tileset in whichever of Tiled's formats its extension says it's in.
+fn parse_tileset(fs: &mut Filesystem, path: &Path) -> Result<xml_parser::Tileset> {
+    let fh = fs.open(path)?;
+    if json_parser::is_json(path) {
+        json_parser::parse_tileset(fh, 1)
+    } else {
+        xml_parser::parse_tileset(fh, 1)
+    }
+}
+
 fn unwrap_object(value: Value) -> serde_json::Map<String, Value> {
     match value {
         Value::Object(map) => map,
@@ -69,11 +101,24 @@ pub struct TileData<T> {
     pub tile_type: Option<String>,
     pub local_id: u32,
     pub frames: Option<Vec<Frame>>,
+    /// Shapes drawn on the tile in Tiled's collision editor.
+    #[serde(default)]
+    pub colliders: Vec<TileCollider>,
 
     #[serde(bound(deserialize = "T: Properties"))]
     pub properties: T,
 }
 
+/// A collision shape drawn on a tile, positioned relative to the tile's top-left
+/// corner and rotated by `rot` degrees around its own origin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileCollider {
+    pub x: f32,
+    pub y: f32,
+    pub rot: f32,
+    pub shape: ObjectShape,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(bound(deserialize = "T: Properties"))]
 pub struct TileSheet<T> {
@@ -123,6 +168,26 @@ impl<T> TileSheet<T> {
             .tiles
             .iter()
             .map(|tile| {
+                let mut colliders = Vec::new();
+                for object in tile.objectgroup.iter().flat_map(|group| &group.objects) {
+                    match ObjectShape::from_tiled(&object.shape) {
+                        ObjectShape::Point(..) => {}
+                        shape @ ObjectShape::Rect { .. } | shape @ ObjectShape::Polygon { .. } => {
+                            colliders.push(TileCollider {
+                                x: object.x,
+                                y: object.y,
+                                rot: object.rotation,
+                                shape,
+                            })
+                        }
+                        ObjectShape::Ellipse { .. } | ObjectShape::Polyline { .. } => log::warn!(
+                            "ignoring unsupported collision shape on tile {} of tileset `{}`",
+                            tile.id,
+                            tiled.name
+                        ),
+                    }
+                }
+
                 let tile_data = TileData {
                     tile_type: tile.tile_type.clone(),
                     local_id: tile.id,
@@ -135,6 +200,7 @@ impl<T> TileSheet<T> {
                             })
                             .collect()
                     }),
+                    colliders,
                     properties: deserialize_properties(
                         &tile.properties,
                         Some(unwrap_object(json!({
@@ -212,10 +278,61 @@ impl<T> TileSheet<T> {
         self.get_region_from_local_id(gid - self.first_global_id)
     }
 
+    /// Get an `InstanceParam` which draws a tile flipped the way Tiled says to. The
+    /// tile is drawn at its size in pixels, with its width and height swapped if it's
+    /// flipped diagonally (see [`TileFlip::footprint`]), with its top-left corner at the
+    /// origin, and then transformed by `tx`, which should place it, for example by
+    /// translating to the tile's position.
+    pub fn get_instance_param_from_global_id(
+        &self,
+        gid: u32,
+        flip: TileFlip,
+        tx: &Transform3<f32>,
+    ) -> InstanceParam {
+        let region = self.get_region_from_global_id(gid);
+        // Drawables scale each sprite up to the size of its source rectangle, so the
+        // flip has to happen at that size.
+        let size = na::convert::<_, Vector2<f32>>(region.bounds.extents());
+        InstanceParam::new()
+            .src(region.uv)
+            .prepend_transform(tx)
+            .scale2(flip.footprint(size))
+            .prepend_transform(&flip.to_transform())
+            .scale2(size.map(f32::recip))
+    }
+
     pub fn iter_regions(&self) -> impl Iterator<Item = TileSheetRegion> + '_ {
         (0..self.tile_count).map(move |local_id| self.get_region_from_local_id(local_id))
     }
 
+    /// Build a `SpriteSheet` with a frame for every tile, indexed by local ID. Tile
+    /// animations aren't carried over as tags, since their frames needn't be
+    /// consecutive tiles.
+    pub fn to_sprite_sheet(&self) -> SpriteSheet {
+        let frames = self
+            .iter_regions()
+            .map(|region| {
+                let extents = region.bounds.extents();
+                sprite::Frame {
+                    frame: region.bounds,
+                    frame_source: Box2::new(0, 0, extents.x, extents.y),
+                    source_size: extents,
+                    offset: (-na::convert::<_, Vector2<f32>>(extents) / 2.).map(f32::floor),
+                    uvs: region.uv,
+                    duration: 0,
+                }
+            })
+            .collect();
+
+        SpriteSheet {
+            image: self.source.to_string_lossy().into_owned(),
+            tag_ids: HashMap::new(),
+            tags: Vec::new(),
+            frames,
+            size: Vector2::new(self.sheet_width, self.sheet_height),
+        }
+    }
+
     pub fn iter_tile_data(&self) -> impl Iterator<Item = (u32, &TileData<T>)> + '_ {
         self.tile_data
             .iter()
@@ -244,24 +361,116 @@ impl<T> TileSheet<T> {
     }
 }
 
+/// How a tile is flipped. Tiled represents rotations as combinations of flips; for
+/// example, a tile rotated 90 degrees clockwise is flipped diagonally and then
+/// horizontally.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TileFlip {
+    pub horizontal: bool,
+    pub vertical: bool,
+    /// Flipped over the top-left to bottom-right diagonal, swapping the x and y axes.
+    /// This is applied before the other two.
+    pub diagonal: bool,
+}
+
+impl TileFlip {
+    /// Split a gid as Tiled stores it into the actual gid and its flip flags.
+    pub fn split_gid(raw: u32) -> (u32, Self) {
+        let flip = Self {
+            horizontal: raw & FLIPPED_HORIZONTALLY_FLAG != 0,
+            vertical: raw & FLIPPED_VERTICALLY_FLAG != 0,
+            diagonal: raw & FLIPPED_DIAGONALLY_FLAG != 0,
+        };
+
+        (raw & !ALL_FLIP_FLAGS, flip)
+    }
+
+    /// Combine a gid with these flip flags, the way Tiled stores them.
+    pub fn join_gid(self, gid: u32) -> u32 {
+        let mut raw = gid & !ALL_FLIP_FLAGS;
+        if self.horizontal {
+            raw |= FLIPPED_HORIZONTALLY_FLAG;
+        }
+        if self.vertical {
+            raw |= FLIPPED_VERTICALLY_FLAG;
+        }
+        if self.diagonal {
+            raw |= FLIPPED_DIAGONALLY_FLAG;
+        }
+        raw
+    }
+
+    pub fn is_flipped(self) -> bool {
+        self.horizontal || self.vertical || self.diagonal
+    }
+
+    /// The size a tile of the given size takes up once flipped. Flipping diagonally
+    /// swaps its width and height.
+    pub fn footprint(self, size: Vector2<f32>) -> Vector2<f32> {
+        if self.diagonal {
+            Vector2::new(size.y, size.x)
+        } else {
+            size
+        }
+    }
+
+    /// A transform mapping the unit square onto itself such that a quad drawn in the
+    /// unit square shows its texture flipped. It has to be applied before anything
+    /// which moves the quad out of the unit square.
+    pub fn to_transform(self) -> Transform3<f32> {
+        let mut tx = Matrix4::identity();
+        if self.diagonal {
+            tx.swap_rows(0, 1);
+        }
+        if self.horizontal {
+            tx = Matrix4::new_translation(&Vector3::x())
+                * Matrix4::new_nonuniform_scaling(&Vector3::new(-1., 1., 1.))
+                * tx;
+        }
+        if self.vertical {
+            tx = Matrix4::new_translation(&Vector3::y())
+                * Matrix4::new_nonuniform_scaling(&Vector3::new(1., -1., 1.))
+                * tx;
+        }
+        Transform3::from_matrix_unchecked(tx)
+    }
+}
+
+impl From<xml_parser::LayerTile> for TileFlip {
+    fn from(lt: xml_parser::LayerTile) -> Self {
+        Self {
+            horizontal: lt.flip_h,
+            vertical: lt.flip_v,
+            diagonal: lt.flip_d,
+        }
+    }
+}
+
+impl<'a> SmartComponent<ScContext<'a>> for TileFlip {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chunk {
     pub x: i32,
     pub y: i32,
     pub w: u32,
     pub h: u32,
+    /// Gids as Tiled stores them, with the flip flags in the top three bits. Use
+    /// [`Chunk::tiles`] or [`TileFlip::split_gid`] to separate them.
     pub data: Vec<u32>,
 }
 
 impl Chunk {
-    pub fn tiles(&self) -> impl Iterator<Item = ((i32, i32), u32)> + '_ {
+    pub fn tiles(&self) -> impl Iterator<Item = ((i32, i32), u32, TileFlip)> + '_ {
         let (w, x, y) = (self.w, self.x, self.y);
         self.data
             .iter()
             .copied()
             .enumerate()
             .map(move |(i, n)| ((i as u32 % w, i as u32 / w), n))
-            .map(move |((i, j), n)| ((i as i32 + x, j as i32 + y), n))
+            .map(move |((i, j), n)| {
+                let (gid, flip) = TileFlip::split_gid(n);
+                ((i as i32 + x, j as i32 + y), gid, flip)
+            })
     }
 }
 
@@ -271,6 +480,11 @@ pub struct TileLayer<L> {
     pub name: Option<String>,
     pub opacity: f32,
     pub visible: bool,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    pub tint: Color,
     pub chunks: HashMap<(i32, i32), Chunk>,
     pub properties: L,
 }
@@ -283,6 +497,11 @@ impl Default for DefaultTileLayer {
             name: None,
             opacity: 1.0,
             visible: true,
+            offset_x: 0.,
+            offset_y: 0.,
+            parallax_x: 1.0,
+            parallax_y: 1.0,
+            tint: Color::WHITE,
             chunks: HashMap::new(),
             properties: Value::Object(Default::default()),
         }
@@ -303,19 +522,49 @@ pub struct ImageLayer<L> {
     pub visible: bool,
     pub offset_x: f32,
     pub offset_y: f32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    pub tint: Color,
     pub source: PathBuf,
     pub image_width: u32,
     pub image_height: u32,
     pub properties: L,
 }
 
+/// The shape of an object, relative to its position. Rectangles and ellipses extend
+/// right and down from it, and polylines are open paths which don't enclose anything.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ObjectShape {
     Rect { width: f32, height: f32 },
+    Ellipse { width: f32, height: f32 },
     Polygon { points: Vec<(f32, f32)> },
+    Polyline { points: Vec<(f32, f32)> },
     Point(f32, f32),
 }
 
+impl ObjectShape {
+    fn from_tiled(shape: &xml_parser::ObjectShape) -> Self {
+        use xml_parser::ObjectShape::*;
+        match shape {
+            Rect { width, height } => ObjectShape::Rect {
+                width: *width,
+                height: *height,
+            },
+            Ellipse { width, height } => ObjectShape::Ellipse {
+                width: *width,
+                height: *height,
+            },
+            Polygon { points } => ObjectShape::Polygon {
+                points: points.clone(),
+            },
+            Polyline { points } => ObjectShape::Polyline {
+                points: points.clone(),
+            },
+            Point(x, y) => ObjectShape::Point(*x, *y),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(bound(deserialize = "O: Properties"))]
 pub struct Object<O> {
@@ -339,16 +588,350 @@ pub struct ObjectLayer<L, O> {
     pub name: String,
     pub opacity: f32,
     pub visible: bool,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    pub tint: Color,
     pub objects: Vec<Object<O>>,
     pub properties: L,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "L: Properties, O: Properties"))]
+pub struct GroupLayer<L, O> {
+    pub name: String,
+    pub opacity: f32,
+    pub visible: bool,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    pub tint: Color,
+    pub layers: Vec<Layer<L, O>>,
+    pub properties: L,
+}
+
+/// A layer of a map. The opacity, visibility, offset, parallax factors and tint of a
+/// layer inside a group are already composed with the group's, and its ancestors', so
+/// every layer can be drawn without looking at the groups it's in.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(bound(deserialize = "L: Properties, O: Properties"))]
 pub enum Layer<L, O> {
     TileLayer(TileLayer<L>),
     ImageLayer(ImageLayer<L>),
     ObjectLayer(ObjectLayer<L, O>),
+    Group(GroupLayer<L, O>),
+}
+
+/// A layer's drawing attributes, composed with those of the groups it's in.
+#[derive(Debug, Clone, Copy)]
+struct Composed {
+    opacity: f32,
+    visible: bool,
+    offset_x: f32,
+    offset_y: f32,
+    parallax_x: f32,
+    parallax_y: f32,
+    tint: Color,
+}
+
+impl Default for Composed {
+    fn default() -> Self {
+        Self {
+            opacity: 1.0,
+            visible: true,
+            offset_x: 0.,
+            offset_y: 0.,
+            parallax_x: 1.0,
+            parallax_y: 1.0,
+            tint: Color::WHITE,
+        }
+    }
+}
+
+impl Composed {
+    fn compose(&self, child: &Self) -> Self {
+        Self {
+            opacity: self.opacity * child.opacity,
+            visible: self.visible && child.visible,
+            offset_x: self.offset_x + child.offset_x,
+            offset_y: self.offset_y + child.offset_y,
+            parallax_x: self.parallax_x * child.parallax_x,
+            parallax_y: self.parallax_y * child.parallax_y,
+            tint: Color::new(
+                self.tint.r * child.tint.r,
+                self.tint.g * child.tint.g,
+                self.tint.b * child.tint.b,
+                self.tint.a * child.tint.a,
+            ),
+        }
+    }
+}
+
+// Every kind of layer in the parser has the same drawing attributes, but no common type
+// to get them from.
+macro_rules! impl_composed_from {
+    ($($layer:ty),*) => {
+        $(
+            impl From<&$layer> for Composed {
+                fn from(layer: &$layer) -> Self {
+                    Self {
+                        opacity: layer.opacity,
+                        visible: layer.visible,
+                        offset_x: layer.offset_x,
+                        offset_y: layer.offset_y,
+                        parallax_x: layer.parallax_x,
+                        parallax_y: layer.parallax_y,
+                        // Tiled's tints are `0xAARRGGBB`.
+                        tint: layer.tint.map_or(Color::WHITE, |argb| {
+                            Color::from_rgba_u32(argb.rotate_left(8))
+                        }),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_composed_from!(
+    xml_parser::Layer,
+    xml_parser::ImageLayer,
+    xml_parser::ObjectGroup,
+    xml_parser::Group
+);
+
+/// Convert the layers directly inside a group, or the map itself if `group` is `None`,
+/// composing each one's drawing attributes with `parent`.
+fn layers_from_tiled<L, O>(
+    tiled: &xml_parser::Map,
+    group: Option<&xml_parser::Group>,
+    parent: &Composed,
+) -> Result<Vec<Layer<L, O>>>
+where
+    L: Properties,
+    O: Properties,
+{
+    let (tile_layers, image_layers, object_groups, groups) = match group {
+        Some(group) => (
+            &group.layers,
+            &group.image_layers,
+            &group.object_groups,
+            &group.groups,
+        ),
+        None => (
+            &tiled.layers,
+            &tiled.image_layers,
+            &tiled.object_groups,
+            &tiled.groups,
+        ),
+    };
+
+    let mut layers = Vec::new();
+
+    for layer in tile_layers.iter() {
+        let composed = parent.compose(&Composed::from(layer));
+        let mut chunks = HashMap::new();
+
+        match &layer.tiles {
+            LayerData::Finite(data) => {
+                chunks.insert(
+                    (0, 0),
+                    Chunk {
+                        x: 0,
+                        y: 0,
+                        w: tiled.width,
+                        h: tiled.height,
+                        data: data
+                            .iter()
+                            .flatten()
+                            .map(|&lt| TileFlip::from(lt).join_gid(lt.gid))
+                            .collect(),
+                    },
+                );
+            }
+            LayerData::Infinite(tiled_chunks) => {
+                for (&(x, y), tiled_chunk) in tiled_chunks.iter() {
+                    chunks.insert(
+                        (x, y),
+                        Chunk {
+                            x: tiled_chunk.x,
+                            y: tiled_chunk.y,
+                            w: tiled_chunk.width,
+                            h: tiled_chunk.height,
+                            data: tiled_chunk
+                                .tiles
+                                .iter()
+                                .flatten()
+                                .map(|&lt| TileFlip::from(lt).join_gid(lt.gid))
+                                .collect(),
+                        },
+                    );
+                }
+            }
+        }
+
+        let tile_layer = Layer::TileLayer(TileLayer {
+            name: Some(layer.name.clone()),
+            visible: composed.visible,
+            opacity: composed.opacity,
+            offset_x: composed.offset_x,
+            offset_y: composed.offset_y,
+            parallax_x: composed.parallax_x,
+            parallax_y: composed.parallax_y,
+            tint: composed.tint,
+            chunks,
+            properties: deserialize_properties(
+                &layer.properties,
+                Some(unwrap_object(json!({
+                    "name": layer.name,
+                    "type": "Tile",
+                }))),
+            )
+            .with_context(|| {
+                anyhow!(
+                    "error deserializing properties for tile layer `{}`",
+                    layer.name
+                )
+            })?,
+        });
+
+        layers.push((layer.layer_index, tile_layer));
+    }
+
+    for layer in image_layers.iter() {
+        // Tiled happily saves image layers which were never given an image.
+        let image = match layer.image.as_ref() {
+            Some(image) => image,
+            None => {
+                log::warn!("ignoring image layer `{}` with no image", layer.name);
+                continue;
+            }
+        };
+        let composed = parent.compose(&Composed::from(layer));
+        let image_layer = Layer::ImageLayer(ImageLayer {
+            name: Some(layer.name.clone()),
+            visible: composed.visible,
+            opacity: composed.opacity,
+            offset_x: composed.offset_x,
+            offset_y: composed.offset_y,
+            parallax_x: composed.parallax_x,
+            parallax_y: composed.parallax_y,
+            tint: composed.tint,
+            source: image.source.clone(),
+            image_width: image.width as u32,
+            image_height: image.height as u32,
+            properties: deserialize_properties(
+                &layer.properties,
+                Some(unwrap_object(json!({
+                    "type": "Image",
+                    "name": layer.name,
+                }))),
+            )
+            .with_context(|| {
+                anyhow!(
+                    "error deserializing layer properties for image layer `{}`",
+                    layer.name
+                )
+            })?,
+        });
+
+        layers.push((layer.layer_index, image_layer));
+    }
+
+    for layer in object_groups.iter() {
+        let composed = parent.compose(&Composed::from(layer));
+        let mut objects = Vec::new();
+        for object in layer.objects.iter() {
+            let shape = ObjectShape::from_tiled(&object.shape);
+            let object = Object {
+                id: object.id,
+                gid: object.gid,
+                name: object.name.clone(),
+                object_type: object.obj_type.clone(),
+                width: object.width,
+                height: object.height,
+                x: object.x,
+                y: object.y,
+                rot: object.rotation,
+                visible: object.visible,
+                shape,
+                properties: deserialize_properties(&object.properties, Some(unwrap_object(json!({
+                    "type": object.obj_type,
+                    "name": object.name,
+                })))).with_context(|| {
+                    anyhow!(
+                        "error deserializing object properties for object `{}` (id #{}) from layer `{}`",
+                        object.name,
+                        object.id,
+                        layer.name
+                    )
+                })?,
+            };
+
+            objects.push(object);
+        }
+
+        let object_layer = Layer::ObjectLayer(ObjectLayer {
+            name: layer.name.clone(),
+            opacity: composed.opacity,
+            visible: composed.visible,
+            offset_x: composed.offset_x,
+            offset_y: composed.offset_y,
+            parallax_x: composed.parallax_x,
+            parallax_y: composed.parallax_y,
+            tint: composed.tint,
+            objects,
+            properties: deserialize_properties(
+                &layer.properties,
+                Some(unwrap_object(json!({
+                    "type": "Object",
+                    "name": layer.name,
+                }))),
+            )
+            .with_context(|| {
+                anyhow!(
+                    "error deserializing layer properties for object layer `{}`",
+                    layer.name
+                )
+            })?,
+        });
+
+        layers.push((layer.layer_index.unwrap_or(0), object_layer));
+    }
+
+    for group in groups.iter() {
+        let composed = parent.compose(&Composed::from(group));
+        let group_layer = Layer::Group(GroupLayer {
+            name: group.name.clone(),
+            opacity: composed.opacity,
+            visible: composed.visible,
+            offset_x: composed.offset_x,
+            offset_y: composed.offset_y,
+            parallax_x: composed.parallax_x,
+            parallax_y: composed.parallax_y,
+            tint: composed.tint,
+            layers: layers_from_tiled(tiled, Some(group), &composed)?,
+            properties: deserialize_properties(
+                &group.properties,
+                Some(unwrap_object(json!({
+                    "type": "Group",
+                    "name": group.name,
+                }))),
+            )
+            .with_context(|| {
+                anyhow!(
+                    "error deserializing layer properties for group `{}`",
+                    group.name
+                )
+            })?,
+        });
+
+        layers.push((group.layer_index, group_layer));
+    }
+
+    layers.sort_by_key(|&(i, _)| i);
+    Ok(layers.into_iter().map(|(_, v)| v).collect())
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -362,6 +945,8 @@ pub struct TiledMap<L, T, O> {
     tile_width: u32,
     tile_height: u32,
 
+    orientation: Orientation,
+
     tile_sheets: Vec<TileSheet<T>>,
     layers: Vec<Layer<L, O>>,
 }
@@ -384,169 +969,7 @@ impl<L, T, O> TiledMap<L, T, O> {
             .map(|ts| TileSheet::from_tiled(ts))
             .collect::<Result<_>>()?;
 
-        let mut layers = Vec::new();
-
-        for layer in tiled.layers.iter() {
-            let mut chunks = HashMap::new();
-
-            match &layer.tiles {
-                LayerData::Finite(data) => {
-                    chunks.insert(
-                        (0, 0),
-                        Chunk {
-                            x: 0,
-                            y: 0,
-                            w: tiled.width,
-                            h: tiled.height,
-                            data: data.iter().flatten().map(|lt| lt.gid).collect(),
-                        },
-                    );
-                }
-                LayerData::Infinite(tiled_chunks) => {
-                    for (&(x, y), tiled_chunk) in tiled_chunks.iter() {
-                        chunks.insert(
-                            (x, y),
-                            Chunk {
-                                x: tiled_chunk.x,
-                                y: tiled_chunk.y,
-                                w: tiled_chunk.width,
-                                h: tiled_chunk.height,
-                                data: tiled_chunk
-                                    .tiles
-                                    .iter()
-                                    .flatten()
-                                    .map(|lt| lt.gid)
-                                    .collect(),
-                            },
-                        );
-                    }
-                }
-            }
-
-            let tile_layer = Layer::TileLayer(TileLayer {
-                name: Some(layer.name.clone()),
-                visible: layer.visible,
-                opacity: layer.opacity,
-                chunks,
-                properties: deserialize_properties(
-                    &layer.properties,
-                    Some(unwrap_object(json!({
-                        "name": layer.name,
-                        "type": "Tile",
-                    }))),
-                )
-                .with_context(|| {
-                    anyhow!(
-                        "error deserializing properties for tile layer `{}`",
-                        layer.name
-                    )
-                })?,
-            });
-
-            layers.push((layer.layer_index, tile_layer));
-        }
-
-        for layer in tiled.image_layers.iter() {
-            let image = layer.image.as_ref().unwrap();
-            let image_layer = Layer::ImageLayer(ImageLayer {
-                name: Some(layer.name.clone()),
-                visible: layer.visible,
-                opacity: layer.opacity,
-                offset_x: layer.offset_x,
-                offset_y: layer.offset_y,
-                source: image.source.clone(),
-                image_width: image.width as u32,
-                image_height: image.height as u32,
-                properties: deserialize_properties(
-                    &layer.properties,
-                    Some(unwrap_object(json!({
-                        "type": "Image",
-                        "name": layer.name,
-                    }))),
-                )
-                .with_context(|| {
-                    anyhow!(
-                        "error deserializing layer properties for image layer `{}`",
-                        layer.name
-                    )
-                })?,
-            });
-
-            layers.push((layer.layer_index, image_layer));
-        }
-
-        for layer in tiled.object_groups.iter() {
-            let mut objects = Vec::new();
-            for object in layer.objects.iter() {
-                use xml_parser::ObjectShape::*;
-                let shape = match &object.shape {
-                    Rect { width, height } => ObjectShape::Rect {
-                        width: *width,
-                        height: *height,
-                    },
-                    Polygon { points } => ObjectShape::Polygon {
-                        points: points.clone(),
-                    },
-                    Point(x, y) => ObjectShape::Point(*x, *y),
-                    _ => bail!(
-                        "unsupported shape type for object named `{}` (id {})",
-                        object.name,
-                        object.id
-                    ),
-                };
-
-                let object = Object {
-                    id: object.id,
-                    gid: object.gid,
-                    name: object.name.clone(),
-                    object_type: object.obj_type.clone(),
-                    width: object.width,
-                    height: object.height,
-                    x: object.x,
-                    y: object.y,
-                    rot: object.rotation,
-                    visible: object.visible,
-                    shape,
-                    properties: deserialize_properties(&object.properties, Some(unwrap_object(json!({
-                        "type": object.obj_type,
-                        "name": object.name,
-                    })))).with_context(|| {
-                        anyhow!(
-                            "error deserializing object properties for object `{}` (id #{}) from layer `{}`",
-                            object.name,
-                            object.id,
-                            layer.name
-                        )
-                    })?,
-                };
-
-                objects.push(object);
-            }
-
-            let object_layer = Layer::ObjectLayer(ObjectLayer {
-                name: layer.name.clone(),
-                opacity: layer.opacity,
-                visible: layer.visible,
-                objects,
-                properties: deserialize_properties(
-                    &layer.properties,
-                    Some(unwrap_object(json!({
-                        "type": "Object",
-                        "name": layer.name,
-                    }))),
-                )
-                .with_context(|| {
-                    anyhow!(
-                        "error deserializing layer properties for object layer `{}`",
-                        layer.name
-                    )
-                })?,
-            });
-
-            layers.push((layer.layer_index.unwrap_or(0), object_layer));
-        }
-
-        layers.sort_by_key(|&(i, _)| i);
+        let layers = layers_from_tiled(tiled, None, &Composed::default())?;
 
         Ok(TiledMap {
             source: path.to_owned(),
@@ -557,8 +980,10 @@ impl<L, T, O> TiledMap<L, T, O> {
             tile_width: tiled.tile_width,
             tile_height: tiled.tile_height,
 
+            orientation: Orientation::from_tiled(tiled),
+
             tile_sheets,
-            layers: layers.into_iter().map(|(_, v)| v).collect(),
+            layers,
         })
     }
 
@@ -574,10 +999,27 @@ impl<L, T, O> TiledMap<L, T, O> {
         (self.tile_width, self.tile_height)
     }
 
+    /// The map's top-level layers, in drawing order.
     pub fn layers(&self) -> &[Layer<L, O>] {
         &self.layers
     }
 
+    /// Every layer in the map which isn't a group, in drawing order.
+    pub fn leaf_layers(&self) -> Vec<&Layer<L, O>> {
+        fn walk<'a, L, O>(layers: &'a [Layer<L, O>], leaves: &mut Vec<&'a Layer<L, O>>) {
+            for layer in layers {
+                match layer {
+                    Layer::Group(group) => walk(&group.layers, leaves),
+                    leaf => leaves.push(leaf),
+                }
+            }
+        }
+
+        let mut leaves = Vec::new();
+        walk(&self.layers, &mut leaves);
+        leaves
+    }
+
     pub fn tile_sheets(&self) -> &[TileSheet<T>] {
         &self.tile_sheets
     }
@@ -604,8 +1046,7 @@ where
         resources: &R,
     ) -> Result<Loaded<Self>> {
         let path = key.to_path()?;
-        let fh = resources.fetch_mut::<Filesystem>().open(&path)?;
-        let tiled = xml_parser::parse_tileset(fh, 1)?;
+        let tiled = parse_tileset(&mut *resources.fetch_mut::<Filesystem>(), &path)?;
         Ok(TileSheet::from_tiled(&tiled)?.into())
     }
 }
@@ -622,7 +1063,7 @@ where
         resources: &R,
     ) -> Result<Loaded<Self>> {
         let path = key.to_path()?;
-        let tiled = xml_parser::parse_file(&mut *resources.fetch_mut::<Filesystem>(), &path)?;
+        let tiled = parse_map(&mut *resources.fetch_mut::<Filesystem>(), &path)?;
 
         let mut deps = vec![];
         for ts in tiled.tilesets.iter() {
@@ -631,10 +1072,8 @@ where
             }
         }
 
-        for ls in tiled.image_layers.iter() {
-            if let Some(img) = &ls.image {
-                deps.push(Key::from_path(Path::new(&img.source)).clone_static());
-            }
+        for source in image_layer_sources(&tiled) {
+            deps.push(Key::from_path(&source).clone_static());
         }
 
         let tiled_map = Self::from_tiled(&path, &tiled).with_context(|| {
@@ -649,6 +1088,77 @@ where
     }
 }
 
+// Lua has no way to pick property types, so it gets maps with untyped properties.
+inventory::submit! {
+    LuaAsset::new::<TiledMap<Value, Value, Value>>("TiledMap")
+}
+
+inventory::submit! {
+    LuaAsset::new::<TileSheet<Value>>("TileSheet")
+}
+
+fn tileset_images(tileset: &xml_parser::Tileset) -> impl Iterator<Item = PathBuf> + '_ {
+    tileset
+        .images
+        .iter()
+        .chain(tileset.tiles.iter().flat_map(|tile| tile.images.iter()))
+        .map(|image| image.source.clone())
+}
+
+// Image layers can be nested in groups, any number deep.
+fn image_layer_sources(tiled: &xml_parser::Map) -> Vec<PathBuf> {
+    fn walk(
+        image_layers: &[xml_parser::ImageLayer],
+        groups: &[xml_parser::Group],
+        sources: &mut Vec<PathBuf>,
+    ) {
+        sources.extend(
+            image_layers
+                .iter()
+                .filter_map(|layer| Some(layer.image.as_ref()?.source.clone())),
+        );
+        for group in groups {
+            walk(&group.image_layers, &group.groups, sources);
+        }
+    }
+
+    let mut sources = Vec::new();
+    walk(&tiled.image_layers, &tiled.groups, &mut sources);
+    sources
+}
+
+// Paths in a map come out of the parser already resolved, tilesets and all.
+fn map_dependencies(fs: &mut Filesystem, path: &Path) -> Result<Vec<PathBuf>> {
+    let tiled = parse_map(fs, path)?;
+    let mut deps = vec![];
+    for ts in tiled.tilesets.iter() {
+        deps.extend(ts.source.clone());
+        deps.extend(tileset_images(ts));
+    }
+
+    deps.extend(image_layer_sources(&tiled));
+
+    Ok(deps)
+}
+
+// Standalone tilesets aren't parsed with a path, so their images are still relative.
+fn tileset_dependencies(fs: &mut Filesystem, path: &Path) -> Result<Vec<PathBuf>> {
+    let tiled = parse_tileset(fs, path)?;
+    Ok(tileset_images(&tiled)
+        .map(|source| path.with_file_name(source))
+        .collect())
+}
+
+// Older versions of Tiled save JSON as plain `.json`, which can't be told apart from
+// any other JSON file by its extension, so only `.tmj` and `.tsj` are registered.
+inventory::submit! {
+    AssetDependencies::new(&["tmx", "tmj"], true, map_dependencies)
+}
+
+inventory::submit! {
+    AssetDependencies::new(&["tsx", "tsj"], false, tileset_dependencies)
+}
+
 pub struct TiledMapAccessor<L, T, O>(Entity, PhantomData<(L, T, O)>)
 where
     L: Properties,
@@ -707,3 +1217,241 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFS;
+
+    fn corners(flip: TileFlip) -> Vec<Point3<f32>> {
+        let tx = flip.to_transform();
+        [(0., 0.), (1., 0.), (0., 1.), (1., 1.)]
+            .iter()
+            .map(|&(x, y)| tx * Point3::new(x, y, 0.))
+            .collect()
+    }
+
+    #[test]
+    fn flip_flags_roundtrip() {
+        let raw = 0xa000_0007;
+        let (gid, flip) = TileFlip::split_gid(raw);
+        assert_eq!(gid, 7);
+        assert!(flip.horizontal && !flip.vertical && flip.diagonal);
+        assert_eq!(flip.join_gid(gid), raw);
+    }
+
+    #[test]
+    fn flips_map_the_unit_square_onto_itself() {
+        let p = |x, y| Point3::new(x, y, 0.);
+        assert_eq!(
+            corners(TileFlip::default()),
+            vec![p(0., 0.), p(1., 0.), p(0., 1.), p(1., 1.)]
+        );
+
+        let horizontal = TileFlip {
+            horizontal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(
+            corners(horizontal),
+            vec![p(1., 0.), p(0., 0.), p(1., 1.), p(0., 1.)]
+        );
+
+        // Rotated 90 degrees clockwise (with y pointing down), the top-left corner of
+        // the texture ends up in the top-right.
+        let rotated = TileFlip {
+            horizontal: true,
+            diagonal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(
+            corners(rotated),
+            vec![p(1., 0.), p(1., 1.), p(0., 0.), p(0., 1.)]
+        );
+    }
+
+    #[test]
+    fn instance_params_keep_flipped_tiles_in_place() -> Result<()> {
+        let tiled = xml_parser::parse_tileset(
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.5" name="tall" tilewidth="16" tileheight="24" tilecount="2" columns="2">
+ <image source="tall.png" width="32" height="24"/>
+</tileset>
+"##
+            .as_bytes(),
+            1,
+        )?;
+        let sheet = TileSheet::<Value>::from_tiled(&tiled)?;
+        let tx = Transform3::from_matrix_unchecked(Matrix4::new_translation(&Vector3::new(
+            32., 16., 0.,
+        )));
+
+        let corners = |flip| {
+            let param = sheet.get_instance_param_from_global_id(2, flip, &tx);
+            // Drawables scale the unit quad up to the size of the source rectangle on
+            // the texture, the same way `SpriteBatch::flush` does.
+            let quad = param
+                .scale2(param.src.extents())
+                .scale2(Vector2::new(32., 24.));
+            [(0., 0.), (1., 1.)]
+                .iter()
+                .map(|&(x, y)| quad.tx * Point3::new(x, y, 0.))
+                .map(|p| (p.x.round(), p.y.round()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(corners(TileFlip::default()), vec![(32., 16.), (48., 40.)]);
+        let horizontal = TileFlip {
+            horizontal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(corners(horizontal), vec![(48., 16.), (32., 40.)]);
+        let vertical = TileFlip {
+            vertical: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(corners(vertical), vec![(32., 40.), (48., 16.)]);
+        let diagonal = TileFlip {
+            diagonal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(corners(diagonal), vec![(32., 16.), (56., 32.)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn groups_compose_down_the_tree() -> Result<()> {
+        let memfs = MemoryFS::new();
+        memfs.insert(
+            "/maps/groups.tmx",
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.5" orientation="orthogonal" width="1" height="1" tilewidth="16" tileheight="16" infinite="0">
+ <group id="1" name="outer" offsetx="10" opacity="0.5" parallaxx="0.5" tintcolor="#ff0000">
+  <group id="2" name="inner" offsety="4" parallaxx="0.5" visible="0">
+   <layer id="3" name="ground" width="1" height="1" offsetx="1" opacity="0.5" tintcolor="#80ffffff">
+    <data encoding="csv">1</data>
+   </layer>
+  </group>
+  <imagelayer id="4" name="sky">
+   <image source="sky.png" width="8" height="8"/>
+  </imagelayer>
+ </group>
+ <layer id="5" name="top" width="1" height="1">
+  <data encoding="csv">0</data>
+ </layer>
+</map>
+"##,
+        )?;
+        let mut fs = Filesystem::from_vfs(memfs);
+        let path = Path::new("/maps/groups.tmx");
+        let tiled = parse_map(&mut fs, path)?;
+        assert_eq!(
+            image_layer_sources(&tiled),
+            vec![PathBuf::from("/maps/sky.png")]
+        );
+
+        let map = TiledMap::<Value, Value, Value>::from_tiled(path, &tiled)?;
+        assert!(matches!(
+            map.layers(),
+            [Layer::Group(_), Layer::TileLayer(_)]
+        ));
+
+        let leaves = map.leaf_layers();
+        assert_eq!(leaves.len(), 3);
+        let ground = match leaves[0] {
+            Layer::TileLayer(layer) => layer,
+            _ => panic!("expected the ground layer first"),
+        };
+        assert_eq!(ground.name.as_deref(), Some("ground"));
+        assert_eq!((ground.offset_x, ground.offset_y), (11., 4.));
+        assert_eq!((ground.parallax_x, ground.parallax_y), (0.25, 1.));
+        assert_eq!(ground.opacity, 0.25);
+        assert!(!ground.visible);
+        assert_eq!(ground.tint, Color::from_rgba(0xff, 0, 0, 0x80));
+
+        match leaves[1] {
+            Layer::ImageLayer(sky) => {
+                assert_eq!((sky.offset_x, sky.parallax_x), (10., 0.5));
+                assert!(sky.visible);
+            }
+            _ => panic!("expected the sky layer second"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn image_layers_without_an_image_are_skipped() -> Result<()> {
+        let memfs = MemoryFS::new();
+        memfs.insert(
+            "/maps/empty.tmj",
+            r##"{
+ "version": "1.4", "orientation": "orthogonal", "width": 1, "height": 1,
+ "tilewidth": 16, "tileheight": 16, "infinite": false, "tilesets": [],
+ "layers": [
+  { "type": "imagelayer", "id": 1, "name": "blank", "image": "" },
+  { "type": "imagelayer", "id": 2, "name": "sky", "image": "sky.png",
+    "imagewidth": 8, "imageheight": 8 }
+ ]
+}"##,
+        )?;
+        let mut fs = Filesystem::from_vfs(memfs);
+        let path = Path::new("/maps/empty.tmj");
+        let tiled = parse_map(&mut fs, path)?;
+        let map = TiledMap::<Value, Value, Value>::from_tiled(path, &tiled)?;
+        match map.layers() {
+            [Layer::ImageLayer(sky)] => {
+                assert_eq!(sky.name.as_deref(), Some("sky"));
+                assert_eq!(sky.source, Path::new("/maps/sky.png"));
+            }
+            _ => panic!("expected only the sky layer"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn ellipse_and_polyline_objects_are_kept() -> Result<()> {
+        let memfs = MemoryFS::new();
+        memfs.insert(
+            "/maps/shapes.tmx",
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.5" orientation="orthogonal" width="1" height="1" tilewidth="16" tileheight="16" infinite="0">
+ <objectgroup id="1" name="objects">
+  <object id="1" name="pond" x="0" y="0" width="32" height="16">
+   <ellipse/>
+  </object>
+  <object id="2" name="path" x="0" y="0">
+   <polyline points="0,0 16,0 16,16"/>
+  </object>
+ </objectgroup>
+</map>
+"##,
+        )?;
+        let mut fs = Filesystem::from_vfs(memfs);
+        let path = Path::new("/maps/shapes.tmx");
+        let tiled = parse_map(&mut fs, path)?;
+        let map = TiledMap::<Value, Value, Value>::from_tiled(path, &tiled)?;
+        let objects = match map.layers() {
+            [Layer::ObjectLayer(layer)] => &layer.objects,
+            _ => panic!("expected one object layer"),
+        };
+
+        assert!(matches!(
+            objects[0].shape,
+            ObjectShape::Ellipse {
+                width,
+                height,
+            } if width == 32. && height == 16.
+        ));
+        match &objects[1].shape {
+            ObjectShape::Polyline { points } => {
+                assert_eq!(points, &[(0., 0.), (16., 0.), (16., 16.)])
+            }
+            _ => panic!("expected a polyline"),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+//! Collision geometry for tile layers, built from the shapes drawn on tiles in Tiled's
+//! collision editor.
+//!
+//! Tiles whose only collider covers the whole tile are merged into as few rectangles as
+//! possible, so that a solid wall becomes one shape instead of one per tile. Every other
+//! collider becomes a shape of its own, or several if it's a concave polygon, which
+//! [`convex_decomposition`] splits into convex pieces.
+
+use {
+    anyhow::*,
+    hashbrown::HashMap,
+    ncollide2d::{
+        query::PointQuery,
+        shape::{Compound, ConvexPolygon, Cuboid, Shape, ShapeHandle},
+    },
+    std::collections::BTreeSet,
+};
+
+use crate::{
+    chunked_grid::ChunkedBitGrid,
+    math::*,
+    tiled::{ObjectShape, Orientation, TileCollider, TileLayer, TiledMap},
+};
+
+/// How far a collider can be from the edges of its tile and still count as covering it.
+const EPSILON: f32 = 1e-3;
+
+/// Builds collision geometry out of the colliders of the tiles in some of a map's
+/// layers.
+pub struct TileCollisionBuilder<'a, L, T, O> {
+    map: &'a TiledMap<L, T, O>,
+    layers: Vec<&'a TileLayer<L>>,
+}
+
+impl<'a, L, T, O> TileCollisionBuilder<'a, L, T, O> {
+    pub fn new(map: &'a TiledMap<L, T, O>) -> Self {
+        Self {
+            map,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Add a layer's tiles. Full tiles from different layers with the same offset are
+    /// merged together.
+    pub fn layer(&mut self, layer: &'a TileLayer<L>) -> &mut Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// The collision shapes of every added layer, in map coordinates. Only orthogonal
+    /// maps are supported.
+    pub fn shapes(&self) -> Result<Vec<(Isometry2<f32>, ShapeHandle<f32>)>> {
+        ensure!(
+            self.map.orientation() == Orientation::Orthogonal,
+            "tile collision shapes are only supported for orthogonal maps, not {:?}",
+            self.map.orientation()
+        );
+
+        let (tile_width, tile_height) = self.map.tile_dimensions();
+        let cell_size = Vector2::new(tile_width as f32, tile_height as f32);
+
+        let mut shapes = Vec::new();
+        // Full tiles, by the offset of the layer they're in.
+        let mut full = HashMap::<(u32, u32), BTreeSet<(i32, i32)>>::new();
+
+        for layer in &self.layers {
+            let offset = Vector2::new(layer.offset_x, layer.offset_y);
+
+            for (_, chunk) in layer.chunks() {
+                for ((i, j), gid, flip) in chunk.tiles() {
+                    if gid == 0 {
+                        continue;
+                    }
+
+                    let sheet = self
+                        .map
+                        .get_tile_sheet_for_gid(gid)
+                        .ok_or_else(|| anyhow!("tile {} isn't in any tile sheet", gid))?;
+                    let colliders = match self.map.get_tile_data_for_gid(gid) {
+                        Some(data) if !data.colliders.is_empty() => &data.colliders,
+                        _ => continue,
+                    };
+
+                    let size = na::convert::<_, Vector2<f32>>(
+                        sheet.get_region_from_global_id(gid).bounds.extents(),
+                    );
+                    let cell_mins =
+                        Point2::new(i as f32 * cell_size.x, j as f32 * cell_size.y) + offset;
+                    let cell = Box2::from_extents(cell_mins, cell_size);
+                    // Tiles are anchored to the bottom-left corner of their cell.
+                    let footprint = flip.footprint(size);
+                    let corner = cell_mins + Vector2::new(0., cell_size.y - footprint.y);
+                    let flip = flip.to_transform();
+
+                    for collider in colliders {
+                        let points = match collider_points(collider) {
+                            Some(points) => points,
+                            None => continue,
+                        };
+
+                        // Flip in the unit square, then scale back up to the flipped tile.
+                        let points = points
+                            .iter()
+                            .map(|p| {
+                                let unit = p.coords.component_div(&size);
+                                let flipped = flip * Point3::new(unit.x, unit.y, 0.);
+                                corner
+                                    + Vector2::new(flipped.x, flipped.y).component_mul(&footprint)
+                            })
+                            .collect::<Vec<_>>();
+
+                        let is_box = match collider.shape {
+                            ObjectShape::Rect { .. } => collider.rot % 90. == 0.,
+                            _ => false,
+                        };
+
+                        if is_box {
+                            let aabb = Box2::from_points(&points);
+                            if colliders.len() == 1 && approx_eq(&aabb, &cell) {
+                                let key = (offset.x.to_bits(), offset.y.to_bits());
+                                full.entry(key).or_default().insert((j, i));
+                            } else {
+                                shapes.push((
+                                    Isometry2::translation(aabb.center().x, aabb.center().y),
+                                    ShapeHandle::new(Cuboid::new(aabb.half_extents())),
+                                ));
+                            }
+                        } else {
+                            match convex_decomposition(&points) {
+                                Some(pieces) => {
+                                    shapes.extend(pieces.into_iter().map(|piece| {
+                                        (Isometry2::identity(), ShapeHandle::new(piece))
+                                    }))
+                                }
+                                None => log::warn!(
+                                    "ignoring degenerate or self-intersecting collider on tile {} \
+                                     of tileset `{}`",
+                                    gid,
+                                    sheet.name()
+                                ),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut keys = full.keys().copied().collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            let offset = Vector2::new(f32::from_bits(key.0), f32::from_bits(key.1));
+            for (mins, extents) in merge_cells(full.remove(&key).unwrap()) {
+                let extents = extents.component_mul(&cell_size);
+                let center = mins.component_mul(&cell_size) + extents / 2. + offset;
+                shapes.push((
+                    Isometry2::translation(center.x, center.y),
+                    ShapeHandle::new(Cuboid::new(extents / 2.)),
+                ));
+            }
+        }
+
+        Ok(shapes)
+    }
+
+    /// All of the collision shapes of the added layers as one compound shape, or `None`
+    /// if none of their tiles have colliders.
+    pub fn compound(&self) -> Result<Option<Compound<f32>>> {
+        let shapes = self.shapes()?;
+        if shapes.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Compound::new(shapes)))
+    }
+
+    /// Set every cell of a grid whose center is inside one of the collision shapes of
+    /// the added layers.
+    pub fn rasterize(&self, grid: &mut ChunkedBitGrid) -> Result<()> {
+        let scale = grid.scale();
+        for (isometry, shape) in self.shapes()? {
+            let query = match shape.as_point_query() {
+                Some(query) => query,
+                None => continue,
+            };
+
+            let aabb = shape.aabb(&isometry);
+            let (mins, maxs) = (*aabb.mins() / scale, *aabb.maxs() / scale);
+            for x in mins.x.floor() as i32..maxs.x.ceil() as i32 {
+                for y in mins.y.floor() as i32..maxs.y.ceil() as i32 {
+                    let center = Point2::new(x as f32 + 0.5, y as f32 + 0.5) * scale;
+                    if query.contains_point(&isometry, &center) {
+                        grid.set((x, y), true);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The outline of a collider, relative to the top-left corner of its tile.
+fn collider_points(collider: &TileCollider) -> Option<Vec<Point2<f32>>> {
+    let points = match &collider.shape {
+        ObjectShape::Rect { width, height } => {
+            vec![(0., 0.), (*width, 0.), (*width, *height), (0., *height)]
+        }
+        ObjectShape::Polygon { points } => points.clone(),
+        // Tile sheets only keep rectangle and polygon colliders.
+        ObjectShape::Ellipse { .. } | ObjectShape::Polyline { .. } | ObjectShape::Point(..) => {
+            return None
+        }
+    };
+
+    let isometry = Isometry2::new(
+        Vector2::new(collider.x, collider.y),
+        collider.rot.to_radians(),
+    );
+    Some(
+        points
+            .into_iter()
+            .map(|(x, y)| isometry * Point2::new(x, y))
+            .collect(),
+    )
+}
+
+/// Twice the signed area of the triangle `a`, `b`, `c`, positive if it turns the same
+/// way as a polygon with positive area.
+fn cross(a: &Point2<f32>, b: &Point2<f32>, c: &Point2<f32>) -> f32 {
+    (b - a).perp(&(c - b))
+}
+
+/// Whether the polygon with the given vertices, in order of positive area, is convex.
+fn is_convex(points: &[Point2<f32>], indices: &[usize]) -> bool {
+    let n = indices.len();
+    (0..n).all(|i| {
+        let (a, b, c) = (indices[i], indices[(i + 1) % n], indices[(i + 2) % n]);
+        cross(&points[a], &points[b], &points[c]) >= -EPSILON
+    })
+}
+
+/// Split a simple polygon into as few convex polygons as is easy to find, by clipping
+/// it into triangles and then merging triangles back together wherever the result is
+/// still convex. A polygon which is already convex comes back in one piece. Returns
+/// `None` if the polygon has no area or intersects itself.
+pub fn convex_decomposition(points: &[Point2<f32>]) -> Option<Vec<ConvexPolygon<f32>>> {
+    let mut indices = Vec::<usize>::new();
+    for (i, point) in points.iter().enumerate() {
+        if indices
+            .last()
+            .map_or(true, |&last| (points[last] - point).norm() > EPSILON)
+        {
+            indices.push(i);
+        }
+    }
+    while indices.len() > 1 && (points[indices[0]] - points[*indices.last()?]).norm() <= EPSILON {
+        indices.pop();
+    }
+
+    let n = indices.len();
+    let area = (0..n)
+        .map(|i| {
+            points[indices[i]]
+                .coords
+                .perp(&points[indices[(i + 1) % n]].coords)
+        })
+        .sum::<f32>();
+    if n < 3 || area.abs() <= EPSILON {
+        return None;
+    }
+    if area < 0. {
+        indices.reverse();
+    }
+
+    let mut pieces = Vec::new();
+    if is_convex(points, &indices) {
+        pieces.push(indices);
+    } else {
+        // Ear clipping. An ear is a convex corner whose triangle has no other vertex in
+        // it; every simple polygon with more than three vertices has at least two.
+        let mut remaining = indices;
+        while remaining.len() > 3 {
+            let n = remaining.len();
+            let ear = (0..n).find(|&i| {
+                let (a, b, c) = (
+                    remaining[(i + n - 1) % n],
+                    remaining[i],
+                    remaining[(i + 1) % n],
+                );
+                let (pa, pb, pc) = (&points[a], &points[b], &points[c]);
+                cross(pa, pb, pc) > EPSILON
+                    && remaining.iter().all(|&j| {
+                        j == a
+                            || j == b
+                            || j == c
+                            || cross(pa, pb, &points[j]) < 0.
+                            || cross(pb, pc, &points[j]) < 0.
+                            || cross(pc, pa, &points[j]) < 0.
+                    })
+            })?;
+
+            pieces.push(vec![
+                remaining[(ear + n - 1) % n],
+                remaining[ear],
+                remaining[(ear + 1) % n],
+            ]);
+            remaining.remove(ear);
+        }
+        pieces.push(remaining);
+
+        // Merge pieces across the diagonals they share, as long as they stay convex.
+        'merge: loop {
+            for i in 0..pieces.len() {
+                for j in i + 1..pieces.len() {
+                    if let Some(merged) = merge_pieces(&pieces[i], &pieces[j]) {
+                        if is_convex(points, &merged) {
+                            pieces[i] = merged;
+                            pieces.swap_remove(j);
+                            continue 'merge;
+                        }
+                    }
+                }
+            }
+
+            break;
+        }
+    }
+
+    pieces
+        .into_iter()
+        .map(|piece| {
+            let piece = piece.into_iter().map(|i| points[i]).collect::<Vec<_>>();
+            ConvexPolygon::try_from_points(&piece)
+        })
+        .collect()
+}
+
+/// Join two polygons which share an edge, going in opposite directions around each.
+fn merge_pieces(p: &[usize], q: &[usize]) -> Option<Vec<usize>> {
+    let (np, nq) = (p.len(), q.len());
+    let (i, j) = (0..np).find_map(|i| {
+        let (a, b) = (p[i], p[(i + 1) % np]);
+        let j = (0..nq).find(|&j| q[j] == b && q[(j + 1) % nq] == a)?;
+        Some((i, j))
+    })?;
+
+    // Around `p` from the end of the shared edge back to its start, then around `q`
+    // without either of the edge's vertices.
+    let mut merged = (1..=np).map(|k| p[(i + k) % np]).collect::<Vec<_>>();
+    merged.extend((2..nq).map(|k| q[(j + k) % nq]));
+    Some(merged)
+}
+
+fn approx_eq(a: &Box2<f32>, b: &Box2<f32>) -> bool {
+    (a.mins - b.mins).norm() < EPSILON && (a.maxs - b.maxs).norm() < EPSILON
+}
+
+/// Greedily cover a set of cells, keyed by row and then column, with rectangles. Each
+/// rectangle is as wide as it can be and then as tall as it can be at that width.
+/// Rectangles are returned as their top-left cell and their size in cells.
+fn merge_cells(mut cells: BTreeSet<(i32, i32)>) -> Vec<(Vector2<f32>, Vector2<f32>)> {
+    let mut rects = Vec::new();
+
+    loop {
+        let (j, i) = match cells.iter().next() {
+            Some(&cell) => cell,
+            None => break,
+        };
+
+        let mut width = 1;
+        while cells.contains(&(j, i + width)) {
+            width += 1;
+        }
+
+        let mut height = 1;
+        while (i..i + width).all(|x| cells.contains(&(j + height, x))) {
+            height += 1;
+        }
+
+        for y in j..j + height {
+            for x in i..i + width {
+                cells.remove(&(y, x));
+            }
+        }
+
+        rects.push((
+            Vector2::new(i as f32, j as f32),
+            Vector2::new(width as f32, height as f32),
+        ));
+    }
+
+    rects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::Filesystem,
+        tiled::{parse_map, Layer},
+        vfs::MemoryFS,
+    };
+    use {serde_json::Value, std::path::Path};
+
+    #[test]
+    fn full_tiles_merge_and_rasterize() -> Result<()> {
+        let memfs = MemoryFS::new();
+        memfs.insert(
+            "/maps/walls.tmx",
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.5" orientation="orthogonal" width="3" height="2" tilewidth="16" tileheight="16" infinite="0">
+ <tileset firstgid="1" name="walls" tilewidth="16" tileheight="16" tilecount="2" columns="2">
+  <image source="walls.png" width="32" height="16"/>
+  <tile id="0">
+   <objectgroup draworder="index" id="2">
+    <object id="1" x="0" y="0" width="16" height="16"/>
+   </objectgroup>
+  </tile>
+  <tile id="1">
+   <objectgroup draworder="index" id="2">
+    <object id="1" x="0" y="8" width="16" height="8"/>
+   </objectgroup>
+  </tile>
+ </tileset>
+ <layer id="1" name="walls" width="3" height="2">
+  <data encoding="csv">1,1,2,1,1,0</data>
+ </layer>
+</map>
+"##,
+        )?;
+        let mut fs = Filesystem::from_vfs(memfs);
+        let path = Path::new("/maps/walls.tmx");
+        let map = TiledMap::<Value, Value, Value>::from_tiled(path, &parse_map(&mut fs, path)?)?;
+        let layer = match map.layers() {
+            [Layer::TileLayer(layer)] => layer,
+            _ => panic!("expected a single tile layer"),
+        };
+
+        let mut builder = TileCollisionBuilder::new(&map);
+        builder.layer(layer);
+
+        let shapes = builder.shapes()?;
+        let boxes = shapes
+            .iter()
+            .map(|(isometry, shape)| {
+                let cuboid = shape.as_shape::<Cuboid<f32>>().unwrap();
+                (isometry.translation.vector, cuboid.half_extents)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            boxes,
+            vec![
+                (Vector2::new(40., 12.), Vector2::new(8., 4.)),
+                (Vector2::new(16., 16.), Vector2::new(16., 16.)),
+            ]
+        );
+
+        let mut grid = ChunkedBitGrid::new(8.);
+        builder.rasterize(&mut grid)?;
+        let mut cells = grid.iter().collect::<Vec<_>>();
+        cells.sort();
+        let mut expected = (0..4)
+            .flat_map(|x| (0..4).map(move |y| (x, y)))
+            .chain(vec![(4, 1), (5, 1)])
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(cells, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn diagonally_flipped_colliders_swap_their_extents() -> Result<()> {
+        let memfs = MemoryFS::new();
+        memfs.insert(
+            "/maps/tall.tmx",
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.5" orientation="orthogonal" width="1" height="1" tilewidth="16" tileheight="16" infinite="0">
+ <tileset firstgid="1" name="tall" tilewidth="16" tileheight="24" tilecount="1" columns="1">
+  <image source="tall.png" width="16" height="24"/>
+  <tile id="0">
+   <objectgroup draworder="index" id="2">
+    <object id="1" x="0" y="0" width="16" height="8"/>
+   </objectgroup>
+  </tile>
+ </tileset>
+ <layer id="1" name="tall" width="1" height="1">
+  <data encoding="csv">536870913</data>
+ </layer>
+</map>
+"##,
+        )?;
+        let mut fs = Filesystem::from_vfs(memfs);
+        let path = Path::new("/maps/tall.tmx");
+        let map = TiledMap::<Value, Value, Value>::from_tiled(path, &parse_map(&mut fs, path)?)?;
+        let layer = match map.layers() {
+            [Layer::TileLayer(layer)] => layer,
+            _ => panic!("expected a single tile layer"),
+        };
+
+        let mut builder = TileCollisionBuilder::new(&map);
+        builder.layer(layer);
+
+        // The 24x16 footprint sits on the bottom of the cell, and the strip along the
+        // top of the tile ends up down its left side.
+        let shapes = builder.shapes()?;
+        let boxes = shapes
+            .iter()
+            .map(|(isometry, shape)| {
+                let cuboid = shape.as_shape::<Cuboid<f32>>().unwrap();
+                (isometry.translation.vector, cuboid.half_extents)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(boxes, vec![(Vector2::new(4., 8.), Vector2::new(4., 8.))]);
+
+        Ok(())
+    }
+
+    fn area(polygon: &ConvexPolygon<f32>) -> f32 {
+        let points = polygon.points();
+        let n = points.len();
+        (0..n)
+            .map(|i| points[i].coords.perp(&points[(i + 1) % n].coords))
+            .sum::<f32>()
+            .abs()
+            / 2.
+    }
+
+    #[test]
+    fn concave_polygons_split_into_convex_pieces() {
+        let p = |x, y| Point2::new(x, y);
+        let square = [p(0., 0.), p(16., 0.), p(16., 16.), p(0., 16.)];
+        assert_eq!(convex_decomposition(&square).unwrap().len(), 1);
+
+        // An L, in both directions and with its first point repeated at the end.
+        let mut l = vec![
+            p(0., 0.),
+            p(16., 0.),
+            p(16., 8.),
+            p(8., 8.),
+            p(8., 16.),
+            p(0., 16.),
+            p(0., 0.),
+        ];
+        for _ in 0..2 {
+            let pieces = convex_decomposition(&l).unwrap();
+            assert_eq!(pieces.len(), 2);
+            let total = pieces.iter().map(area).sum::<f32>();
+            assert!((total - 192.).abs() < EPSILON);
+            // The notch of the L isn't covered.
+            let isometry = Isometry2::identity();
+            assert!(pieces
+                .iter()
+                .all(|piece| !piece.contains_point(&isometry, &p(12., 12.))));
+            l.reverse();
+        }
+
+        let line = [p(0., 0.), p(8., 0.), p(16., 0.)];
+        assert!(convex_decomposition(&line).is_none());
+        let bowtie = [p(0., 0.), p(16., 16.), p(16., 0.), p(0., 16.)];
+        assert!(convex_decomposition(&bowtie).is_none());
+    }
+}
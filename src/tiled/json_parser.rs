@@ -0,0 +1,852 @@
+//! Tiled's JSON map and tileset formats (`.tmj`/`.tsj`, or `.json` from older versions
+//! of Tiled), parsed into the same structures as `xml_parser` so that everything
+//! downstream of the parser doesn't care which format a map was saved in.
+
+use {
+    anyhow::*,
+    serde::Deserialize,
+    serde_json::Value,
+    std::{
+        collections::HashMap,
+        io::Read,
+        path::{Path, PathBuf},
+    },
+};
+
+use crate::{
+    filesystem::Filesystem,
+    tiled::xml_parser::{
+        self, convert_to_tile, decode_gzip, decode_zlib, decode_zstd, parse_argb, Chunk, Colour,
+        Frame, Group, Image, ImageLayer, Layer, LayerData, LayerTile, Map, Object, ObjectGroup,
+        ObjectShape, Properties, PropertyValue, Tile, TiledError, Tileset,
+    },
+};
+
+/// Whether a map or tileset is in the JSON format, going by its extension.
+pub fn is_json(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("tmj") | Some("tsj") | Some("json")
+    )
+}
+
+fn default_opacity() -> f32 {
+    1.0
+}
+
+fn default_parallax() -> f32 {
+    1.0
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_string_type() -> String {
+    "string".to_owned()
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonProperty {
+    name: String,
+    #[serde(rename = "type", default = "default_string_type")]
+    property_type: String,
+    value: Value,
+}
+
+// Property values are typed in JSON, but going through their string form means they're
+// checked against their declared types exactly the same way as TMX properties are.
+fn parse_properties(properties: Vec<JsonProperty>) -> Result<Properties> {
+    let mut p = HashMap::new();
+    for property in properties {
+        let value = match property.value {
+            Value::String(s) => s,
+            other => other.to_string(),
+        };
+        p.insert(
+            property.name,
+            PropertyValue::new(property.property_type, value)?,
+        );
+    }
+    Ok(p)
+}
+
+// Tiled writes `#aarrggbb` when a colour isn't fully opaque; `Colour` has no alpha.
+fn parse_colour(s: &str) -> Option<Colour> {
+    let s = s.trim_start_matches('#');
+    match s.len() {
+        8 => s[2..].parse().ok(),
+        _ => s.parse().ok(),
+    }
+}
+
+fn resolve(source: &str, path: Option<&Path>) -> PathBuf {
+    path.map(|p| p.with_file_name(source))
+        .unwrap_or_else(|| PathBuf::from(source))
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonMap {
+    version: Value,
+    orientation: String,
+    width: u32,
+    height: u32,
+    #[serde(rename = "tilewidth")]
+    tile_width: u32,
+    #[serde(rename = "tileheight")]
+    tile_height: u32,
+    #[serde(default)]
+    infinite: bool,
+    #[serde(rename = "staggeraxis")]
+    stagger_axis: Option<String>,
+    #[serde(rename = "staggerindex")]
+    stagger_index: Option<String>,
+    #[serde(rename = "hexsidelength")]
+    hex_side_length: Option<u32>,
+    #[serde(rename = "backgroundcolor")]
+    background_colour: Option<String>,
+    #[serde(default)]
+    tilesets: Vec<Value>,
+    #[serde(default)]
+    layers: Vec<JsonLayer>,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonLayer {
+    TileLayer(JsonTileLayer),
+    ImageLayer(JsonImageLayer),
+    ObjectGroup(JsonObjectGroup),
+    Group(JsonGroup),
+    /// Layer types which the TMX parser doesn't load either.
+    #[serde(other)]
+    Unsupported,
+}
+
+/// The layers directly inside a map or group, each indexed by its position there.
+#[derive(Debug, Default)]
+struct Layers {
+    layers: Vec<Layer>,
+    image_layers: Vec<ImageLayer>,
+    object_groups: Vec<ObjectGroup>,
+    groups: Vec<Group>,
+}
+
+fn parse_layers(json: Vec<JsonLayer>, infinite: bool, map_path: Option<&Path>) -> Result<Layers> {
+    let mut layers = Layers::default();
+    let mut layer_index = 0;
+    for layer in json {
+        match layer {
+            JsonLayer::TileLayer(layer) => {
+                layers.layers.push(layer.into_layer(layer_index, infinite)?)
+            }
+            JsonLayer::ImageLayer(layer) => layers
+                .image_layers
+                .push(layer.into_image_layer(layer_index, map_path)?),
+            JsonLayer::ObjectGroup(group) => layers
+                .object_groups
+                .push(group.into_object_group(Some(layer_index))?),
+            JsonLayer::Group(group) => {
+                layers
+                    .groups
+                    .push(group.into_group(layer_index, infinite, map_path)?)
+            }
+            JsonLayer::Unsupported => continue,
+        }
+        layer_index += 1;
+    }
+    Ok(layers)
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonTileLayer {
+    name: String,
+    #[serde(default = "default_opacity")]
+    opacity: f32,
+    #[serde(default = "default_true")]
+    visible: bool,
+    #[serde(rename = "offsetx", default)]
+    offset_x: f32,
+    #[serde(rename = "offsety", default)]
+    offset_y: f32,
+    #[serde(rename = "parallaxx", default = "default_parallax")]
+    parallax_x: f32,
+    #[serde(rename = "parallaxy", default = "default_parallax")]
+    parallax_y: f32,
+    #[serde(rename = "tintcolor")]
+    tint: Option<String>,
+    #[serde(default)]
+    width: u32,
+    data: Option<JsonData>,
+    chunks: Option<Vec<JsonChunk>>,
+    encoding: Option<String>,
+    compression: Option<String>,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+/// Tile data is either an array of global tile IDs, or the same little-endian bytes as
+/// TMX's base64 encoding.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum JsonData {
+    Gids(Vec<u32>),
+    Base64(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonChunk {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    data: JsonData,
+}
+
+fn parse_data(
+    data: JsonData,
+    encoding: Option<&str>,
+    compression: Option<&str>,
+    width: u32,
+) -> Result<Vec<Vec<LayerTile>>> {
+    if width == 0 {
+        return Ok(Vec::new());
+    }
+
+    match (data, encoding.unwrap_or("csv")) {
+        (JsonData::Gids(gids), "csv") => Ok(gids
+            .chunks(width as usize)
+            .map(|row| row.iter().map(|&gid| LayerTile::new(gid)).collect())
+            .collect()),
+        (JsonData::Base64(s), "base64") => {
+            let bytes = base64::decode(s.trim()).map_err(TiledError::Base64DecodingError)?;
+            let bytes = match compression.unwrap_or("") {
+                "" => bytes,
+                "zlib" => decode_zlib(bytes)?,
+                "gzip" => decode_gzip(bytes)?,
+                "zstd" => decode_zstd(bytes)?,
+                c => bail!(TiledError::Other(format!(
+                    "Unknown compression format {}",
+                    c
+                ))),
+            };
+            Ok(convert_to_tile(&bytes, width))
+        }
+        (_, e) => bail!(TiledError::MalformedAttributes(format!(
+            "layer data doesn't match its encoding {}",
+            e
+        ))),
+    }
+}
+
+impl JsonTileLayer {
+    fn into_layer(self, layer_index: u32, infinite: bool) -> Result<Layer> {
+        let encoding = self.encoding.as_deref();
+        let compression = self.compression.as_deref();
+        let tiles = if infinite {
+            let mut chunks = HashMap::new();
+            for chunk in self.chunks.unwrap_or_default() {
+                let tiles = parse_data(chunk.data, encoding, compression, chunk.width)?;
+                chunks.insert(
+                    (chunk.x, chunk.y),
+                    Chunk {
+                        x: chunk.x,
+                        y: chunk.y,
+                        width: chunk.width,
+                        height: chunk.height,
+                        tiles,
+                    },
+                );
+            }
+            LayerData::Infinite(chunks)
+        } else {
+            match self.data {
+                Some(data) => {
+                    LayerData::Finite(parse_data(data, encoding, compression, self.width)?)
+                }
+                None => LayerData::Finite(Vec::new()),
+            }
+        };
+
+        Ok(Layer {
+            name: self.name,
+            opacity: self.opacity,
+            visible: self.visible,
+            offset_x: self.offset_x,
+            offset_y: self.offset_y,
+            parallax_x: self.parallax_x,
+            parallax_y: self.parallax_y,
+            tint: self.tint.as_deref().and_then(parse_argb),
+            tiles,
+            properties: parse_properties(self.properties)?,
+            layer_index,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonImageLayer {
+    name: String,
+    #[serde(default = "default_opacity")]
+    opacity: f32,
+    #[serde(default = "default_true")]
+    visible: bool,
+    #[serde(rename = "offsetx", default)]
+    offset_x: f32,
+    #[serde(rename = "offsety", default)]
+    offset_y: f32,
+    #[serde(rename = "parallaxx", default = "default_parallax")]
+    parallax_x: f32,
+    #[serde(rename = "parallaxy", default = "default_parallax")]
+    parallax_y: f32,
+    #[serde(rename = "tintcolor")]
+    tint: Option<String>,
+    #[serde(default)]
+    image: String,
+    #[serde(rename = "imagewidth", default)]
+    image_width: i32,
+    #[serde(rename = "imageheight", default)]
+    image_height: i32,
+    #[serde(rename = "transparentcolor")]
+    transparent_colour: Option<String>,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+impl JsonImageLayer {
+    fn into_image_layer(self, layer_index: u32, map_path: Option<&Path>) -> Result<ImageLayer> {
+        // An image layer with no image set has an empty `image` rather than none.
+        let image = match self.image.as_str() {
+            "" => None,
+            source => Some(Image {
+                source: resolve(source, map_path),
+                width: self.image_width,
+                height: self.image_height,
+                transparent_colour: self.transparent_colour.as_deref().and_then(parse_colour),
+            }),
+        };
+
+        Ok(ImageLayer {
+            name: self.name,
+            opacity: self.opacity,
+            visible: self.visible,
+            offset_x: self.offset_x,
+            offset_y: self.offset_y,
+            parallax_x: self.parallax_x,
+            parallax_y: self.parallax_y,
+            tint: self.tint.as_deref().and_then(parse_argb),
+            image,
+            properties: parse_properties(self.properties)?,
+            layer_index,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonObjectGroup {
+    #[serde(default)]
+    name: String,
+    #[serde(default = "default_opacity")]
+    opacity: f32,
+    #[serde(default = "default_true")]
+    visible: bool,
+    #[serde(rename = "offsetx", default)]
+    offset_x: f32,
+    #[serde(rename = "offsety", default)]
+    offset_y: f32,
+    #[serde(rename = "parallaxx", default = "default_parallax")]
+    parallax_x: f32,
+    #[serde(rename = "parallaxy", default = "default_parallax")]
+    parallax_y: f32,
+    #[serde(rename = "tintcolor")]
+    tint: Option<String>,
+    #[serde(rename = "color")]
+    colour: Option<String>,
+    #[serde(default)]
+    objects: Vec<JsonObject>,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+impl JsonObjectGroup {
+    fn into_object_group(self, layer_index: Option<u32>) -> Result<ObjectGroup> {
+        Ok(ObjectGroup {
+            name: self.name,
+            opacity: self.opacity,
+            visible: self.visible,
+            offset_x: self.offset_x,
+            offset_y: self.offset_y,
+            parallax_x: self.parallax_x,
+            parallax_y: self.parallax_y,
+            tint: self.tint.as_deref().and_then(parse_argb),
+            objects: self
+                .objects
+                .into_iter()
+                .map(JsonObject::into_object)
+                .collect::<Result<_>>()?,
+            colour: self.colour.as_deref().and_then(parse_colour),
+            layer_index,
+            properties: parse_properties(self.properties)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonGroup {
+    #[serde(default)]
+    name: String,
+    #[serde(default = "default_opacity")]
+    opacity: f32,
+    #[serde(default = "default_true")]
+    visible: bool,
+    #[serde(rename = "offsetx", default)]
+    offset_x: f32,
+    #[serde(rename = "offsety", default)]
+    offset_y: f32,
+    #[serde(rename = "parallaxx", default = "default_parallax")]
+    parallax_x: f32,
+    #[serde(rename = "parallaxy", default = "default_parallax")]
+    parallax_y: f32,
+    #[serde(rename = "tintcolor")]
+    tint: Option<String>,
+    #[serde(default)]
+    layers: Vec<JsonLayer>,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+impl JsonGroup {
+    fn into_group(
+        self,
+        layer_index: u32,
+        infinite: bool,
+        map_path: Option<&Path>,
+    ) -> Result<Group> {
+        let children = parse_layers(self.layers, infinite, map_path)?;
+        Ok(Group {
+            name: self.name,
+            opacity: self.opacity,
+            visible: self.visible,
+            offset_x: self.offset_x,
+            offset_y: self.offset_y,
+            parallax_x: self.parallax_x,
+            parallax_y: self.parallax_y,
+            tint: self.tint.as_deref().and_then(parse_argb),
+            layers: children.layers,
+            image_layers: children.image_layers,
+            object_groups: children.object_groups,
+            groups: children.groups,
+            properties: parse_properties(self.properties)?,
+            layer_index,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonPoint {
+    x: f32,
+    y: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonObject {
+    #[serde(default)]
+    id: u32,
+    #[serde(default)]
+    gid: u32,
+    #[serde(default)]
+    name: String,
+    /// Tiled 1.9 renamed an object's type to its class.
+    #[serde(rename = "type", alias = "class", default)]
+    obj_type: String,
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    width: f32,
+    #[serde(default)]
+    height: f32,
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default = "default_true")]
+    visible: bool,
+    #[serde(default)]
+    ellipse: bool,
+    #[serde(default)]
+    point: bool,
+    polyline: Option<Vec<JsonPoint>>,
+    polygon: Option<Vec<JsonPoint>>,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+impl JsonObject {
+    fn into_object(self) -> Result<Object> {
+        let points = |points: Vec<JsonPoint>| points.into_iter().map(|p| (p.x, p.y)).collect();
+        let shape = if self.ellipse {
+            ObjectShape::Ellipse {
+                width: self.width,
+                height: self.height,
+            }
+        } else if self.point {
+            ObjectShape::Point(self.x, self.y)
+        } else if let Some(polyline) = self.polyline {
+            ObjectShape::Polyline {
+                points: points(polyline),
+            }
+        } else if let Some(polygon) = self.polygon {
+            ObjectShape::Polygon {
+                points: points(polygon),
+            }
+        } else {
+            ObjectShape::Rect {
+                width: self.width,
+                height: self.height,
+            }
+        };
+
+        Ok(Object {
+            id: self.id,
+            gid: self.gid,
+            name: self.name,
+            obj_type: self.obj_type,
+            width: self.width,
+            height: self.height,
+            x: self.x,
+            y: self.y,
+            rotation: self.rotation,
+            visible: self.visible,
+            shape,
+            properties: parse_properties(self.properties)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonTileset {
+    #[serde(rename = "firstgid")]
+    first_gid: Option<u32>,
+    name: String,
+    #[serde(rename = "tilewidth")]
+    tile_width: u32,
+    #[serde(rename = "tileheight")]
+    tile_height: u32,
+    #[serde(default)]
+    spacing: u32,
+    #[serde(default)]
+    margin: u32,
+    tilecount: Option<u32>,
+    image: Option<String>,
+    #[serde(rename = "imagewidth", default)]
+    image_width: i32,
+    #[serde(rename = "imageheight", default)]
+    image_height: i32,
+    #[serde(rename = "transparentcolor")]
+    transparent_colour: Option<String>,
+    #[serde(default)]
+    tiles: Vec<JsonTile>,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonTile {
+    id: u32,
+    /// Tiled 1.9 renamed a tile's type to its class.
+    #[serde(rename = "type", alias = "class")]
+    tile_type: Option<String>,
+    probability: Option<f32>,
+    image: Option<String>,
+    #[serde(rename = "imagewidth", default)]
+    image_width: i32,
+    #[serde(rename = "imageheight", default)]
+    image_height: i32,
+    #[serde(rename = "objectgroup")]
+    object_group: Option<JsonObjectGroup>,
+    animation: Option<Vec<JsonFrame>>,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonFrame {
+    #[serde(rename = "tileid")]
+    tile_id: u32,
+    duration: u32,
+}
+
+// Tilesets and their tiles have at most one image each in JSON, flattened into their
+// own fields.
+fn images(
+    source: Option<String>,
+    width: i32,
+    height: i32,
+    transparent_colour: Option<Colour>,
+    path: Option<&Path>,
+) -> Vec<Image> {
+    source
+        .map(|source| Image {
+            source: resolve(&source, path),
+            width,
+            height,
+            transparent_colour,
+        })
+        .into_iter()
+        .collect()
+}
+
+impl JsonTileset {
+    /// `path` is the file the tileset's image paths are relative to, and `source` the
+    /// tileset's own file if it isn't embedded in a map.
+    fn into_tileset(
+        self,
+        first_gid: u32,
+        path: Option<&Path>,
+        source: Option<&Path>,
+    ) -> Result<Tileset> {
+        let mut tiles = Vec::new();
+        for tile in self.tiles {
+            tiles.push(Tile {
+                id: tile.id,
+                images: images(tile.image, tile.image_width, tile.image_height, None, path),
+                properties: parse_properties(tile.properties)?,
+                objectgroup: tile
+                    .object_group
+                    .map(|group| group.into_object_group(None))
+                    .transpose()?,
+                animation: tile.animation.map(|frames| {
+                    frames
+                        .into_iter()
+                        .map(|frame| Frame {
+                            tile_id: frame.tile_id,
+                            duration: frame.duration,
+                        })
+                        .collect()
+                }),
+                tile_type: tile.tile_type,
+                probability: tile.probability.unwrap_or(1.0),
+            });
+        }
+
+        Ok(Tileset {
+            first_gid,
+            name: self.name,
+            source: source.map(Path::to_owned),
+            tile_width: self.tile_width,
+            tile_height: self.tile_height,
+            spacing: self.spacing,
+            margin: self.margin,
+            tilecount: self.tilecount,
+            images: images(
+                self.image,
+                self.image_width,
+                self.image_height,
+                self.transparent_colour.as_deref().and_then(parse_colour),
+                path,
+            ),
+            tiles,
+            properties: parse_properties(self.properties)?,
+        })
+    }
+}
+
+// A map's tilesets are either embedded in it, or refer to a tileset file by `source`,
+// which may well be a TSX file.
+fn parse_map_tileset(
+    fs: &mut Filesystem,
+    value: Value,
+    map_path: Option<&Path>,
+) -> Result<Tileset> {
+    if let Some(source) = value.get("source").and_then(Value::as_str) {
+        let first_gid = value
+            .get("firstgid")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| {
+                TiledError::MalformedAttributes(
+                    "tileset references must have a firstgid".to_string(),
+                )
+            })?;
+        return xml_parser::parse_tileset_reference(fs, first_gid as u32, source, map_path);
+    }
+
+    let tileset = serde_json::from_value::<JsonTileset>(value)?;
+    let first_gid = tileset.first_gid.ok_or_else(|| {
+        TiledError::MalformedAttributes("tileset must have a firstgid".to_string())
+    })?;
+    tileset.into_tileset(first_gid, map_path, None)
+}
+
+fn parse_impl<R: Read>(fs: &mut Filesystem, reader: R, map_path: Option<&Path>) -> Result<Map> {
+    let map = serde_json::from_reader::<_, JsonMap>(reader)?;
+
+    let version = match map.version {
+        Value::String(s) => s,
+        other => other.to_string(),
+    };
+    let orientation = map.orientation.parse().map_err(|_| {
+        TiledError::MalformedAttributes(format!("unknown orientation {}", map.orientation))
+    })?;
+
+    let mut tilesets = Vec::new();
+    for tileset in map.tilesets {
+        tilesets.push(parse_map_tileset(fs, tileset, map_path)?);
+    }
+
+    let layers = parse_layers(map.layers, map.infinite, map_path)?;
+
+    Ok(Map {
+        version,
+        orientation,
+        width: map.width,
+        height: map.height,
+        tile_width: map.tile_width,
+        tile_height: map.tile_height,
+        tilesets,
+        layers: layers.layers,
+        image_layers: layers.image_layers,
+        object_groups: layers.object_groups,
+        groups: layers.groups,
+        properties: parse_properties(map.properties)?,
+        background_colour: map.background_colour.as_deref().and_then(parse_colour),
+        infinite: map.infinite,
+        stagger_axis: map.stagger_axis.as_deref().map(str::parse).transpose()?,
+        stagger_index: map.stagger_index.as_deref().map(str::parse).transpose()?,
+        hex_side_length: map.hex_side_length,
+    })
+}
+
+/// Parse a file hopefully containing a Tiled map in JSON. External tilesets, in either
+/// format, are loaded relative to the map file's path.
+pub fn parse_file(filesystem: &mut Filesystem, path: &Path) -> Result<Map> {
+    let file = filesystem
+        .open(path)
+        .with_context(|| TiledError::Other(format!("Map file not found: {:?}", path)))?;
+    parse_impl(filesystem, file, Some(path))
+}
+
+/// Parse a buffer hopefully containing a Tiled tileset in JSON. As with
+/// `xml_parser::parse_tileset`, image paths are left relative to the tileset.
+pub fn parse_tileset<R: Read>(reader: R, first_gid: u32) -> Result<Tileset> {
+    let tileset = serde_json::from_reader::<_, JsonTileset>(reader)?;
+    tileset.into_tileset(first_gid, None, None)
+}
+
+/// Parse an external tileset which a map refers to, resolving its images relative to
+/// the tileset file.
+pub(super) fn parse_tileset_with_path<R: Read>(
+    reader: R,
+    first_gid: u32,
+    path: &Path,
+) -> Result<Tileset> {
+    let tileset = serde_json::from_reader::<_, JsonTileset>(reader)?;
+    tileset.into_tileset(first_gid, Some(path), Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFS;
+
+    const TSX: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.4" name="tiles" tilewidth="16" tileheight="16" tilecount="4" columns="2">
+ <image source="tiles.png" width="32" height="32"/>
+ <tile id="1" type="spike">
+  <properties>
+   <property name="damage" type="int" value="2"/>
+  </properties>
+  <animation>
+   <frame tileid="1" duration="100"/>
+   <frame tileid="2" duration="100"/>
+  </animation>
+ </tile>
+</tileset>
+"##;
+
+    const TSJ: &str = r##"{
+ "name": "tiles", "tilewidth": 16, "tileheight": 16, "tilecount": 4, "columns": 2,
+ "image": "tiles.png", "imagewidth": 32, "imageheight": 32,
+ "tiles": [{
+  "id": 1, "type": "spike",
+  "properties": [{ "name": "damage", "type": "int", "value": 2 }],
+  "animation": [{ "tileid": 1, "duration": 100 }, { "tileid": 2, "duration": 100 }]
+ }]
+}"##;
+
+    const TMX: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.4" orientation="orthogonal" width="2" height="2" tilewidth="16" tileheight="16" infinite="0">
+ <properties>
+  <property name="gravity" type="float" value="9.5"/>
+  <property name="tint" type="color" value="#ff102030"/>
+ </properties>
+ <tileset firstgid="1" source="tiles.tsx"/>
+ <layer id="1" name="ground" width="2" height="2">
+  <data encoding="csv">
+1,2,
+0,2147483649
+</data>
+ </layer>
+ <objectgroup id="2" name="walls">
+  <object id="1" name="wall" type="solid" x="0" y="0">
+   <properties>
+    <property name="hp" type="int" value="3"/>
+   </properties>
+   <polygon points="0,0 16,0 16,16"/>
+  </object>
+ </objectgroup>
+ <group id="3" name="decor" offsetx="4">
+  <imagelayer id="4" name="sky">
+   <image source="sky.png" width="8" height="8"/>
+  </imagelayer>
+ </group>
+</map>
+"##;
+
+    const TMJ: &str = r##"{
+ "version": "1.4", "orientation": "orthogonal", "width": 2, "height": 2,
+ "tilewidth": 16, "tileheight": 16, "infinite": false,
+ "properties": [
+  { "name": "gravity", "type": "float", "value": 9.5 },
+  { "name": "tint", "type": "color", "value": "#ff102030" }
+ ],
+ "tilesets": [{ "firstgid": 1, "source": "tiles.tsx" }],
+ "layers": [
+  { "type": "tilelayer", "id": 1, "name": "ground", "width": 2, "height": 2,
+    "data": [1, 2, 0, 2147483649] },
+  { "type": "objectgroup", "id": 2, "name": "walls", "objects": [{
+    "id": 1, "name": "wall", "type": "solid", "x": 0, "y": 0,
+    "properties": [{ "name": "hp", "type": "int", "value": 3 }],
+    "polygon": [{ "x": 0, "y": 0 }, { "x": 16, "y": 0 }, { "x": 16, "y": 16 }]
+  }] },
+  { "type": "group", "id": 3, "name": "decor", "offsetx": 4, "layers": [
+    { "type": "imagelayer", "id": 4, "name": "sky", "image": "sky.png",
+      "imagewidth": 8, "imageheight": 8 }
+  ] }
+ ]
+}"##;
+
+    #[test]
+    fn json_and_xml_parse_the_same() -> Result<()> {
+        let memfs = MemoryFS::new();
+        memfs.insert("/maps/tiles.tsx", TSX)?;
+        memfs.insert("/maps/map.tmx", TMX)?;
+        memfs.insert("/maps/map.tmj", TMJ)?;
+        let mut fs = Filesystem::from_vfs(memfs);
+
+        let xml = xml_parser::parse_file(&mut fs, Path::new("/maps/map.tmx"))?;
+        let json = parse_file(&mut fs, Path::new("/maps/map.tmj"))?;
+        assert_eq!(json, xml);
+        assert_eq!(json.groups.len(), 1);
+        assert_eq!(json.groups[0].image_layers.len(), 1);
+        assert_eq!(
+            json.tilesets[0].images[0].source,
+            Path::new("/maps/tiles.png")
+        );
+
+        let xml = xml_parser::parse_tileset(TSX.as_bytes(), 1)?;
+        let json = parse_tileset(TSJ.as_bytes(), 1)?;
+        assert_eq!(json, xml);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,507 @@
+//! Map orientations, and converting between tile coordinates and map coordinates in
+//! each of them.
+//!
+//! Map coordinates are pixels, laid out the way Tiled draws the map. A tile's position
+//! is the top-left corner of its bounding box, which is the size of the map's tiles;
+//! tile images are drawn with their bottom-left corner at the bottom-left corner of
+//! that box.
+
+use {
+    anyhow::*,
+    serde::{Deserialize, Serialize},
+    smallvec::SmallVec,
+    std::str::FromStr,
+};
+
+use crate::{
+    math::*,
+    tiled::{xml_parser, TiledMap},
+};
+
+/// Which axis of a staggered or hexagonal map has every other row or column shifted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StaggerAxis {
+    X,
+    Y,
+}
+
+impl FromStr for StaggerAxis {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "x" => Ok(StaggerAxis::X),
+            "y" => Ok(StaggerAxis::Y),
+            _ => bail!("unknown stagger axis `{}`", s),
+        }
+    }
+}
+
+/// Whether the odd or even rows or columns of a staggered or hexagonal map are the
+/// shifted ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StaggerIndex {
+    Odd,
+    Even,
+}
+
+impl FromStr for StaggerIndex {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "odd" => Ok(StaggerIndex::Odd),
+            "even" => Ok(StaggerIndex::Even),
+            _ => bail!("unknown stagger index `{}`", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Orientation {
+    Orthogonal,
+    /// Diamond-shaped tiles, with the tile x axis running down and to the right and
+    /// the tile y axis down and to the left.
+    Isometric,
+    /// Diamond-shaped tiles in a grid where every other row or column is shifted by
+    /// half a tile, so that the map is roughly rectangular.
+    Staggered {
+        axis: StaggerAxis,
+        index: StaggerIndex,
+    },
+    /// Like `Staggered`, with hexagons whose sides along the stagger axis are
+    /// `side_length` pixels long.
+    Hexagonal {
+        axis: StaggerAxis,
+        index: StaggerIndex,
+        side_length: u32,
+    },
+}
+
+impl Orientation {
+    /// Maps without stagger attributes get Tiled's defaults.
+    pub(super) fn from_tiled(tiled: &xml_parser::Map) -> Self {
+        let axis = tiled.stagger_axis.unwrap_or(StaggerAxis::Y);
+        let index = tiled.stagger_index.unwrap_or(StaggerIndex::Odd);
+        match tiled.orientation {
+            xml_parser::Orientation::Orthogonal => Orientation::Orthogonal,
+            xml_parser::Orientation::Isometric => Orientation::Isometric,
+            xml_parser::Orientation::Staggered => Orientation::Staggered { axis, index },
+            xml_parser::Orientation::Hexagonal => Orientation::Hexagonal {
+                axis,
+                index,
+                side_length: tiled.hex_side_length.unwrap_or(0),
+            },
+        }
+    }
+}
+
+/// The layout of a staggered or hexagonal map. Staggered maps are laid out like
+/// hexagonal maps with a side length of zero.
+#[derive(Debug, Clone, Copy)]
+struct Stagger {
+    x_axis: bool,
+    even: bool,
+    hexagonal: bool,
+    tile: Vector2<f32>,
+    side_length: Vector2<f32>,
+    column_width: f32,
+    row_height: f32,
+}
+
+impl Stagger {
+    fn new(tile: Vector2<f32>, axis: StaggerAxis, index: StaggerIndex, side_length: u32) -> Self {
+        let x_axis = axis == StaggerAxis::X;
+        let side_length = match axis {
+            StaggerAxis::X => Vector2::new(side_length as f32, 0.),
+            StaggerAxis::Y => Vector2::new(0., side_length as f32),
+        };
+        let side_offset = (tile - side_length) / 2.;
+
+        Self {
+            x_axis,
+            even: index == StaggerIndex::Even,
+            hexagonal: side_length != Vector2::zeros(),
+            tile,
+            side_length,
+            column_width: side_offset.x + side_length.x,
+            row_height: side_offset.y + side_length.y,
+        }
+    }
+
+    /// Whether the row or column `n` along the stagger axis is shifted.
+    fn is_shifted(&self, n: i32) -> bool {
+        (n & 1 != 0) != self.even
+    }
+
+    fn tile_to_world(&self, (i, j): (i32, i32)) -> Point2<f32> {
+        if self.x_axis {
+            let mut y = j as f32 * (self.tile.y + self.side_length.y);
+            if self.is_shifted(i) {
+                y += self.row_height;
+            }
+            Point2::new(i as f32 * self.column_width, y)
+        } else {
+            let mut x = i as f32 * (self.tile.x + self.side_length.x);
+            if self.is_shifted(j) {
+                x += self.column_width;
+            }
+            Point2::new(x, j as f32 * self.row_height)
+        }
+    }
+
+    /// Find the tile under a point the way Tiled does: split the map into blocks two
+    /// tiles across along the stagger axis, and pick the nearest of the four tiles
+    /// which can overlap the point's block.
+    fn world_to_tile(&self, mut point: Point2<f32>) -> (i32, i32) {
+        let side_offset = (self.tile - self.side_length) / 2.;
+        if self.x_axis {
+            point.x -= if self.even {
+                self.tile.x
+            } else {
+                side_offset.x
+            };
+        } else {
+            point.y -= if self.even {
+                self.tile.y
+            } else {
+                side_offset.y
+            };
+        }
+
+        let block = Vector2::new(self.column_width * 2., self.row_height * 2.);
+        let mut reference = Vector2::new(
+            (point.x / block.x).floor() as i32,
+            (point.y / block.y).floor() as i32,
+        );
+        let relative = Vector2::new(
+            point.x - reference.x as f32 * block.x,
+            point.y - reference.y as f32 * block.y,
+        );
+
+        let (centers, offsets) = if self.x_axis {
+            reference.x = reference.x * 2 + self.even as i32;
+            let left = self.side_length.x / 2.;
+            let center = Vector2::new(left + self.column_width, self.tile.y / 2.);
+            (
+                [
+                    Vector2::new(left, center.y),
+                    Vector2::new(center.x, center.y - self.row_height),
+                    Vector2::new(center.x, center.y + self.row_height),
+                    Vector2::new(center.x + self.column_width, center.y),
+                ],
+                [(0, 0), (1, -1), (1, 0), (2, 0)],
+            )
+        } else {
+            reference.y = reference.y * 2 + self.even as i32;
+            let top = self.side_length.y / 2.;
+            let center = Vector2::new(self.tile.x / 2., top + self.row_height);
+            (
+                [
+                    Vector2::new(center.x, top),
+                    Vector2::new(center.x - self.column_width, center.y),
+                    Vector2::new(center.x + self.column_width, center.y),
+                    Vector2::new(center.x, center.y + self.row_height),
+                ],
+                [(0, 0), (-1, 1), (0, 1), (0, 2)],
+            )
+        };
+
+        // Staggered tiles are diamonds, so the nearest center is the nearest by the
+        // taxicab distance scaled to the tile's size.
+        let half_tile = self.tile / 2.;
+        let distance = |center: &Vector2<f32>| {
+            let d = relative - center;
+            if self.hexagonal {
+                d.norm_squared()
+            } else {
+                d.x.abs() / half_tile.x + d.y.abs() / half_tile.y
+            }
+        };
+
+        let nearest = (1..4).fold(0, |nearest, n| {
+            if distance(&centers[n]) < distance(&centers[nearest]) {
+                n
+            } else {
+                nearest
+            }
+        });
+
+        let (di, dj) = offsets[nearest];
+        (reference.x + di, reference.y + dj)
+    }
+
+    fn neighbors(&self, (i, j): (i32, i32)) -> SmallVec<[(i32, i32); 6]> {
+        let mut neighbors = SmallVec::new();
+        if self.x_axis {
+            let (up, down) = if self.is_shifted(i) {
+                (j, j + 1)
+            } else {
+                (j - 1, j)
+            };
+            neighbors.extend_from_slice(&[(i - 1, up), (i - 1, down), (i + 1, up), (i + 1, down)]);
+            if self.hexagonal {
+                neighbors.extend_from_slice(&[(i, j - 1), (i, j + 1)]);
+            }
+        } else {
+            let (left, right) = if self.is_shifted(j) {
+                (i, i + 1)
+            } else {
+                (i - 1, i)
+            };
+            neighbors.extend_from_slice(&[
+                (left, j - 1),
+                (right, j - 1),
+                (left, j + 1),
+                (right, j + 1),
+            ]);
+            if self.hexagonal {
+                neighbors.extend_from_slice(&[(i - 1, j), (i + 1, j)]);
+            }
+        }
+        neighbors
+    }
+
+    fn draw_order_key(&self, (i, j): (i32, i32)) -> (i32, i32) {
+        if self.x_axis {
+            (j * 2 + self.is_shifted(i) as i32, i)
+        } else {
+            (j, i)
+        }
+    }
+}
+
+impl<L, T, O> TiledMap<L, T, O> {
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    fn tile_size(&self) -> Vector2<f32> {
+        Vector2::new(self.tile_width as f32, self.tile_height as f32)
+    }
+
+    /// Where the top corner of tile (0, 0) of an isometric map is. Tiled shifts the map
+    /// right so that its leftmost tile starts at zero.
+    fn isometric_origin(&self) -> f32 {
+        self.height as f32 * self.tile_width as f32 / 2.
+    }
+
+    fn stagger(&self) -> Option<Stagger> {
+        match self.orientation {
+            Orientation::Orthogonal | Orientation::Isometric => None,
+            Orientation::Staggered { axis, index } => {
+                Some(Stagger::new(self.tile_size(), axis, index, 0))
+            }
+            Orientation::Hexagonal {
+                axis,
+                index,
+                side_length,
+            } => Some(Stagger::new(self.tile_size(), axis, index, side_length)),
+        }
+    }
+
+    /// The top-left corner of a tile's bounding box.
+    pub fn tile_to_world(&self, (i, j): (i32, i32)) -> Point2<f32> {
+        let tile = self.tile_size();
+        match self.orientation {
+            Orientation::Orthogonal => Point2::new(i as f32 * tile.x, j as f32 * tile.y),
+            Orientation::Isometric => Point2::new(
+                self.isometric_origin() + (i - j - 1) as f32 * tile.x / 2.,
+                (i + j) as f32 * tile.y / 2.,
+            ),
+            _ => self.stagger().unwrap().tile_to_world((i, j)),
+        }
+    }
+
+    pub fn tile_center(&self, tile: (i32, i32)) -> Point2<f32> {
+        self.tile_to_world(tile) + self.tile_size() / 2.
+    }
+
+    /// The tile under a point.
+    pub fn world_to_tile(&self, point: Point2<f32>) -> (i32, i32) {
+        let tile = self.tile_size();
+        match self.orientation {
+            Orientation::Orthogonal => (
+                (point.x / tile.x).floor() as i32,
+                (point.y / tile.y).floor() as i32,
+            ),
+            Orientation::Isometric => {
+                let x = (point.x - self.isometric_origin()) / tile.x;
+                let y = point.y / tile.y;
+                ((y + x).floor() as i32, (y - x).floor() as i32)
+            }
+            _ => self.stagger().unwrap().world_to_tile(point),
+        }
+    }
+
+    /// Convert a position from an object layer into map coordinates. Tiled positions
+    /// objects in isometric maps along the map's axes, measuring both in units of the
+    /// tile height; in every other orientation, object positions are already map
+    /// coordinates.
+    pub fn object_to_world(&self, point: Point2<f32>) -> Point2<f32> {
+        match self.orientation {
+            Orientation::Isometric => {
+                let tile = self.tile_size();
+                let (x, y) = (point.x / tile.y, point.y / tile.y);
+                Point2::new(
+                    self.isometric_origin() + (x - y) * tile.x / 2.,
+                    (x + y) * tile.y / 2.,
+                )
+            }
+            _ => point,
+        }
+    }
+
+    /// The tiles which share an edge with a tile: four for orthogonal, isometric and
+    /// staggered maps, and six for hexagonal maps.
+    pub fn neighbors(&self, (i, j): (i32, i32)) -> SmallVec<[(i32, i32); 6]> {
+        match self.stagger() {
+            Some(stagger) => stagger.neighbors((i, j)),
+            None => SmallVec::from_slice(&[(i - 1, j), (i + 1, j), (i, j - 1), (i, j + 1)]),
+        }
+    }
+
+    /// A key which sorts tiles into the order Tiled draws them in, back to front, so
+    /// that tiles taller than the grid overlap the right neighbors.
+    pub fn draw_order_key(&self, (i, j): (i32, i32)) -> (i32, i32) {
+        match self.orientation {
+            Orientation::Orthogonal => (j, i),
+            Orientation::Isometric => (i + j, i),
+            _ => self.stagger().unwrap().draw_order_key((i, j)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filesystem::Filesystem, tiled::parse_map, vfs::MemoryFS};
+    use {serde_json::Value, std::path::Path};
+
+    fn map(orientation: Orientation) -> TiledMap<Value, Value, Value> {
+        TiledMap {
+            source: "/map.tmx".into(),
+            width: 8,
+            height: 8,
+            tile_width: 32,
+            tile_height: 16,
+            orientation,
+            tile_sheets: Vec::new(),
+            layers: Vec::new(),
+        }
+    }
+
+    fn orientations() -> Vec<Orientation> {
+        let mut orientations = vec![Orientation::Orthogonal, Orientation::Isometric];
+        for &axis in &[StaggerAxis::X, StaggerAxis::Y] {
+            for &index in &[StaggerIndex::Odd, StaggerIndex::Even] {
+                orientations.push(Orientation::Staggered { axis, index });
+                orientations.push(Orientation::Hexagonal {
+                    axis,
+                    index,
+                    side_length: 8,
+                });
+            }
+        }
+        orientations
+    }
+
+    #[test]
+    fn tile_centers_map_back_to_their_tiles() {
+        for orientation in orientations() {
+            let map = map(orientation);
+            for i in -3..4 {
+                for j in -3..4 {
+                    let center = map.tile_center((i, j));
+                    assert_eq!(
+                        map.world_to_tile(center),
+                        (i, j),
+                        "{:?} at {:?}",
+                        orientation,
+                        center
+                    );
+
+                    for neighbor in map.neighbors((i, j)) {
+                        assert!(
+                            map.neighbors(neighbor).contains(&(i, j)),
+                            "{:?}: {:?} and {:?}",
+                            orientation,
+                            (i, j),
+                            neighbor
+                        );
+                        assert!(map.tile_center(neighbor) != center);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn staggered_layout_matches_tiled() -> Result<()> {
+        let memfs = MemoryFS::new();
+        memfs.insert(
+            "/hex.tmx",
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.5" orientation="hexagonal" width="4" height="4" tilewidth="32" tileheight="28" hexsidelength="14" staggeraxis="x" staggerindex="even" infinite="0">
+</map>
+"##,
+        )?;
+        let mut fs = Filesystem::from_vfs(memfs);
+        let path = Path::new("/hex.tmx");
+        let hex = TiledMap::<Value, Value, Value>::from_tiled(path, &parse_map(&mut fs, path)?)?;
+        assert_eq!(
+            hex.orientation(),
+            Orientation::Hexagonal {
+                axis: StaggerAxis::X,
+                index: StaggerIndex::Even,
+                side_length: 14,
+            }
+        );
+        // Columns are 23 pixels apart, and even columns are shifted down half a tile.
+        assert_eq!(hex.tile_to_world((0, 0)), Point2::new(0., 14.));
+        assert_eq!(hex.tile_to_world((1, 0)), Point2::new(23., 0.));
+        assert_eq!(hex.neighbors((1, 1)).len(), 6);
+
+        let staggered = map(Orientation::Staggered {
+            axis: StaggerAxis::Y,
+            index: StaggerIndex::Odd,
+        });
+        assert_eq!(staggered.tile_to_world((0, 1)), Point2::new(16., 8.));
+        assert_eq!(staggered.tile_to_world((0, 2)), Point2::new(0., 16.));
+        assert!(staggered.draw_order_key((3, 0)) < staggered.draw_order_key((0, 1)));
+
+        let isometric = map(Orientation::Isometric);
+        assert_eq!(isometric.tile_to_world((0, 0)), Point2::new(112., 0.));
+        assert_eq!(
+            isometric.object_to_world(Point2::new(16., 0.)),
+            isometric.tile_to_world((1, 0)) + Vector2::new(16., 0.)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_stagger_attributes_are_rejected() -> Result<()> {
+        let memfs = MemoryFS::new();
+        memfs.insert(
+            "/bad.tmx",
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.5" orientation="staggered" width="4" height="4" tilewidth="32" tileheight="16" staggeraxis="z" staggerindex="odd" infinite="0">
+</map>
+"##,
+        )?;
+        memfs.insert(
+            "/bad.tmj",
+            r##"{
+ "version": "1.5", "orientation": "staggered", "width": 4, "height": 4,
+ "tilewidth": 32, "tileheight": 16, "staggeraxis": "x", "staggerindex": "sideways",
+ "infinite": false, "tilesets": [], "layers": []
+}"##,
+        )?;
+        let mut fs = Filesystem::from_vfs(memfs);
+        assert!(parse_map(&mut fs, Path::new("/bad.tmx")).is_err());
+        assert!(parse_map(&mut fs, Path::new("/bad.tmj")).is_err());
+
+        Ok(())
+    }
+}
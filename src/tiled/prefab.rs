@@ -0,0 +1,371 @@
+//! Spawning entities from the objects in a map's object layers.
+//!
+//! A [`PrefabRegistry`] maps object types (called classes in newer versions of Tiled)
+//! to spawners, which add components for each object of their type. Tile objects
+//! without a type of their own use their tile's. Every spawned entity gets a
+//! `Transform` placing it where its object is in the map, and tile objects also get
+//! the `SpriteFrame` of their tile, a `Cached<SpriteSheet>` of their tileset and their
+//! `TileFlip`. Hooks run for every spawned object before its spawner, which is how
+//! `sludge-2d` adds positions and collision shapes, so spawners can replace anything a
+//! hook adds.
+
+use {anyhow::*, hashbrown::HashMap, rlua::prelude::*, std::fmt};
+
+use crate::{
+    api::EntityUserDataRegistry,
+    assets::Cached,
+    ecs::{Entity, EntityBuilder, World},
+    math::*,
+    sprite::{FrameId, SpriteFrame},
+    tiled::{Layer, Object, ObjectLayer, Properties, TileData, TileFlip, TiledMap},
+    transform::Transform,
+    Resources, SludgeLuaContextExt,
+};
+
+/// A Rust spawner or hook.
+pub type SpawnFn<L, T, O> =
+    Box<dyn Fn(&PrefabObject<L, T, O>, &mut EntityBuilder) -> Result<()> + Send + Sync>;
+
+enum Spawner<L, T, O> {
+    Rust(SpawnFn<L, T, O>),
+    /// A Lua function called with the object and the name of its layer, which returns
+    /// a table of components like the one passed to `spawn`, or `nil`.
+    Lua(LuaRegistryKey),
+}
+
+/// An object being spawned, along with where it came from.
+pub struct PrefabObject<'a, L, T, O> {
+    pub map: &'a TiledMap<L, T, O>,
+    pub layer: &'a ObjectLayer<L, O>,
+    pub object: &'a Object<O>,
+    /// The type the object was spawned as.
+    pub object_type: &'a str,
+    /// For tile objects, the global ID of the tile without its flip flags.
+    pub gid: Option<u32>,
+    pub flip: TileFlip,
+}
+
+impl<'a, L, T, O> PrefabObject<'a, L, T, O> {
+    /// Where the object is in the map, including its layer's offset, and its rotation.
+    /// Tiled rotates objects around their origin, which is the top-left corner of
+    /// rectangles and the bottom-left corner of tile objects. Positions in isometric
+    /// maps are converted with [`TiledMap::object_to_world`].
+    pub fn isometry(&self) -> Isometry2<f32> {
+        let position = self
+            .map
+            .object_to_world(Point2::new(self.object.x, self.object.y));
+        Isometry2::new(
+            position.coords + Vector2::new(self.layer.offset_x, self.layer.offset_y),
+            self.object.rot.to_radians(),
+        )
+    }
+
+    pub fn transform(&self) -> Transform3<f32> {
+        Transform3::from_matrix_unchecked(homogeneous_mat3_to_mat4(
+            &self.isometry().to_homogeneous(),
+        ))
+    }
+
+    pub fn tile_data(&self) -> Option<&'a TileData<T>> {
+        self.gid.and_then(|gid| self.map.get_tile_data_for_gid(gid))
+    }
+}
+
+/// Spawners for the object types of maps, and hooks run for every spawned object.
+pub struct PrefabRegistry<L, T, O> {
+    spawners: HashMap<String, Spawner<L, T, O>>,
+    hooks: Vec<SpawnFn<L, T, O>>,
+}
+
+impl<L, T, O> Default for PrefabRegistry<L, T, O> {
+    fn default() -> Self {
+        Self {
+            spawners: HashMap::new(),
+            hooks: Vec::new(),
+        }
+    }
+}
+
+impl<L, T, O> fmt::Debug for PrefabRegistry<L, T, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut types = self.spawners.keys().collect::<Vec<_>>();
+        types.sort();
+        f.debug_struct("PrefabRegistry")
+            .field("spawners", &types)
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+impl<L, T, O> PrefabRegistry<L, T, O> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&mut self, object_type: &str, spawner: Spawner<L, T, O>) -> Result<()> {
+        ensure!(
+            !self.contains(object_type),
+            "a spawner for object type `{}` is already registered",
+            object_type
+        );
+        self.spawners.insert(object_type.to_owned(), spawner);
+        Ok(())
+    }
+
+    /// Register a spawner for objects of the given type.
+    pub fn register<F>(&mut self, object_type: &str, spawner: F) -> Result<()>
+    where
+        F: Fn(&PrefabObject<L, T, O>, &mut EntityBuilder) -> Result<()> + Send + Sync + 'static,
+    {
+        self.insert(object_type, Spawner::Rust(Box::new(spawner)))
+    }
+
+    /// Register a Lua function as the spawner for objects of the given type. It's
+    /// called with the object and the name of its layer, and returns a table of
+    /// components like the one passed to `spawn`, or `nil` to only get the components
+    /// every object gets.
+    pub fn register_lua<'lua>(
+        &mut self,
+        lua: LuaContext<'lua>,
+        object_type: &str,
+        function: LuaFunction<'lua>,
+    ) -> Result<()> {
+        let key = lua.create_registry_value(function)?;
+        self.insert(object_type, Spawner::Lua(key))
+    }
+
+    /// Add a hook which runs for every spawned object, before its spawner.
+    pub fn add_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&PrefabObject<L, T, O>, &mut EntityBuilder) -> Result<()> + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+    }
+
+    pub fn contains(&self, object_type: &str) -> bool {
+        self.spawners.contains_key(object_type)
+    }
+
+    fn build<'lua>(
+        &self,
+        spawner: &Spawner<L, T, O>,
+        prefab: &PrefabObject<L, T, O>,
+        lua: Option<LuaContext<'lua>>,
+        builder: &mut EntityBuilder,
+    ) -> Result<()>
+    where
+        O: Properties,
+    {
+        for hook in &self.hooks {
+            hook(prefab, builder)?;
+        }
+
+        match spawner {
+            Spawner::Rust(spawner) => spawner(prefab, builder)?,
+            Spawner::Lua(key) => {
+                let lua = lua.ok_or_else(|| {
+                    anyhow!("objects with Lua spawners must be spawned with `instantiate_with_lua`")
+                })?;
+                let function = lua.registry_value::<LuaFunction>(key)?;
+                let object = rlua_serde::to_value(lua, prefab.object)?;
+                let components =
+                    function.call::<_, Option<LuaTable>>((object, prefab.layer.name.as_str()))?;
+                if let Some(components) = components {
+                    lua.resources()
+                        .fetch::<EntityUserDataRegistry>()
+                        .bundle(lua, components, builder)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<L, T, O> TiledMap<L, T, O>
+where
+    L: Properties,
+    T: Properties,
+    O: Properties,
+{
+    /// Spawn an entity for every object in the map whose type has a spawner, returning
+    /// them in drawing order. Objects with other types are skipped with a warning, and
+    /// untyped objects are skipped silently.
+    pub fn instantiate(
+        &self,
+        world: &mut World,
+        registry: &PrefabRegistry<L, T, O>,
+    ) -> Result<Vec<Entity>> {
+        self.instantiate_inner(registry, None, |builder| world.spawn(builder.build()))
+    }
+
+    /// Like `instantiate`, but also supports Lua spawners, and spawns into the `World`
+    /// in the Lua context's resources. The `World` isn't borrowed while spawners run, so
+    /// they can look at it; the map shouldn't be borrowed out of it, though.
+    pub fn instantiate_with_lua<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        registry: &PrefabRegistry<L, T, O>,
+    ) -> Result<Vec<Entity>> {
+        self.instantiate_inner(registry, Some(lua), |builder| {
+            lua.resources().fetch_mut::<World>().spawn(builder.build())
+        })
+    }
+
+    fn instantiate_inner<'lua>(
+        &self,
+        registry: &PrefabRegistry<L, T, O>,
+        lua: Option<LuaContext<'lua>>,
+        mut spawn: impl FnMut(&mut EntityBuilder) -> Entity,
+    ) -> Result<Vec<Entity>> {
+        let mut sheets = HashMap::new();
+        let mut entities = Vec::new();
+
+        for layer in self.leaf_layers() {
+            let layer = match layer {
+                Layer::ObjectLayer(layer) => layer,
+                _ => continue,
+            };
+
+            for object in &layer.objects {
+                let (gid, flip) = TileFlip::split_gid(object.gid);
+                let gid = Some(gid).filter(|&gid| gid != 0);
+                let object_type = match (object.object_type.as_str(), gid) {
+                    ("", Some(gid)) => self
+                        .get_tile_data_for_gid(gid)
+                        .and_then(|tile| tile.tile_type.as_deref())
+                        .unwrap_or(""),
+                    (object_type, _) => object_type,
+                };
+
+                let spawner = match registry.spawners.get(object_type) {
+                    Some(spawner) => spawner,
+                    None => {
+                        if !object_type.is_empty() {
+                            log::warn!(
+                                "no spawner for object {} of type `{}` in {:?}",
+                                object.id,
+                                object_type,
+                                self.source()
+                            );
+                        }
+                        continue;
+                    }
+                };
+
+                let prefab = PrefabObject {
+                    map: self,
+                    layer,
+                    object,
+                    object_type,
+                    gid,
+                    flip,
+                };
+
+                let mut builder = EntityBuilder::new();
+                builder.add(Transform::new(prefab.transform()));
+
+                if let Some(gid) = gid {
+                    let sheet = self.get_tile_sheet_for_gid(gid).ok_or_else(|| {
+                        anyhow!(
+                            "object {} refers to tile {}, which isn't in any tileset",
+                            object.id,
+                            gid
+                        )
+                    })?;
+                    let first_gid = sheet.first_global_id();
+                    let cached = sheets
+                        .entry(first_gid)
+                        .or_insert_with(|| Cached::new(sheet.to_sprite_sheet()));
+                    builder.add(SpriteFrame(FrameId::new(gid - first_gid)));
+                    builder.add(cached.clone());
+                    builder.add(flip);
+                }
+
+                registry
+                    .build(spawner, &prefab, lua, &mut builder)
+                    .with_context(|| {
+                        anyhow!(
+                            "error spawning object {} of type `{}` from {:?}",
+                            object.id,
+                            object_type,
+                            self.source()
+                        )
+                    })?;
+
+                entities.push(spawn(&mut builder));
+            }
+        }
+
+        Ok(entities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filesystem::Filesystem, sprite::SpriteSheet, tiled::parse_map, vfs::MemoryFS};
+    use {serde_json::Value, std::path::Path};
+
+    #[derive(Debug, PartialEq)]
+    struct Spawned(String);
+
+    #[test]
+    fn objects_spawn_by_type() -> Result<()> {
+        let memfs = MemoryFS::new();
+        memfs.insert(
+            "/maps/level.tmx",
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.5" orientation="orthogonal" width="2" height="2" tilewidth="16" tileheight="16" infinite="0">
+ <tileset firstgid="1" name="things" tilewidth="16" tileheight="16" tilecount="4" columns="2">
+  <image source="things.png" width="32" height="32"/>
+  <tile id="3" type="chest"/>
+ </tileset>
+ <objectgroup id="1" name="actors" offsetx="100">
+  <object id="1" name="hero" type="player" x="8" y="16" width="16" height="16"/>
+  <object id="2" type="decoration" x="0" y="0"/>
+  <object id="3" x="0" y="0"/>
+  <object id="4" gid="2147483652" x="32" y="48" width="16" height="16"/>
+ </objectgroup>
+</map>
+"##,
+        )?;
+        let mut fs = Filesystem::from_vfs(memfs);
+        let path = Path::new("/maps/level.tmx");
+        let map = TiledMap::<Value, Value, Value>::from_tiled(path, &parse_map(&mut fs, path)?)?;
+
+        let mut registry = PrefabRegistry::new();
+        registry.register("player", |prefab, builder| {
+            builder.add(Spawned(prefab.object.name.clone()));
+            Ok(())
+        })?;
+        registry.register("chest", |prefab, builder| {
+            builder.add(Spawned(prefab.object_type.to_owned()));
+            Ok(())
+        })?;
+        assert!(registry.register("chest", |_, _| Ok(())).is_err());
+
+        let mut world = World::new();
+        let entities = map.instantiate(&mut world, &registry)?;
+        assert_eq!(entities.len(), 2);
+
+        let hero = entities[0];
+        assert_eq!(*world.get_raw::<Spawned>(hero)?, Spawned("hero".to_owned()));
+        let position = world.get_raw::<Transform>(hero)?.local() * Point3::origin();
+        assert_eq!(position, Point3::new(108., 16., 0.));
+        assert!(world.get_raw::<SpriteFrame>(hero).is_err());
+
+        let chest = entities[1];
+        assert_eq!(
+            *world.get_raw::<Spawned>(chest)?,
+            Spawned("chest".to_owned())
+        );
+        assert_eq!(world.get_raw::<SpriteFrame>(chest)?.0, FrameId::new(3));
+        assert!(world.get_raw::<TileFlip>(chest)?.horizontal);
+        let sheet = world.get_raw::<Cached<SpriteSheet>>(chest)?.load();
+        assert_eq!(sheet.frames.len(), 4);
+        assert_eq!(sheet.frames[3].frame, Box2::new(16, 16, 16, 16));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,413 @@
+//! Drawing tile layers with a `SpriteBatch` per chunk and tileset.
+
+use {anyhow::*, hashbrown::HashMap};
+
+use crate::{
+    assets::{Cache, Cached, Key},
+    graphics::{Color, Drawable, Graphics, InstanceParam, SpriteBatch, SpriteId, Texture},
+    math::*,
+    tiled::{Chunk, Orientation, TileFlip, TileLayer, TiledMap},
+    Resources,
+};
+
+impl<L, T, O> TiledMap<L, T, O> {
+    /// Load the texture of every tile sheet, in the same order as `tile_sheets`, for
+    /// building `TileLayerRenderer`s. Textures need the `Graphics` out of the cache's
+    /// resources to load, so this has to happen before it's borrowed to build them.
+    pub fn load_textures<'a, R: Resources<'a>>(
+        &self,
+        cache: &Cache<'a, R>,
+    ) -> Result<Vec<Cached<Texture>>> {
+        self.tile_sheets()
+            .iter()
+            .map(|sheet| {
+                cache
+                    .get::<Texture>(&Key::from_path(sheet.source()))
+                    .with_context(|| anyhow!("error loading tile sheet `{}`", sheet.name()))
+            })
+            .collect()
+    }
+}
+
+/// A run of tiles which are drawn one after the other, and culled together.
+#[derive(Debug)]
+struct RenderChunk {
+    aabb: Box2<f32>,
+    /// A new batch starts whenever the tile sheet changes, so that tiles are drawn in
+    /// order even when they come from different sheets.
+    batches: Vec<SpriteBatch>,
+}
+
+/// Every sprite showing an animated tile, and where in the tile's animation they are.
+#[derive(Debug)]
+struct Animation {
+    /// The UVs of each frame, and the time at which it ends, in milliseconds since the
+    /// start of the animation.
+    frames: Vec<(Box2<f32>, u32)>,
+    /// The frame the sprites are showing, or `usize::MAX` before the first update.
+    current: usize,
+    /// Indices of the chunk and batch each sprite is in.
+    sprites: Vec<(usize, usize, SpriteId)>,
+}
+
+impl Animation {
+    /// The frame showing `elapsed` milliseconds into the animation, looping around, or
+    /// `None` if the animation takes no time at all.
+    fn frame_at(&self, elapsed: f32) -> Option<usize> {
+        let total = match self.frames.last() {
+            Some(&(_, end)) if end > 0 => end,
+            _ => return None,
+        };
+
+        let time = (elapsed % total as f32) as u32;
+        Some(
+            self.frames
+                .iter()
+                .position(|&(_, end)| time < end)
+                .unwrap_or(0),
+        )
+    }
+}
+
+/// Where the sprite of a tile with the given source rectangle goes, with `position` at
+/// the top-left corner of its footprint. The batch scales each sprite up to the size of
+/// its source rectangle, so the flip has to happen at that size.
+fn tile_param(
+    src: Box2<f32>,
+    color: Color,
+    position: Vector2<f32>,
+    size: Vector2<f32>,
+    flip: TileFlip,
+) -> InstanceParam {
+    InstanceParam::new()
+        .src(src)
+        .color(color)
+        .translate2(position)
+        .scale2(flip.footprint(size))
+        .prepend_transform(&flip.to_transform())
+        .scale2(size.map(f32::recip))
+}
+
+/// Draws a tile layer, skipping chunks which are out of view. Views are found by
+/// mapping the corners of clip space back through the current projection and
+/// transform, so culling works with whatever camera the layer is drawn under.
+///
+/// Animated tiles only move when `update` is called.
+#[derive(Debug)]
+pub struct TileLayerRenderer {
+    chunks: Vec<RenderChunk>,
+    animations: Vec<Animation>,
+    /// Milliseconds since the renderer was built.
+    elapsed: f32,
+    offset: Vector2<f32>,
+    visible: bool,
+}
+
+impl TileLayerRenderer {
+    /// Build a renderer for one of a map's tile layers. `textures` are the textures of
+    /// the map's tile sheets, as loaded by `TiledMap::load_textures`.
+    pub fn new<L, T, O>(
+        ctx: &mut Graphics,
+        map: &TiledMap<L, T, O>,
+        layer: &TileLayer<L>,
+        textures: &[Cached<Texture>],
+    ) -> Result<Self> {
+        ensure!(
+            textures.len() == map.tile_sheets().len(),
+            "expected {} tile sheet textures, got {}",
+            map.tile_sheets().len(),
+            textures.len()
+        );
+
+        let (_, tile_height) = map.tile_dimensions();
+        let color = Color {
+            a: layer.tint.a * layer.opacity,
+            ..layer.tint
+        };
+
+        // Draw tiles in the map's draw order, so that tiles taller than the map's grid
+        // overlap the ones behind them. On orthogonal maps, that's row by row, so each
+        // of the layer's chunks can be drawn on its own as long as the chunks are
+        // sorted too. On other maps, rows run diagonally across chunks, so the whole
+        // layer is sorted and then cut up into runs the size of a chunk.
+        let nonempty = |chunk: &Chunk| {
+            chunk
+                .tiles()
+                .filter(|&(_, gid, _)| gid != 0)
+                .collect::<Vec<_>>()
+        };
+        let mut sorted = layer.chunks().map(|(_, chunk)| chunk).collect::<Vec<_>>();
+        sorted.sort_by_key(|chunk| map.draw_order_key((chunk.x, chunk.y)));
+        let runs = match map.orientation() {
+            Orientation::Orthogonal => sorted
+                .into_iter()
+                .map(|chunk| {
+                    let mut run = nonempty(chunk);
+                    run.sort_by_key(|&(tile, _, _)| map.draw_order_key(tile));
+                    run
+                })
+                .collect::<Vec<_>>(),
+            _ => {
+                let run_length = sorted.iter().map(|chunk| chunk.data.len()).max();
+                let mut all = sorted.into_iter().flat_map(nonempty).collect::<Vec<_>>();
+                all.sort_by_key(|&(tile, _, _)| map.draw_order_key(tile));
+                all.chunks(run_length.unwrap_or(1).max(1))
+                    .map(<[_]>::to_vec)
+                    .collect()
+            }
+        };
+
+        let mut chunks = Vec::new();
+        let mut animations = Vec::new();
+        let mut animation_indices = HashMap::new();
+
+        for run in runs {
+            let mut aabb = Box2::invalid();
+            let mut batches = Vec::<(usize, SpriteBatch)>::new();
+
+            for &(tile, gid, flip) in &run {
+                let sheet_index = map
+                    .tile_sheets()
+                    .iter()
+                    .position(|ts| ts.first_global_id() <= gid && gid <= ts.last_global_id())
+                    .ok_or_else(|| anyhow!("tile {} isn't in any tile sheet", gid))?;
+                let sheet = &map.tile_sheets()[sheet_index];
+                let region = sheet.get_region_from_global_id(gid);
+
+                // Tiles are anchored to the bottom-left corner of their cell, which
+                // only matters for tiles of a different size than the map's grid.
+                let size = na::convert::<_, Vector2<f32>>(region.bounds.extents());
+                let footprint = flip.footprint(size);
+                let position = map.tile_to_world(tile).coords
+                    + Vector2::new(0., tile_height as f32 - footprint.y);
+                aabb.merge(&Box2::from_extents(Point2::from(position), footprint));
+                let param = tile_param(region.uv, color, position, size, flip);
+
+                if batches
+                    .last()
+                    .map_or(true, |&(last, _)| last != sheet_index)
+                {
+                    let texture = textures[sheet_index].clone();
+                    let batch = SpriteBatch::with_capacity(ctx, texture, run.len());
+                    batches.push((sheet_index, batch));
+                }
+                let batch_index = batches.len() - 1;
+                let sprite = batches[batch_index].1.insert(param);
+
+                if sheet.is_tile_animated_by_global_id(gid) {
+                    let index = *animation_indices.entry(gid).or_insert_with(|| {
+                        let frames = sheet
+                            .get_tile_data_from_local_id(region.local_id)
+                            .and_then(|data| data.frames.as_ref())
+                            .into_iter()
+                            .flatten()
+                            .scan(0, |end, frame| {
+                                *end += frame.duration;
+                                let uv = sheet.get_region_from_local_id(frame.local_id).uv;
+                                Some((uv, *end))
+                            })
+                            .collect();
+
+                        animations.push(Animation {
+                            frames,
+                            current: usize::MAX,
+                            sprites: Vec::new(),
+                        });
+                        animations.len() - 1
+                    });
+
+                    animations[index]
+                        .sprites
+                        .push((chunks.len(), batch_index, sprite));
+                }
+            }
+
+            chunks.push(RenderChunk {
+                aabb,
+                batches: batches.into_iter().map(|(_, batch)| batch).collect(),
+            });
+        }
+
+        let mut renderer = Self {
+            chunks,
+            animations,
+            elapsed: 0.,
+            offset: Vector2::new(layer.offset_x, layer.offset_y),
+            visible: layer.visible && layer.opacity > 0.,
+        };
+        renderer.update(0.);
+
+        Ok(renderer)
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+
+    /// Advance the layer's tile animations by `dt` seconds.
+    pub fn update(&mut self, dt: f32) {
+        self.elapsed += dt * 1_000.;
+
+        let chunks = &mut self.chunks;
+        for animation in &mut self.animations {
+            let current = match animation.frame_at(self.elapsed) {
+                Some(current) => current,
+                None => continue,
+            };
+            if current == animation.current {
+                continue;
+            }
+
+            animation.current = current;
+            let uv = animation.frames[current].0;
+            for &(chunk, batch, sprite) in &animation.sprites {
+                chunks[chunk].batches[batch][sprite].src = uv;
+            }
+        }
+    }
+
+    /// The part of the layer which is in view when it's drawn with `mvp`, the product of
+    /// the projection, modelview and instance transforms, or `None` if it's degenerate.
+    fn view(mvp: &Matrix4<f32>) -> Option<Box2<f32>> {
+        let inverse = mvp.try_inverse()?;
+        Some(Box2::new(-1., -1., 2., 2.).transformed_by(&inverse))
+    }
+
+    /// The chunks which are at least partly in view when the layer is drawn with `mvp`.
+    /// If the matrix is degenerate, every chunk is.
+    fn chunks_in_view<'a>(&'a self, mvp: &Matrix4<f32>) -> impl Iterator<Item = &'a RenderChunk> {
+        let view = Self::view(mvp);
+        self.chunks
+            .iter()
+            .filter(move |chunk| view.map_or(true, |view| view.intersects(&chunk.aabb)))
+    }
+}
+
+impl Drawable for TileLayerRenderer {
+    fn draw(&self, ctx: &mut Graphics, instance: InstanceParam) {
+        if !self.visible {
+            return;
+        }
+
+        let instance = instance.translate2(self.offset);
+        let mvp = ctx.projection * ctx.modelview.top() * instance.tx.matrix();
+        for chunk in self.chunks_in_view(&mvp) {
+            for batch in &chunk.batches {
+                batch.draw(ctx, instance);
+            }
+        }
+    }
+
+    fn aabb2(&self) -> Box2<f32> {
+        let mut aabb = Box2::invalid();
+        for chunk in &self.chunks {
+            aabb.merge(&chunk.aabb);
+        }
+        aabb.mins += self.offset;
+        aabb.maxs += self.offset;
+        aabb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renderer(aabbs: &[Box2<f32>]) -> TileLayerRenderer {
+        TileLayerRenderer {
+            chunks: aabbs
+                .iter()
+                .map(|&aabb| RenderChunk {
+                    aabb,
+                    batches: Vec::new(),
+                })
+                .collect(),
+            animations: Vec::new(),
+            elapsed: 0.,
+            offset: Vector2::zeros(),
+            visible: true,
+        }
+    }
+
+    #[test]
+    fn animations_loop_through_their_frames() {
+        let uv = Box2::new(0., 0., 1., 1.);
+        let animation = Animation {
+            frames: vec![(uv, 100), (uv, 300)],
+            current: usize::MAX,
+            sprites: Vec::new(),
+        };
+        let frames = [0., 99., 100., 299., 300., 450.]
+            .iter()
+            .map(|&t| animation.frame_at(t))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frames,
+            vec![Some(0), Some(0), Some(1), Some(1), Some(0), Some(1)]
+        );
+
+        let instant = Animation {
+            frames: vec![(uv, 0)],
+            ..animation
+        };
+        assert_eq!(instant.frame_at(50.), None);
+    }
+
+    #[test]
+    fn chunks_out_of_view_are_culled() {
+        let renderer = renderer(&[
+            Box2::new(0., 0., 16., 16.),
+            Box2::new(100., 0., 16., 16.),
+            Box2::new(40., 40., 16., 16.),
+        ]);
+        let in_view = |mvp: &Matrix4<f32>| {
+            renderer
+                .chunks_in_view(mvp)
+                .map(|chunk| chunk.aabb.mins.x)
+                .collect::<Vec<_>>()
+        };
+
+        let projection = Matrix4::new_orthographic(0., 64., 64., 0., -1., 1.);
+        assert_eq!(in_view(&projection), vec![0., 40.]);
+
+        let scrolled = projection * Matrix4::new_translation(&Vector3::new(-64., 0., 0.));
+        assert_eq!(in_view(&scrolled), vec![100.]);
+
+        assert_eq!(in_view(&Matrix4::zeros()), vec![0., 100., 40.]);
+    }
+
+    #[test]
+    fn flipped_tiles_stay_in_their_cell() {
+        let corners = |flip| {
+            let param = tile_param(
+                Box2::new(0., 0., 1., 1.),
+                Color::WHITE,
+                Vector2::new(32., 16.),
+                Vector2::new(16., 24.),
+                flip,
+            );
+            // Scaling back down to the unit square isn't exact, so round the corners.
+            [(0., 0.), (16., 24.)]
+                .iter()
+                .map(|&(x, y)| param.tx * Point3::new(x, y, 0.))
+                .map(|p| (p.x.round(), p.y.round()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(corners(TileFlip::default()), vec![(32., 16.), (48., 40.)]);
+        let horizontal = TileFlip {
+            horizontal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(corners(horizontal), vec![(48., 16.), (32., 40.)]);
+        let diagonal = TileFlip {
+            diagonal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(corners(diagonal), vec![(32., 16.), (56., 32.)]);
+    }
+}
@@ -18,7 +18,13 @@ use {
     },
 };
 
-use crate::filesystem::Filesystem;
+use crate::{
+    filesystem::Filesystem,
+    tiled::{
+        json_parser,
+        orientation::{StaggerAxis, StaggerIndex},
+    },
+};
 
 #[derive(Debug, Copy, Clone)]
 pub enum ParseTileError {
@@ -142,7 +148,7 @@ pub enum PropertyValue {
 }
 
 impl PropertyValue {
-    fn new(property_type: String, value: String) -> Result<PropertyValue, Error> {
+    pub(super) fn new(property_type: String, value: String) -> Result<PropertyValue, Error> {
         // Check the property type against the value.
         match property_type.as_str() {
             "bool" => match value.parse() {
@@ -174,6 +180,18 @@ impl PropertyValue {
 
 pub type Properties = HashMap<String, PropertyValue>;
 
+/// Parse a `#aarrggbb` or `#rrggbb` colour, such as a layer's tint, into `0xAARRGGBB`.
+/// Colours without an alpha are opaque.
+pub(super) fn parse_argb(s: &str) -> Option<u32> {
+    let s = s.trim_start_matches('#');
+    let argb = u32::from_str_radix(s, 16).ok()?;
+    match s.len() {
+        6 => Some(0xff000000 | argb),
+        8 => Some(argb),
+        _ => None,
+    }
+}
+
 fn parse_properties<R: Read>(parser: &mut EventReader<R>) -> Result<Properties, Error> {
     let mut p = HashMap::new();
     parse_tag!(parser, "properties", {
@@ -211,9 +229,13 @@ pub struct Map {
     pub layers: Vec<Layer>,
     pub image_layers: Vec<ImageLayer>,
     pub object_groups: Vec<ObjectGroup>,
+    pub groups: Vec<Group>,
     pub properties: Properties,
     pub background_colour: Option<Colour>,
     pub infinite: bool,
+    pub stagger_axis: Option<StaggerAxis>,
+    pub stagger_index: Option<StaggerIndex>,
+    pub hex_side_length: Option<u32>,
 }
 
 impl Map {
@@ -223,11 +245,14 @@ impl Map {
         attrs: Vec<OwnedAttribute>,
         map_path: Option<&Path>,
     ) -> Result<Map> {
-        let ((c, infinite), (v, o, w, h, tw, th)) = get_attrs!(
+        let ((c, infinite, sa, si, hs), (v, o, w, h, tw, th)) = get_attrs!(
             attrs,
             optionals: [
                 ("backgroundcolor", colour, |v:String| v.parse().ok()),
                 ("infinite", infinite, |v:String| Some(v == "1")),
+                ("staggeraxis", stagger_axis, |v| Some(v)),
+                ("staggerindex", stagger_index, |v| Some(v)),
+                ("hexsidelength", hex_side_length, |v| Some(v)),
             ],
             required: [
                 ("version", version, |v| Some(v)),
@@ -239,12 +264,21 @@ impl Map {
             ],
             Error::from(TiledError::MalformedAttributes("map must have a version, width and height with correct types".to_string()))
         );
+        // Unlike the attributes above, these are rejected rather than ignored if they're
+        // malformed, the same as in JSON maps.
+        let sa = sa.as_deref().map(str::parse).transpose()?;
+        let si = si.as_deref().map(str::parse).transpose()?;
+        let hs = hs
+            .map(|v: String| v.parse())
+            .transpose()
+            .context("malformed hexsidelength")?;
 
         let mut tilesets = Vec::new();
         let mut layers = Vec::new();
         let mut image_layers = Vec::new();
         let mut properties = HashMap::new();
         let mut object_groups = Vec::new();
+        let mut groups = Vec::new();
         let mut layer_index = 0;
         parse_tag!(parser, "map", {
             "tileset" => | attrs| {
@@ -270,6 +304,11 @@ impl Map {
                 layer_index += 1;
                 Ok(())
             },
+            "group" => |attrs| {
+                groups.push(Group::new(parser, attrs, w, layer_index, infinite.unwrap_or(false), map_path)?);
+                layer_index += 1;
+                Ok(())
+            },
         });
         Ok(Map {
             version: v,
@@ -282,9 +321,13 @@ impl Map {
             layers,
             image_layers,
             object_groups,
+            groups,
             properties,
             background_colour: c,
             infinite: infinite.unwrap_or(false),
+            stagger_axis: sa,
+            stagger_index: si,
+            hex_side_length: hs,
         })
     }
 
@@ -424,14 +467,7 @@ impl Tileset {
             TiledError::MalformedAttributes("tileset must have a firstgid, name tile width and height with correct types".to_string())
         );
 
-        let tileset_path = map_path.ok_or(TiledError::Other("Maps with external tilesets must know their file location.  See parse_with_path(Path).".to_string()))?.with_file_name(&source);
-        let file = fs.open(&tileset_path).with_context(|| {
-            TiledError::Other(format!(
-                "External tileset file not found: {:?}",
-                tileset_path
-            ))
-        })?;
-        Tileset::new_external(file, first_gid, Some(&tileset_path))
+        parse_tileset_reference(fs, first_gid, &source, map_path)
     }
 
     fn new_external<R: Read>(
@@ -635,10 +671,10 @@ pub struct LayerTile {
     pub flip_d: bool,
 }
 
-const FLIPPED_HORIZONTALLY_FLAG: u32 = 0x80000000;
-const FLIPPED_VERTICALLY_FLAG: u32 = 0x40000000;
-const FLIPPED_DIAGONALLY_FLAG: u32 = 0x20000000;
-const ALL_FLIP_FLAGS: u32 =
+pub(crate) const FLIPPED_HORIZONTALLY_FLAG: u32 = 0x80000000;
+pub(crate) const FLIPPED_VERTICALLY_FLAG: u32 = 0x40000000;
+pub(crate) const FLIPPED_DIAGONALLY_FLAG: u32 = 0x20000000;
+pub(crate) const ALL_FLIP_FLAGS: u32 =
     FLIPPED_HORIZONTALLY_FLAG | FLIPPED_VERTICALLY_FLAG | FLIPPED_DIAGONALLY_FLAG;
 
 impl LayerTile {
@@ -663,6 +699,12 @@ pub struct Layer {
     pub name: String,
     pub opacity: f32,
     pub visible: bool,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    /// The layer's tint as `0xAARRGGBB`, if it has one.
+    pub tint: Option<u32>,
     /// The tiles are arranged in rows. Each tile is a number which can be used
     ///  to find which tileset it belongs to and can then be rendered.
     pub tiles: LayerData,
@@ -678,11 +720,16 @@ impl Layer {
         layer_index: u32,
         infinite: bool,
     ) -> Result<Layer, Error> {
-        let ((o, v), n) = get_attrs!(
+        let ((o, v, ox, oy, px, py, t), n) = get_attrs!(
             attrs,
             optionals: [
                 ("opacity", opacity, |v:String| v.parse().ok()),
                 ("visible", visible, |v:String| v.parse().ok().map(|x:i32| x == 1)),
+                ("offsetx", offset_x, |v:String| v.parse().ok()),
+                ("offsety", offset_y, |v:String| v.parse().ok()),
+                ("parallaxx", parallax_x, |v:String| v.parse().ok()),
+                ("parallaxy", parallax_y, |v:String| v.parse().ok()),
+                ("tintcolor", tint, |v:String| parse_argb(&v)),
             ],
             required: [
                 ("name", name, |v| Some(v)),
@@ -710,6 +757,11 @@ impl Layer {
             name: n,
             opacity: o.unwrap_or(1.0),
             visible: v.unwrap_or(true),
+            offset_x: ox.unwrap_or(0.0),
+            offset_y: oy.unwrap_or(0.0),
+            parallax_x: px.unwrap_or(1.0),
+            parallax_y: py.unwrap_or(1.0),
+            tint: t,
             tiles: tiles,
             properties: properties,
             layer_index,
@@ -768,6 +820,10 @@ pub struct ImageLayer {
     pub visible: bool,
     pub offset_x: f32,
     pub offset_y: f32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    /// The layer's tint as `0xAARRGGBB`, if it has one.
+    pub tint: Option<u32>,
     pub image: Option<Image>,
     pub properties: Properties,
     pub layer_index: u32,
@@ -780,13 +836,16 @@ impl ImageLayer {
         layer_index: u32,
         map_path: Option<&Path>,
     ) -> Result<ImageLayer, Error> {
-        let ((o, v, ox, oy), n) = get_attrs!(
+        let ((o, v, ox, oy, px, py, t), n) = get_attrs!(
             attrs,
             optionals: [
                 ("opacity", opacity, |v:String| v.parse().ok()),
                 ("visible", visible, |v:String| v.parse().ok().map(|x:i32| x == 1)),
                 ("offsetx", offset_x, |v:String| v.parse().ok()),
                 ("offsety", offset_y, |v:String| v.parse().ok()),
+                ("parallaxx", parallax_x, |v:String| v.parse().ok()),
+                ("parallaxy", parallax_y, |v:String| v.parse().ok()),
+                ("tintcolor", tint, |v:String| parse_argb(&v)),
             ],
             required: [
                 ("name", name, |v| Some(v)),
@@ -810,6 +869,9 @@ impl ImageLayer {
             visible: v.unwrap_or(true),
             offset_x: ox.unwrap_or(0.0),
             offset_y: oy.unwrap_or(0.0),
+            parallax_x: px.unwrap_or(1.0),
+            parallax_y: py.unwrap_or(1.0),
+            tint: t,
             image,
             properties,
             layer_index,
@@ -822,6 +884,12 @@ pub struct ObjectGroup {
     pub name: String,
     pub opacity: f32,
     pub visible: bool,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    /// The layer's tint as `0xAARRGGBB`, if it has one.
+    pub tint: Option<u32>,
     pub objects: Vec<Object>,
     pub colour: Option<Colour>,
     /**
@@ -837,13 +905,18 @@ impl ObjectGroup {
         attrs: Vec<OwnedAttribute>,
         layer_index: Option<u32>,
     ) -> Result<ObjectGroup, Error> {
-        let ((o, v, c, n), ()) = get_attrs!(
+        let ((o, v, c, n, ox, oy, px, py, t), ()) = get_attrs!(
             attrs,
             optionals: [
                 ("opacity", opacity, |v:String| v.parse().ok()),
                 ("visible", visible, |v:String| v.parse().ok().map(|x:i32| x == 1)),
                 ("color", colour, |v:String| v.parse().ok()),
                 ("name", name, |v:String| v.into()),
+                ("offsetx", offset_x, |v:String| v.parse().ok()),
+                ("offsety", offset_y, |v:String| v.parse().ok()),
+                ("parallaxx", parallax_x, |v:String| v.parse().ok()),
+                ("parallaxy", parallax_y, |v:String| v.parse().ok()),
+                ("tintcolor", tint, |v:String| parse_argb(&v)),
             ],
             required: [],
             TiledError::MalformedAttributes("object groups must have a name".to_string())
@@ -864,6 +937,11 @@ impl ObjectGroup {
             name: n.unwrap_or(String::new()),
             opacity: o.unwrap_or(1.0),
             visible: v.unwrap_or(true),
+            offset_x: ox.unwrap_or(0.0),
+            offset_y: oy.unwrap_or(0.0),
+            parallax_x: px.unwrap_or(1.0),
+            parallax_y: py.unwrap_or(1.0),
+            tint: t,
             objects: objects,
             colour: c,
             layer_index,
@@ -872,6 +950,105 @@ impl ObjectGroup {
     }
 }
 
+/// A group of layers. Its offset, opacity, visibility, parallax and tint apply on top
+/// of each of its children's own.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Group {
+    pub name: String,
+    pub opacity: f32,
+    pub visible: bool,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    /// The group's tint as `0xAARRGGBB`, if it has one.
+    pub tint: Option<u32>,
+    /// The children are indexed by their position in the group, not the map.
+    pub layers: Vec<Layer>,
+    pub image_layers: Vec<ImageLayer>,
+    pub object_groups: Vec<ObjectGroup>,
+    pub groups: Vec<Group>,
+    pub properties: Properties,
+    pub layer_index: u32,
+}
+
+impl Group {
+    fn new<R: Read>(
+        parser: &mut EventReader<R>,
+        attrs: Vec<OwnedAttribute>,
+        width: u32,
+        layer_index: u32,
+        infinite: bool,
+        map_path: Option<&Path>,
+    ) -> Result<Group, Error> {
+        let ((n, o, v, ox, oy, px, py, t), ()) = get_attrs!(
+            attrs,
+            optionals: [
+                ("name", name, |v:String| v.into()),
+                ("opacity", opacity, |v:String| v.parse().ok()),
+                ("visible", visible, |v:String| v.parse().ok().map(|x:i32| x == 1)),
+                ("offsetx", offset_x, |v:String| v.parse().ok()),
+                ("offsety", offset_y, |v:String| v.parse().ok()),
+                ("parallaxx", parallax_x, |v:String| v.parse().ok()),
+                ("parallaxy", parallax_y, |v:String| v.parse().ok()),
+                ("tintcolor", tint, |v:String| parse_argb(&v)),
+            ],
+            required: [],
+            TiledError::MalformedAttributes("group has malformed attributes".to_string())
+        );
+
+        let mut layers = Vec::new();
+        let mut image_layers = Vec::new();
+        let mut object_groups = Vec::new();
+        let mut groups = Vec::new();
+        let mut properties = HashMap::new();
+        let mut child_index = 0;
+        parse_tag!(parser, "group", {
+            "layer" => |attrs| {
+                layers.push(Layer::new(parser, attrs, width, child_index, infinite)?);
+                child_index += 1;
+                Ok(())
+            },
+            "imagelayer" => |attrs| {
+                image_layers.push(ImageLayer::new(parser, attrs, child_index, map_path)?);
+                child_index += 1;
+                Ok(())
+            },
+            "objectgroup" => |attrs| {
+                object_groups.push(ObjectGroup::new(parser, attrs, Some(child_index))?);
+                child_index += 1;
+                Ok(())
+            },
+            "group" => |attrs| {
+                groups.push(Group::new(parser, attrs, width, child_index, infinite, map_path)?);
+                child_index += 1;
+                Ok(())
+            },
+            "properties" => |_| {
+                properties = parse_properties(parser)?;
+                Ok(())
+            },
+        });
+
+        Ok(Group {
+            name: n.unwrap_or_default(),
+            opacity: o.unwrap_or(1.0),
+            visible: v.unwrap_or(true),
+            offset_x: ox.unwrap_or(0.0),
+            offset_y: oy.unwrap_or(0.0),
+            parallax_x: px.unwrap_or(1.0),
+            parallax_y: py.unwrap_or(1.0),
+            tint: t,
+            layers,
+            image_layers,
+            object_groups,
+            groups,
+            properties,
+            layer_index,
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum ObjectShape {
     Rect { width: f32, height: f32 },
@@ -1169,7 +1346,7 @@ fn parse_base64<R: Read>(parser: &mut EventReader<R>) -> Result<Vec<u8>> {
     }
 }
 
-fn decode_zlib(data: Vec<u8>) -> Result<Vec<u8>> {
+pub(super) fn decode_zlib(data: Vec<u8>) -> Result<Vec<u8>> {
     use libflate::zlib::Decoder;
     let mut zd = Decoder::new(BufReader::new(&data[..]))
         .map_err(|e| TiledError::DecompressingError(e.into()))?;
@@ -1181,7 +1358,7 @@ fn decode_zlib(data: Vec<u8>) -> Result<Vec<u8>> {
     Ok(data)
 }
 
-fn decode_gzip(data: Vec<u8>) -> Result<Vec<u8>, Error> {
+pub(super) fn decode_gzip(data: Vec<u8>) -> Result<Vec<u8>, Error> {
     use libflate::gzip::Decoder;
     let mut zd = Decoder::new(BufReader::new(&data[..]))
         .map_err(|e| TiledError::DecompressingError(e.into()))?;
@@ -1192,7 +1369,7 @@ fn decode_gzip(data: Vec<u8>) -> Result<Vec<u8>, Error> {
     Ok(data)
 }
 
-fn decode_zstd(data: Vec<u8>) -> Result<Vec<u8>, Error> {
+pub(super) fn decode_zstd(data: Vec<u8>) -> Result<Vec<u8>, Error> {
     use std::io::Cursor;
     use zstd::stream::read::Decoder;
 
@@ -1235,7 +1412,7 @@ fn decode_csv<R: Read>(parser: &mut EventReader<R>) -> Result<Vec<Vec<LayerTile>
     }
 }
 
-fn convert_to_tile(all: &Vec<u8>, width: u32) -> Vec<Vec<LayerTile>> {
+pub(super) fn convert_to_tile(all: &Vec<u8>, width: u32) -> Vec<Vec<LayerTile>> {
     let mut data = Vec::new();
     for chunk in all.chunks((width * 4) as usize) {
         let mut row = Vec::new();
@@ -1275,6 +1452,29 @@ fn parse_impl<R: Read>(fs: &mut Filesystem, reader: R, map_path: Option<&Path>)
     }
 }
 
+/// Load a tileset which a map refers to by `source`, in whichever of Tiled's formats
+/// its extension says it's in.
+pub(super) fn parse_tileset_reference(
+    fs: &mut Filesystem,
+    first_gid: u32,
+    source: &str,
+    map_path: Option<&Path>,
+) -> Result<Tileset> {
+    let tileset_path = map_path.ok_or(TiledError::Other("Maps with external tilesets must know their file location.  See parse_with_path(Path).".to_string()))?.with_file_name(source);
+    let file = fs.open(&tileset_path).with_context(|| {
+        TiledError::Other(format!(
+            "External tileset file not found: {:?}",
+            tileset_path
+        ))
+    })?;
+
+    if json_parser::is_json(&tileset_path) {
+        json_parser::parse_tileset_with_path(file, first_gid, &tileset_path)
+    } else {
+        Tileset::new_external(file, first_gid, Some(&tileset_path))
+    }
+}
+
 /// Parse a buffer hopefully containing the contents of a Tiled file and try to
 /// parse it. This augments `parse` with a file location: some engines
 /// (e.g. Amethyst) simply hand over a byte stream (and file location) for parsing,
@@ -40,11 +40,13 @@ use {
     anyhow::*,
     atomic_refcell::AtomicRefCell,
     std::{
-        collections::VecDeque,
+        collections::{BTreeMap, VecDeque},
         fmt::{self, Debug},
         fs,
         io::{self, Read, Seek, Write},
         path::{self, Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::SystemTime,
     },
     zip,
 };
@@ -154,6 +156,36 @@ pub trait VFS: Debug + Send + Sync {
     /// Remove a file or directory and all its contents
     fn rmrf(&self, path: &Path) -> Result<()>;
 
+    /// Move a file or directory, replacing whatever file was at the destination.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        bail!(
+            "Cannot rename {:?} to {:?} in {:?}, renaming is not supported",
+            from,
+            to,
+            self
+        );
+    }
+
+    /// Replace the contents of a file all at once, by writing them to a temporary
+    /// file next to it and renaming that over the original. If anything goes
+    /// wrong partway through, the original is left as it was.
+    fn write_atomic(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        let temp = temp_path(path);
+        let result = self
+            .create(&temp)
+            .and_then(|mut file| {
+                file.write_all(bytes)?;
+                Ok(file.flush()?)
+            })
+            .and_then(|()| self.rename(&temp, path));
+
+        if result.is_err() && self.exists(&temp) {
+            let _ = self.rm(&temp);
+        }
+
+        result
+    }
+
     /// Check if the file exists
     fn exists(&self, path: &Path) -> bool;
 
@@ -167,6 +199,13 @@ pub trait VFS: Debug + Send + Sync {
     fn to_path_buf(&self) -> Option<PathBuf>;
 }
 
+/// Where `write_atomic` writes a file's new contents before renaming them into place.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
 pub trait VMetadata {
     /// Returns whether or not it is a directory.
     /// Note that zip files don't actually have directories, awkwardly,
@@ -177,6 +216,10 @@ pub trait VMetadata {
     /// Returns the length of the thing.  If it is a directory,
     /// the result of this is undefined/platform dependent.
     fn len(&self) -> u64;
+    /// Returns the last modification time, if the VFS keeps track of it.
+    fn modified(&self) -> Option<SystemTime> {
+        None
+    }
 }
 
 /// A VFS that points to a directory and uses it as the root of its
@@ -203,6 +246,9 @@ impl VMetadata for PhysicalMetadata {
     fn len(&self) -> u64 {
         self.0.len()
     }
+    fn modified(&self) -> Option<SystemTime> {
+        self.0.modified().ok()
+    }
 }
 
 /// This takes an absolute path and returns either a sanitized relative
@@ -367,6 +413,52 @@ impl VFS for PhysicalFS {
         }
     }
 
+    /// Move a file or directory
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if self.readonly {
+            bail!("Tried to rename file/dir {:?} but FS is read-only", from);
+        }
+
+        self.create_root()?;
+        let from = self.to_absolute(from)?;
+        let to = self.to_absolute(to)?;
+        fs::rename(from, to).map_err(Error::from)
+    }
+
+    /// Replace the contents of a file, syncing them to disk before renaming them
+    /// into place
+    fn write_atomic(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        if self.readonly {
+            bail!("Tried to write file {:?} but FS is read-only", path);
+        }
+
+        self.create_root()?;
+        let p = self.to_absolute(path)?;
+        let temp = temp_path(&p);
+        let result = (|| -> Result<()> {
+            let mut file = fs::File::create(&temp)?;
+            file.write_all(bytes)?;
+            file.sync_all()?;
+            fs::rename(&temp, &p)?;
+
+            // The rename itself isn't durable until the directory is synced too.
+            #[cfg(unix)]
+            {
+                if let Some(parent) = p.parent() {
+                    fs::File::open(parent)?.sync_all()?;
+                }
+            }
+
+            Ok(())
+        })();
+
+        if result.is_err() && temp.exists() {
+            let _ = fs::remove_file(&temp);
+        }
+
+        result
+    }
+
     /// Check if the file exists
     fn exists(&self, path: &Path) -> bool {
         match self.to_absolute(path) {
@@ -431,9 +523,6 @@ impl OverlayFS {
     }
 
     /// Adds a new VFS to the front of the list.
-    /// Currently unused, I suppose, but good to
-    /// have at least for tests.
-    #[allow(dead_code)]
     pub fn push_front(&mut self, fs: Box<dyn VFS>) {
         self.roots.push_front(fs);
     }
@@ -502,6 +591,28 @@ impl VFS for OverlayFS {
         bail!("Could not remove file/dir {:?}", path);
     }
 
+    /// Move a file or directory within whichever VFS will do it
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        for vfs in &self.roots {
+            match vfs.rename(from, to) {
+                Err(_) => (),
+                f => return f,
+            }
+        }
+        bail!("Could not rename file/dir {:?} to {:?}", from, to);
+    }
+
+    /// Replace the contents of a file in the first VFS which can write it
+    fn write_atomic(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        for vfs in &self.roots {
+            match vfs.write_atomic(path, bytes) {
+                Err(_) => (),
+                f => return f,
+            }
+        }
+        bail!("Could not find anywhere writeable to write {:?}", path);
+    }
+
     /// Check if the file exists
     fn exists(&self, path: &Path) -> bool {
         for vfs in &self.roots {
@@ -630,6 +741,15 @@ impl ZipFS {
             index: idx,
         })
     }
+
+    /// Every file in the archive, as an absolute path. Unlike `read_dir`, this
+    /// leaves out directory entries.
+    pub fn files(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.index
+            .iter()
+            .filter(|name| !name.ends_with('/'))
+            .map(|name| Path::new("/").join(name))
+    }
 }
 
 /// A wrapper to contain a zipfile so we can implement
@@ -808,6 +928,372 @@ impl VFS for ZipFS {
     }
 }
 
+#[derive(Debug)]
+struct MemoryFileData {
+    bytes: Vec<u8>,
+    modified: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+enum MemoryNode {
+    File(Arc<Mutex<MemoryFileData>>),
+    Directory,
+}
+
+/// A filesystem which lives entirely in memory, for tests and tools which
+/// shouldn't touch the disk. It's always writable, and clones of it share the
+/// same tree, so a test can keep one around to poke at files after handing
+/// another to a `Filesystem`.
+#[derive(Clone)]
+pub struct MemoryFS {
+    // Keyed by sanitized path, so the root is the empty path.
+    nodes: Arc<Mutex<BTreeMap<PathBuf, MemoryNode>>>,
+}
+
+impl MemoryFS {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::new(), MemoryNode::Directory);
+        Self {
+            nodes: Arc::new(Mutex::new(nodes)),
+        }
+    }
+
+    /// Create or overwrite a file, along with any missing parent directories.
+    pub fn insert<P: AsRef<Path>, B: Into<Vec<u8>>>(&self, path: P, bytes: B) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.mkdir(parent)?;
+        }
+
+        let mut file = self.create(path)?;
+        file.write_all(&bytes.into())?;
+        Ok(())
+    }
+
+    fn sanitize(path: &Path) -> Result<PathBuf> {
+        sanitize_path(path).ok_or_else(|| {
+            anyhow!(
+                "Path {:?} is not valid: must be an absolute path with no \
+                 references to parent directories",
+                path
+            )
+        })
+    }
+}
+
+impl Default for MemoryFS {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for MemoryFS {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "<MemoryFS>")
+    }
+}
+
+/// A handle to a file in a `MemoryFS`. Writes go straight through to the
+/// shared file, so they're visible to other handles immediately.
+pub struct MemoryFile {
+    data: Arc<Mutex<MemoryFileData>>,
+    position: u64,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+impl io::Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.read {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file not opened for reading",
+            ));
+        }
+
+        let data = self.data.lock().unwrap();
+        let start = (self.position as usize).min(data.bytes.len());
+        let n = (&data.bytes[start..]).read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.write {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file not opened for writing",
+            ));
+        }
+
+        let mut data = self.data.lock().unwrap();
+        if self.append {
+            self.position = data.bytes.len() as u64;
+        }
+
+        let start = self.position as usize;
+        let end = start + buf.len();
+        if data.bytes.len() < end {
+            data.bytes.resize(end, 0);
+        }
+        data.bytes[start..end].copy_from_slice(buf);
+        data.modified = SystemTime::now();
+        self.position = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for MemoryFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(n) => {
+                self.position = n;
+                return Ok(n);
+            }
+            io::SeekFrom::End(n) => (self.data.lock().unwrap().bytes.len() as u64, n),
+            io::SeekFrom::Current(n) => (self.position, n),
+        };
+
+        let position = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+
+        match position {
+            Some(n) => {
+                self.position = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl Debug for MemoryFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "<MemoryFile>")
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct MemoryMetadata {
+    len: u64,
+    is_dir: bool,
+    modified: Option<SystemTime>,
+}
+
+impl VMetadata for MemoryMetadata {
+    fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+    fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+    fn len(&self) -> u64 {
+        self.len
+    }
+    fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+}
+
+impl VFS for MemoryFS {
+    fn open_options(&self, path: &Path, open_options: OpenOptions) -> Result<Box<dyn VFile>> {
+        let key = Self::sanitize(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        let data = match nodes.get(&key) {
+            Some(MemoryNode::File(data)) => data.clone(),
+            Some(MemoryNode::Directory) => bail!("Cannot open {:?}, it is a directory", path),
+            None if open_options.create => {
+                match key.parent().and_then(|parent| nodes.get(parent)) {
+                    Some(MemoryNode::Directory) => (),
+                    _ => bail!(
+                        "Cannot create {:?}, its parent directory does not exist",
+                        path
+                    ),
+                }
+
+                let data = Arc::new(Mutex::new(MemoryFileData {
+                    bytes: Vec::new(),
+                    modified: SystemTime::now(),
+                }));
+                nodes.insert(key, MemoryNode::File(data.clone()));
+                data
+            }
+            None => bail!("File {:?} not found", path),
+        };
+
+        let write = open_options.write || open_options.append;
+        if open_options.truncate && write {
+            let mut data = data.lock().unwrap();
+            data.bytes.clear();
+            data.modified = SystemTime::now();
+        }
+
+        Ok(Box::new(MemoryFile {
+            data,
+            position: 0,
+            read: open_options.read,
+            write,
+            append: open_options.append,
+        }))
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        let key = Self::sanitize(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        for ancestor in key.ancestors() {
+            if let Some(MemoryNode::File(_)) = nodes.get(ancestor) {
+                bail!("Cannot make directory {:?}, {:?} is a file", path, ancestor);
+            }
+        }
+
+        for ancestor in key.ancestors() {
+            nodes
+                .entry(ancestor.to_path_buf())
+                .or_insert(MemoryNode::Directory);
+        }
+
+        Ok(())
+    }
+
+    fn rm(&self, path: &Path) -> Result<()> {
+        let key = Self::sanitize(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(&key) {
+            None => bail!("File/dir {:?} not found", path),
+            Some(MemoryNode::Directory) => {
+                let has_children = nodes
+                    .keys()
+                    .any(|other| other.parent() == Some(key.as_path()));
+                if has_children || key.as_os_str().is_empty() {
+                    bail!("Cannot remove directory {:?}, it is not empty", path);
+                }
+            }
+            Some(MemoryNode::File(_)) => (),
+        }
+
+        nodes.remove(&key);
+        Ok(())
+    }
+
+    fn rmrf(&self, path: &Path) -> Result<()> {
+        let key = Self::sanitize(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        ensure!(nodes.contains_key(&key), "File/dir {:?} not found", path);
+        nodes.retain(|other, _| other.as_os_str().is_empty() || !other.starts_with(&key));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let from_key = Self::sanitize(from)?;
+        let to_key = Self::sanitize(to)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        ensure!(
+            nodes.contains_key(&from_key),
+            "File/dir {:?} not found",
+            from
+        );
+        ensure!(
+            !from_key.as_os_str().is_empty() && !to_key.starts_with(&from_key),
+            "Cannot move {:?} into itself",
+            from
+        );
+
+        match to_key.parent().and_then(|parent| nodes.get(parent)) {
+            Some(MemoryNode::Directory) => (),
+            _ => bail!(
+                "Cannot rename to {:?}, its parent directory does not exist",
+                to
+            ),
+        }
+
+        if let Some(MemoryNode::Directory) = nodes.get(&to_key) {
+            bail!("Cannot rename to {:?}, it is a directory", to);
+        }
+
+        let moved = nodes
+            .keys()
+            .filter(|other| other.starts_with(&from_key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for old in moved {
+            let node = nodes.remove(&old).unwrap();
+            let rest = old.strip_prefix(&from_key).unwrap();
+            let new = if rest.as_os_str().is_empty() {
+                to_key.clone()
+            } else {
+                to_key.join(rest)
+            };
+            nodes.insert(new, node);
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        match sanitize_path(path) {
+            Some(key) => self.nodes.lock().unwrap().contains_key(&key),
+            None => false,
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Box<dyn VMetadata>> {
+        let key = Self::sanitize(path)?;
+        let metadata = match self.nodes.lock().unwrap().get(&key) {
+            None => bail!("Metadata not found for {:?}", path),
+            Some(MemoryNode::Directory) => MemoryMetadata {
+                len: 0,
+                is_dir: true,
+                modified: None,
+            },
+            Some(MemoryNode::File(data)) => {
+                let data = data.lock().unwrap();
+                MemoryMetadata {
+                    len: data.bytes.len() as u64,
+                    is_dir: false,
+                    modified: Some(data.modified),
+                }
+            }
+        };
+
+        Ok(Box::new(metadata))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Box<dyn Iterator<Item = Result<PathBuf>>>> {
+        let key = Self::sanitize(path)?;
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(&key) {
+            Some(MemoryNode::Directory) => (),
+            _ => bail!("Directory {:?} not found", path),
+        }
+
+        // Like `PhysicalFS`, entries are returned as the given path plus their name.
+        let itr = nodes
+            .keys()
+            .filter(|other| other.parent() == Some(key.as_path()))
+            .map(|other| Ok(path.join(other.file_name().unwrap())))
+            .collect::<Vec<_>>();
+        Ok(Box::new(itr.into_iter()))
+    }
+
+    fn to_path_buf(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -950,6 +1436,65 @@ mod tests {
         assert!(!fs.exists(testdir));
     }
 
+    #[test]
+    fn headless_test_memory_all() {
+        let fs = MemoryFS::new();
+        let testdir = Path::new("/testdir");
+        let f1 = Path::new("/testdir/file1.txt");
+
+        assert!(fs.exists(Path::new("/")));
+        assert!(fs.create(f1).is_err());
+
+        fs.mkdir(testdir).unwrap();
+        assert!(fs.exists(testdir));
+        fs.rm(testdir).unwrap();
+        assert!(!fs.exists(testdir));
+
+        fs.mkdir(testdir).unwrap();
+        {
+            let mut f = fs.append(f1).unwrap();
+            let _ = f.write(b"Foo!").unwrap();
+        }
+        {
+            let mut f = fs.append(f1).unwrap();
+            let _ = f.write(b"Bar!").unwrap();
+        }
+        {
+            let mut buf = Vec::new();
+            let mut f = fs.open(f1).unwrap();
+            let _ = f.seek(io::SeekFrom::Start(4)).unwrap();
+            let _ = f.read_to_end(&mut buf).unwrap();
+            assert_eq!(&buf[..], b"Bar!");
+            assert!(f.write(b"nope").is_err());
+        }
+
+        {
+            let m = fs.metadata(f1).unwrap();
+            assert!(m.is_file());
+            assert_eq!(m.len(), 8);
+            assert!(m.modified().is_some());
+
+            let m = fs.metadata(testdir).unwrap();
+            assert!(m.is_dir());
+        }
+
+        {
+            let r = fs.read_dir(testdir).unwrap().collect::<Result<Vec<_>>>();
+            assert_eq!(r.unwrap(), vec![f1.to_path_buf()]);
+        }
+
+        // Clones share the same files.
+        let clone = fs.clone();
+        clone.insert("/testdir/nested/file2.txt", "Baz!").unwrap();
+        assert!(fs.exists(Path::new("/testdir/nested/file2.txt")));
+
+        assert!(fs.rm(testdir).is_err());
+        fs.rmrf(testdir).unwrap();
+        assert!(!fs.exists(testdir));
+        assert!(!fs.exists(f1));
+        assert!(fs.exists(Path::new("/")));
+    }
+
     #[test]
     fn headless_test_zip_files() {
         let mut finished_zip_bytes: io::Cursor<_> = {
@@ -1,6 +1,14 @@
 use sludge::{
+    assets::{Asset, Cache, DefaultCache, Key, Loaded, LuaAsset},
     components::{Name, Persistent},
+    filesystem::Filesystem,
+    persist::{self, Migrations},
     prelude::*,
+    vfs::{MemoryFS, OpenOptions, VFS},
+};
+use std::{
+    collections::HashMap,
+    io::{Seek, SeekFrom, Write},
 };
 
 fn roundtrip(space: &Space) -> Result<Space> {
@@ -61,3 +69,386 @@ fn persist_simple() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn persist_entity_references() -> Result<()> {
+    let space = Space::new()?;
+
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                local fish = sludge.spawn { Name = "Wanda", Persistent = true }
+                sludge.thread.yield(1)
+                fish.Name = fish.Name:get() .. " (restored)"
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+    space
+        .lua()
+        .context(|lua| space.scheduler_mut().update(lua, 1.))?;
+
+    let space = roundtrip(&space)?;
+    space
+        .lua()
+        .context(|lua| space.scheduler_mut().update(lua, 1.))?;
+
+    let world = space.world();
+    let mut query = world.query::<&Name>();
+    let names = query
+        .iter()
+        .map(|(_, name)| name.0.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["Wanda (restored)".to_owned()]);
+
+    Ok(())
+}
+
+#[test]
+fn persist_waiting_threads() -> Result<()> {
+    let space = Space::new()?;
+
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                local _, who = sludge.thread.yield("door_opened")
+                sludge.spawn { Name = "opened by " .. who }
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+    space
+        .lua()
+        .context(|lua| space.scheduler_mut().update(lua, 1.))?;
+
+    let space = roundtrip(&space)?;
+    space.lua().context(|lua| -> Result<()> {
+        lua.broadcast("door_opened", "Wanda")?;
+        space.scheduler_mut().update(lua, 1.)?;
+        Ok(())
+    })?;
+
+    let world = space.world();
+    let mut query = world.query::<&Name>();
+    let names = query
+        .iter()
+        .map(|(_, name)| name.0.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["opened by Wanda".to_owned()]);
+
+    Ok(())
+}
+
+#[test]
+fn persist_thread_groups() -> Result<()> {
+    let space = Space::new()?;
+
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            local function wait_and_spawn(name)
+                return function()
+                    sludge.thread.yield("phase_two")
+                    sludge.spawn { Name = name }
+                end
+            end
+
+            sludge.thread.spawn_in("boss", wait_and_spawn("left arm"))
+            sludge.thread.spawn_in("boss", wait_and_spawn("right arm"))
+            sludge.thread.spawn(wait_and_spawn("music"))
+            "#,
+        )
+        .exec()
+    })?;
+    space
+        .lua()
+        .context(|lua| space.scheduler_mut().update(lua, 1.))?;
+
+    let space = roundtrip(&space)?;
+    space.lua().context(|lua| -> Result<()> {
+        lua.load(r#"sludge.thread.kill_group("boss")"#).exec()?;
+        lua.broadcast("phase_two", ())?;
+        space.scheduler_mut().update(lua, 1.)?;
+        Ok(())
+    })?;
+
+    let world = space.world();
+    let mut query = world.query::<&Name>();
+    let names = query
+        .iter()
+        .map(|(_, name)| name.0.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["music".to_owned()]);
+
+    Ok(())
+}
+
+#[test]
+fn persist_select_waits() -> Result<()> {
+    let space = Space::new()?;
+
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            local function select(name, events)
+                return function()
+                    local event, arg = sludge.thread.yield(events)
+                    sludge.spawn { Name = name .. ": " .. tostring(event) .. " " .. tostring(arg) }
+                end
+            end
+
+            sludge.thread.spawn(select("dialogue", { "confirm", "cancel", timeout = 2 }))
+            sludge.thread.spawn(select("prompt", { "confirm", timeout = 2 }))
+            "#,
+        )
+        .exec()
+    })?;
+    space
+        .lua()
+        .context(|lua| space.scheduler_mut().update(lua, 1.))?;
+
+    let space = roundtrip(&space)?;
+    space.lua().context(|lua| -> Result<()> {
+        lua.broadcast("cancel", "pressed")?;
+        space.scheduler_mut().update(lua, 1.)?;
+        space.scheduler_mut().update(lua, 2.)?;
+        lua.broadcast("cancel", "again")?;
+        space.scheduler_mut().update(lua, 1.)?;
+        Ok(())
+    })?;
+
+    let world = space.world();
+    let mut query = world.query::<&Name>();
+    let mut names = query
+        .iter()
+        .map(|(_, name)| name.0.clone())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "dialogue: cancel pressed".to_owned(),
+            "prompt: nil nil".to_owned()
+        ]
+    );
+
+    Ok(())
+}
+
+struct Greeting(String);
+
+impl Asset for Greeting {
+    fn load<'a, R: Resources<'a>>(
+        key: &Key,
+        _cache: &Cache<'a, R>,
+        _resources: &R,
+    ) -> Result<Loaded<Self>> {
+        let key = key.to_rust::<HashMap<String, String>>()?;
+        Ok(Greeting(format!("Hello, {}!", key["name"])).into())
+    }
+}
+
+inventory::submit! {
+    LuaAsset::new::<Greeting>("Greeting")
+}
+
+fn insert_cache(space: &Space) {
+    space
+        .resources()
+        .borrow_mut()
+        .insert(DefaultCache::new(space.resources().clone()));
+}
+
+#[test]
+fn persist_asset_handles() -> Result<()> {
+    let space = Space::new()?;
+    insert_cache(&space);
+
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                local greeting = sludge.assets.load("Greeting", { name = "Wanda" })
+                sludge.thread.yield(1)
+                sludge.spawn { Name = greeting:type() .. " for " .. greeting:key().name }
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+    space
+        .lua()
+        .context(|lua| space.scheduler_mut().update(lua, 1.))?;
+
+    let mut bytes = Vec::<u8>::new();
+    space
+        .lua()
+        .context(|lua| sludge::persist::persist(lua, &space, &mut bytes))?;
+
+    let space = Space::new()?;
+    insert_cache(&space);
+    space
+        .lua()
+        .context(|lua| sludge::persist::unpersist(lua, &space, &mut &bytes[..]))?;
+    assert_eq!(space.fetch::<DefaultCache>().memory_usage()[0].count, 1);
+
+    space
+        .lua()
+        .context(|lua| space.scheduler_mut().update(lua, 1.))?;
+
+    let world = space.world();
+    let mut query = world.query::<&Name>();
+    let names = query
+        .iter()
+        .map(|(_, name)| name.0.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["Greeting for Wanda".to_owned()]);
+
+    Ok(())
+}
+
+#[test]
+fn save_migrates_old_versions() -> Result<()> {
+    let space = Space::new()?;
+    space.world_mut().spawn(Fish {
+        name: Name("Wanda".to_owned()),
+        persistent: Persistent,
+    });
+
+    let mut bytes = Vec::<u8>::new();
+    space.save(&mut bytes)?;
+
+    let new_space = Space::new()?;
+    {
+        let mut migrations = new_space.fetch_mut::<Migrations>();
+        migrations.set_version(1);
+        migrations.register(0, |lua, components| {
+            let components = LuaTable::from_lua(components, lua)?;
+            let name = components.get::<_, String>("Name")?;
+            components.set("Name", format!("{} (migrated)", name))?;
+            Ok(LuaValue::Table(components))
+        });
+    }
+    new_space.load(&mut &bytes[..])?;
+
+    let world = new_space.world();
+    let mut query = world.query::<&Name>();
+    let names = query
+        .iter()
+        .map(|(_, name)| name.0.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["Wanda (migrated)".to_owned()]);
+
+    assert!(Space::new()?.load(&mut &b"not a save"[..]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn oversized_save_headers_are_rejected() -> Result<()> {
+    let mut bytes = persist::SAVE_MAGIC.to_vec();
+    bytes.extend_from_slice(&persist::SAVE_FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+
+    let error = persist::SaveHeader::read(&bytes[..]).unwrap_err();
+    assert!(error.to_string().contains("too long"), "{}", error);
+
+    Ok(())
+}
+
+#[test]
+fn corrupt_save_slots_fall_back_to_backups() -> Result<()> {
+    let memfs = MemoryFS::new();
+    let mut fs = Filesystem::from_vfs(memfs.clone());
+
+    for save in &[&b"first"[..], b"second", b"third", b"fourth", b"fifth"] {
+        persist::write_save_slot(&mut fs, "slot", save, 3)?;
+    }
+    assert_eq!(persist::read_save_slot(&mut fs, "slot")?, b"fifth");
+    assert!(fs.exists(persist::save_slot_backup_path("slot", 3)?));
+    assert!(!fs.exists(persist::save_slot_backup_path("slot", 4)?));
+
+    // Flip a byte at the end of the save, then of its newest backup.
+    for path in &[
+        persist::save_slot_path("slot")?,
+        persist::save_slot_backup_path("slot", 1)?,
+    ] {
+        let mut file = memfs.open_options(path.as_ref(), OpenOptions::new().write(true))?;
+        file.seek(SeekFrom::End(-1))?;
+        file.write_all(b"!")?;
+    }
+    assert_eq!(persist::read_save_slot(&mut fs, "slot")?, b"third");
+
+    // Backups past a missing one are still tried.
+    fs.delete(persist::save_slot_backup_path("slot", 2)?)?;
+    assert_eq!(persist::read_save_slot(&mut fs, "slot")?, b"second");
+
+    // The save being replaced is copied into the first backup, not moved.
+    persist::write_save_slot(&mut fs, "slot", b"sixth", 3)?;
+    assert_eq!(persist::read_save_slot(&mut fs, "slot")?, b"sixth");
+
+    Ok(())
+}
+
+#[test]
+fn save_and_load_slots_from_lua() -> Result<()> {
+    let space = Space::new()?;
+    space
+        .resources()
+        .borrow_mut()
+        .insert(Filesystem::from_vfs(MemoryFS::new()));
+
+    let run = |source: &str| -> Result<()> {
+        space.lua().context(|lua| -> Result<()> {
+            lua.load(source).exec()?;
+            let mut scheduler = space.scheduler_mut();
+            scheduler.update(lua, 1.)?;
+            scheduler.update(lua, 1.)?;
+            Ok(())
+        })
+    };
+    let names = || {
+        let world = space.world();
+        let mut query = world.query::<&Name>().with::<Persistent>();
+        let mut names = query
+            .iter()
+            .map(|(_, name)| name.0.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    run(r#"
+        sludge.thread.spawn(function()
+            sludge.spawn { Name = "Wanda", Persistent = true }
+            local result = sludge.save("slot")
+            sludge.spawn { Name = "resumed: " .. tostring(result), Persistent = true }
+        end)
+        "#)?;
+    assert_eq!(names(), vec!["Wanda", "resumed: saved"]);
+
+    // A failed load leaves everything as it was, and wakes the thread with the error.
+    run(r#"
+        sludge.thread.spawn(function()
+            local result, err = sludge.load("missing")
+            sludge.spawn { Name = "failed: " .. tostring(result), Persistent = true }
+        end)
+        "#)?;
+    assert_eq!(names(), vec!["Wanda", "failed: nil", "resumed: saved"]);
+
+    // Loading replaces the persistent entities and the threads, so the thread which
+    // requested the save resumes again, and the loading thread never does.
+    run(r#"
+        sludge.thread.spawn(function()
+            sludge.load("slot")
+            sludge.spawn { Name = "not reached", Persistent = true }
+        end)
+        "#)?;
+    assert_eq!(names(), vec!["Wanda", "resumed: loaded"]);
+
+    Ok(())
+}
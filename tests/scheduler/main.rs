@@ -0,0 +1,153 @@
+use sludge::{dispatcher::Dispatcher, prelude::*, ThreadError};
+
+#[test]
+fn thread_stats() -> Result<()> {
+    let space = Space::new()?;
+
+    let thread = space.lua().context(|lua| -> Result<_> {
+        let thread = lua
+            .load(
+                r#"
+                return sludge.thread.spawn(function()
+                    for i = 1, 3 do
+                        sludge.thread.yield(1)
+                    end
+                    sludge.thread.yield("never")
+                end)
+                "#,
+            )
+            .set_name("stats")?
+            .eval::<LuaThread>()?;
+        Ok(lua.create_registry_value(thread)?)
+    })?;
+
+    space.lua().context(|lua| -> Result<()> {
+        space.scheduler_mut().update(lua, 4.)?;
+
+        let thread = lua.registry_value::<LuaThread>(&thread)?;
+        let stats = space
+            .scheduler()
+            .thread_stats(lua, thread.clone())?
+            .expect("thread should still be alive");
+        assert_eq!(stats.resumes(), 4);
+        assert_eq!(stats.last_resumed(), Some(3));
+        assert_eq!(stats.spawned_at(), Some(r#"[string "stats"]:2"#));
+
+        lua.globals().set("thread", thread)?;
+        let resumes = lua
+            .load("return sludge.thread.stats(thread).resumes")
+            .eval::<u64>()?;
+        assert_eq!(resumes, 4);
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[test]
+fn watchdog_kills_runaway_threads() -> Result<()> {
+    let space = Space::new()?;
+    space.set_watchdog_budget(Some(100_000));
+
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                while true do end
+            end)
+
+            sludge.thread.spawn(function()
+                pcall(function()
+                    while true do end
+                end)
+                sludge.thread.yield(1)
+            end)
+
+            sludge.thread.spawn(function()
+                while true do
+                    sludge.thread.yield(1)
+                end
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+
+    space.lua().context(|lua| -> Result<()> {
+        space.scheduler_mut().update(lua, 2.)?;
+        assert_eq!(space.scheduler().all_thread_stats(lua)?.len(), 1);
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[test]
+fn clearing_the_watchdog_budget_disables_it() -> Result<()> {
+    let space = Space::new()?;
+    space.set_watchdog_budget(Some(100_000));
+
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                for _ = 1, 1000000 do end
+                finished = true
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+
+    space.set_watchdog_budget(None);
+    space.lua().context(|lua| -> Result<()> {
+        space.scheduler_mut().update(lua, 1.)?;
+        assert!(lua.globals().get::<_, bool>("finished")?);
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[test]
+fn error_handler_restarts_and_propagates() -> Result<()> {
+    let space = Space::new()?;
+
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.set_error_handler(function(thread, message, spawned_at)
+                if message:find("try again") then
+                    return "restart"
+                end
+                return "propagate"
+            end)
+
+            local attempts = 0
+            sludge.thread.spawn(function()
+                attempts = attempts + 1
+                if attempts < 3 then
+                    error("try again")
+                end
+                error("give up")
+            end)
+            "#,
+        )
+        .set_name("errors")?
+        .exec()
+    })?;
+
+    space
+        .lua()
+        .context(|lua| space.scheduler_mut().update(lua, 3.))?;
+
+    let error = space
+        .dispatch(&mut Dispatcher::new())
+        .expect_err("the error should have been propagated");
+    let error = error.downcast::<ThreadError>()?;
+    assert!(error.message().contains("give up"));
+    assert_eq!(error.spawned_at(), Some(r#"[string "errors"]:10"#));
+
+    Ok(())
+}